@@ -0,0 +1,85 @@
+use std::{
+    cell::RefCell,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicUsize, Ordering},
+    },
+    thread,
+};
+
+use emacs::{defun, Env, Result};
+use tree_sitter::{Parser, Tree};
+
+use crate::{
+    types::Shared,
+    lang::Language,
+    tree::stamped,
+    error,
+};
+
+/// A parse running on a background thread.
+pub struct ParseJob {
+    cancellation_flag: Arc<AtomicUsize>,
+    /// None while the parse is running. Some(None) if it failed or was cancelled.
+    result: Arc<Mutex<Option<Option<Tree>>>>,
+}
+
+impl_pred!(parse_job_p, &RefCell<ParseJob>);
+
+impl Drop for ParseJob {
+    /// Stop the parse if its job was abandoned, e.g. garbage-collected, since nobody can retrieve
+    /// the result anymore.
+    fn drop(&mut self) {
+        self.cancellation_flag.store(1, Ordering::SeqCst);
+    }
+}
+
+/// Start parsing TEXT as LANGUAGE on a background thread. See `tsc-parse-in-background'.
+#[defun(user_ptr)]
+fn _parse_in_background(
+    language: Language,
+    text: String,
+    old_tree: Option<&Shared<Tree>>,
+) -> Result<ParseJob> {
+    // The thread gets its own copy of the old tree, which is cheap, since the nodes are shared.
+    let old_tree = old_tree.map(|tree| tree.borrow().clone());
+    let cancellation_flag = Arc::new(AtomicUsize::new(0));
+    let result = Arc::new(Mutex::new(None));
+    let (flag, output) = (cancellation_flag.clone(), result.clone());
+    thread::spawn(move || {
+        let mut parser = Parser::new();
+        let tree = parser.set_language(language.0).ok().and_then(|_| {
+            // Safety: The flag outlives the parse, and is detached right after.
+            unsafe { parser.set_cancellation_flag(Some(&flag)) };
+            let tree = parser.parse(text, old_tree.as_ref());
+            unsafe { parser.set_cancellation_flag(None) };
+            tree
+        });
+        *output.lock().expect("Failed to store background parse's result") = Some(tree);
+    });
+    Ok(ParseJob { cancellation_flag, result })
+}
+
+/// Return the tree produced by the background parse JOB, or nil if it is still
+/// running. Signal `tsc-parse-cancelled' or `tsc-parse-failed' if it didn't
+/// succeed.
+///
+/// The result can be retrieved only once. Subsequent calls return nil.
+#[defun]
+fn _parse_job_result(env: &Env, job: &ParseJob) -> Result<Option<Shared<Tree>>> {
+    let result = job.result.lock().expect("Failed to access background parse's result").take();
+    match result {
+        None => Ok(None),
+        Some(Some(tree)) => Ok(Some(stamped(tree))),
+        Some(None) if job.cancellation_flag.load(Ordering::SeqCst) != 0 => {
+            env.signal(error::tsc_parse_cancelled, ())
+        }
+        Some(None) => env.signal(error::tsc_parse_failed, ()),
+    }
+}
+
+/// Request the background parse JOB to stop as soon as possible.
+#[defun]
+fn _cancel_parse_job(job: &ParseJob) -> Result<()> {
+    Ok(job.cancellation_flag.store(1, Ordering::SeqCst))
+}
@@ -0,0 +1,148 @@
+use std::cell::RefCell;
+
+use emacs::{defun, Env, Result, Vector};
+use tree_sitter::{InputEdit, Point as TSPoint, Tree};
+
+use crate::{
+    types::{BytePos, Point},
+    tree::Borrowed,
+};
+
+/// Buffer edits that have not been applied to a syntax tree yet.
+pub struct EditBatch {
+    /// In the order they were made. Adjacent or overlapping edits are merged.
+    edits: Vec<InputEdit>,
+}
+
+impl_pred!(edit_batch_p, &RefCell<EditBatch>);
+
+/// Return the position of TO, relative to FROM, if FROM were moved to BASE.
+fn translate(base: TSPoint, from: TSPoint, to: TSPoint) -> TSPoint {
+    if to.row == from.row {
+        TSPoint { row: base.row, column: base.column + to.column - from.column }
+    } else {
+        TSPoint { row: base.row + to.row - from.row, column: to.column }
+    }
+}
+
+/// Merge LAST with NEXT, which was made after it, if they touch or overlap.
+fn merge(last: &InputEdit, next: &InputEdit) -> Option<InputEdit> {
+    if next.start_byte > last.new_end_byte || next.old_end_byte < last.start_byte {
+        return None;
+    }
+    let (start_byte, start_position) = if next.start_byte < last.start_byte {
+        (next.start_byte, next.start_position)
+    } else {
+        (last.start_byte, last.start_position)
+    };
+    // Text deleted by NEXT beyond what LAST inserted was also in the original text.
+    let (old_end_byte, old_end_position) = if next.old_end_byte > last.new_end_byte {
+        (
+            last.old_end_byte + next.old_end_byte - last.new_end_byte,
+            translate(last.old_end_position, last.new_end_position, next.old_end_position),
+        )
+    } else {
+        (last.old_end_byte, last.old_end_position)
+    };
+    // Text inserted by LAST beyond what NEXT deleted is shifted by NEXT.
+    let (new_end_byte, new_end_position) = if last.new_end_byte > next.old_end_byte {
+        (
+            last.new_end_byte + next.new_end_byte - next.old_end_byte,
+            translate(next.new_end_position, next.old_end_position, last.new_end_position),
+        )
+    } else {
+        (next.new_end_byte, next.new_end_position)
+    };
+    Some(InputEdit {
+        start_byte,
+        old_end_byte,
+        new_end_byte,
+        start_position,
+        old_end_position,
+        new_end_position,
+    })
+}
+
+/// Create a new, empty edit batch.
+///
+/// An edit batch accumulates buffer edits, e.g. from `after-change-functions', so
+/// that they can be applied to a syntax tree later, e.g. when Emacs is idle,
+/// followed by a single re-parse. Consecutive edits that touch or overlap are
+/// merged into one.
+#[defun(user_ptr)]
+fn make_edit_batch() -> Result<EditBatch> {
+    Ok(EditBatch { edits: vec![] })
+}
+
+/// Record an edit in BATCH. The arguments are the same as for `tsc-edit-tree'.
+#[defun]
+fn edit_batch_record(
+    batch: &mut EditBatch,
+    start_bytepos: BytePos,
+    old_end_bytepos: BytePos,
+    new_end_bytepos: BytePos,
+    start_point: Point,
+    old_end_point: Point,
+    new_end_point: Point,
+) -> Result<()> {
+    let edit = InputEdit {
+        start_byte: start_bytepos.into(),
+        old_end_byte: old_end_bytepos.into(),
+        new_end_byte: new_end_bytepos.into(),
+        start_position: start_point.into(),
+        old_end_position: old_end_point.into(),
+        new_end_position: new_end_point.into(),
+    };
+    match batch.edits.last_mut() {
+        Some(last) => match merge(last, &edit) {
+            Some(merged) => *last = merged,
+            None => batch.edits.push(edit),
+        },
+        None => batch.edits.push(edit),
+    }
+    Ok(())
+}
+
+/// Return the number of pending edits in BATCH, after merging.
+#[defun]
+fn edit_batch_count(batch: &EditBatch) -> Result<usize> {
+    Ok(batch.edits.len())
+}
+
+/// Return the pending edits in BATCH, as a vector.
+///
+/// Each edit is a vector of the form [START-BYTEPOS OLD-END-BYTEPOS NEW-END-BYTEPOS
+/// START-POINT OLD-END-POINT NEW-END-POINT], like the arguments of `tsc-edit-tree'.
+#[defun]
+fn edit_batch_edits<'e>(env: &'e Env, batch: &EditBatch) -> Result<Vector<'e>> {
+    let vector = env.make_vector(batch.edits.len(), ())?;
+    for (i, edit) in batch.edits.iter().enumerate() {
+        let fields = env.make_vector(6, ())?;
+        fields.set(0, BytePos::from(edit.start_byte))?;
+        fields.set(1, BytePos::from(edit.old_end_byte))?;
+        fields.set(2, BytePos::from(edit.new_end_byte))?;
+        fields.set(3, Point::from(edit.start_position))?;
+        fields.set(4, Point::from(edit.old_end_position))?;
+        fields.set(5, Point::from(edit.new_end_position))?;
+        vector.set(i, fields)?;
+    }
+    Ok(vector)
+}
+
+/// Apply the pending edits in BATCH to TREE, then clear them.
+/// Return the number of edits applied.
+#[defun]
+fn edit_batch_apply(batch: &mut EditBatch, tree: Borrowed<Tree>) -> Result<usize> {
+    let mut tree = tree.borrow_mut();
+    for edit in &batch.edits {
+        tree.edit(edit);
+    }
+    Ok(batch.edits.drain(..).count())
+}
+
+/// Discard the pending edits in BATCH.
+#[defun]
+fn edit_batch_clear(batch: &mut EditBatch) -> Result<()> {
+    batch.edits.clear();
+    Ok(())
+}
@@ -0,0 +1,264 @@
+use std::{
+    cell::RefCell,
+    convert::TryInto,
+    fs,
+    io::{self, ErrorKind},
+};
+
+use emacs::{defun, Env, Result, Value};
+
+use tree_sitter::{Point as TSPoint, Tree};
+
+use crate::{
+    types::{BytePos, Point},
+    lang::Language,
+    tree::Borrowed,
+};
+
+// -------------------------------------------------------------------------------------------------
+// Binary format (all integers are little-endian):
+//
+// MAGIC, FORMAT_VERSION: u32,
+// key length: u32, key bytes,
+// language ABI version: u32, node kind count: u32, field count: u32,
+// node count: u32, then for each node, in depth-first pre-order:
+//   kind id: u16, field id: u16 (0 means no field), flags: u8, child count: u32,
+//   start byte: u32, end byte: u32,
+//   start row: u32, start column: u32, end row: u32, end column: u32.
+
+const MAGIC: &[u8; 4] = b"TSCT";
+const FORMAT_VERSION: u32 = 1;
+
+const NAMED: u8 = 1;
+const EXTRA: u8 = 1 << 1;
+const MISSING: u8 = 1 << 2;
+
+struct CachedNode {
+    kind_id: u16,
+    field_id: u16,
+    flags: u8,
+    child_count: u32,
+    start_byte: usize,
+    end_byte: usize,
+    start_point: TSPoint,
+    end_point: TSPoint,
+}
+
+/// A read-only snapshot of a syntax tree's nodes, restored from a cache file.
+pub struct CachedTree {
+    language: Language,
+    /// The nodes, in depth-first pre-order.
+    nodes: Vec<CachedNode>,
+}
+
+impl_pred!(cached_tree_p, &RefCell<CachedTree>);
+
+struct Writer(Vec<u8>);
+
+impl Writer {
+    fn u8(&mut self, n: u8) {
+        self.0.push(n);
+    }
+
+    fn u16(&mut self, n: u16) {
+        self.0.extend_from_slice(&n.to_le_bytes());
+    }
+
+    fn u32(&mut self, n: usize) -> io::Result<()> {
+        let n: u32 = n.try_into().map_err(|_| {
+            io::Error::new(ErrorKind::InvalidInput, "Syntax tree is too large to be cached")
+        })?;
+        self.0.extend_from_slice(&n.to_le_bytes());
+        Ok(())
+    }
+
+    fn point(&mut self, point: TSPoint) -> io::Result<()> {
+        self.u32(point.row)?;
+        self.u32(point.column)
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> io::Result<&'a [u8]> {
+        let end = self.pos + n;
+        if end > self.bytes.len() {
+            return Err(io::Error::new(ErrorKind::UnexpectedEof, "Tree cache file is truncated"));
+        }
+        let bytes = &self.bytes[self.pos..end];
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> io::Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> io::Result<u16> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> io::Result<usize> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()) as usize)
+    }
+
+    fn point(&mut self) -> io::Result<TSPoint> {
+        let row = self.u32()?;
+        let column = self.u32()?;
+        Ok(TSPoint { row, column })
+    }
+}
+
+fn language_header(writer: &mut Writer, language: tree_sitter::Language) -> io::Result<()> {
+    writer.u32(language.version())?;
+    writer.u32(language.node_kind_count())?;
+    writer.u32(language.field_count())
+}
+
+/// Save the nodes of the syntax TREE to FILE, tagging them with KEY.
+#[defun]
+fn _save_tree_cache(tree: Borrowed<Tree>, file: String, key: String) -> Result<()> {
+    let tree = tree.borrow();
+    let mut writer = Writer(MAGIC.to_vec());
+    writer.u32(FORMAT_VERSION as usize)?;
+    writer.u32(key.len())?;
+    writer.0.extend_from_slice(key.as_bytes());
+    language_header(&mut writer, tree.language())?;
+
+    let count_pos = writer.0.len();
+    writer.u32(0)?;
+    let mut count = 0;
+    let mut cursor = tree.walk();
+    'traversal: loop {
+        let node = cursor.node();
+        writer.u16(node.kind_id());
+        writer.u16(cursor.field_id().unwrap_or(0));
+        let mut flags = 0;
+        if node.is_named() {
+            flags |= NAMED;
+        }
+        if node.is_extra() {
+            flags |= EXTRA;
+        }
+        if node.is_missing() {
+            flags |= MISSING;
+        }
+        writer.u8(flags);
+        writer.u32(node.child_count())?;
+        writer.u32(node.start_byte())?;
+        writer.u32(node.end_byte())?;
+        writer.point(node.start_position())?;
+        writer.point(node.end_position())?;
+        count += 1;
+        if cursor.goto_first_child() {
+            continue;
+        }
+        while !cursor.goto_next_sibling() {
+            if !cursor.goto_parent() {
+                break 'traversal;
+            }
+        }
+    }
+    let count = (count as u32).to_le_bytes();
+    writer.0[count_pos..count_pos + 4].copy_from_slice(&count);
+
+    fs::write(file, writer.0)?;
+    Ok(())
+}
+
+/// Restore the syntax tree nodes saved in FILE by `tsc-save-tree-cache'.
+///
+/// Return nil if the cache was saved with a KEY different from the given one, by a
+/// different version of LANGUAGE, or in an older format.
+#[defun]
+fn _load_tree_cache(
+    language: Language,
+    file: String,
+    key: String,
+) -> Result<Option<Box<RefCell<CachedTree>>>> {
+    let bytes = fs::read(file)?;
+    let mut reader = Reader { bytes: &bytes, pos: 0 };
+    if reader.take(MAGIC.len())? != MAGIC || reader.u32()? != FORMAT_VERSION as usize {
+        return Ok(None);
+    }
+    let key_len = reader.u32()?;
+    if reader.take(key_len)? != key.as_bytes() {
+        return Ok(None);
+    }
+    let mut expected = Writer(vec![]);
+    language_header(&mut expected, language.0)?;
+    if reader.take(expected.0.len())? != &expected.0[..] {
+        return Ok(None);
+    }
+
+    let count = reader.u32()?;
+    let mut nodes = Vec::with_capacity(count);
+    for _ in 0..count {
+        nodes.push(CachedNode {
+            kind_id: reader.u16()?,
+            field_id: reader.u16()?,
+            flags: reader.u8()?,
+            child_count: reader.u32()? as u32,
+            start_byte: reader.u32()?,
+            end_byte: reader.u32()?,
+            start_point: reader.point()?,
+            end_point: reader.point()?,
+        });
+    }
+    Ok(Some(Box::new(RefCell::new(CachedTree { language, nodes }))))
+}
+
+/// Return the language of the syntax tree restored in CACHED-TREE.
+#[defun]
+fn cached_tree_language(cached_tree: &CachedTree) -> Result<Language> {
+    Ok(cached_tree.language)
+}
+
+/// Return the number of nodes in CACHED-TREE.
+#[defun]
+fn cached_tree_node_count(cached_tree: &CachedTree) -> Result<usize> {
+    Ok(cached_tree.nodes.len())
+}
+
+/// Call FUNC for each node in CACHED-TREE, in depth-first pre-order.
+///
+/// FUNC is called with a vector of the node's properties, in the form
+/// [TYPE FIELD NAMED-P EXTRA-P MISSING-P START-BYTEPOS END-BYTEPOS START-POINT
+/// END-POINT DEPTH]. These are the same as the values of the node properties of
+/// the same names, see `tsc-valid-node-props'.
+#[defun]
+fn cached_tree_mapc(env: &Env, func: Value, cached_tree: &CachedTree) -> Result<()> {
+    let info = cached_tree.language.info();
+    // The number of children yet to be visited, for each ancestor of the current node.
+    let mut remaining: Vec<u32> = vec![];
+    for node in &cached_tree.nodes {
+        let props = env.make_vector(10, ())?;
+        props.set(0, info.node_type(node.kind_id))?;
+        props.set(1, info.field_name(node.field_id))?;
+        props.set(2, node.flags & NAMED != 0)?;
+        props.set(3, node.flags & EXTRA != 0)?;
+        props.set(4, node.flags & MISSING != 0)?;
+        props.set(5, BytePos::from(node.start_byte))?;
+        props.set(6, BytePos::from(node.end_byte))?;
+        props.set(7, Point::from(node.start_point))?;
+        props.set(8, Point::from(node.end_point))?;
+        props.set(9, remaining.len())?;
+        func.call((props,))?;
+        if node.child_count > 0 {
+            remaining.push(node.child_count);
+            continue;
+        }
+        while let Some(count) = remaining.last_mut() {
+            *count -= 1;
+            if *count > 0 {
+                break;
+            }
+            remaining.pop();
+        }
+    }
+    Ok(())
+}
@@ -8,14 +8,19 @@ use emacs::{defun, Result, Value, Env, GlobalRef, Vector, IntoLisp, FromLisp};
 use tree_sitter::{Tree, TreeCursor, Node};
 
 use crate::{
-    types::{self, Shared, BytePos},
-    node::{RNode, LispUtils},
+    types::{self, Shared, BytePos, Point},
+    node::{self, RNode, LispUtils},
     lang::Language,
 };
 
 emacs::use_symbols! {
     wrong_type_argument
     tree_or_node_p
+    functionp
+    vectorp
+    recordp
+    aset
+    tsc__buffer_substring_no_properties => "tsc--buffer-substring-no-properties"
 
     _type        => ":type"
     _named_p     => ":named-p"
@@ -29,9 +34,36 @@ emacs::use_symbols! {
     _end_point   => ":end-point"
     _range       => ":range"
     _byte_range  => ":byte-range"
+    _text        => ":text"
+    _id          => ":id"
+
+    _kind_id     => ":kind-id"
+    _field_id    => ":field-id"
+
+    _child_count       => ":child-count"
+    _named_child_count => ":named-child-count"
 
     _field       => ":field"
     _depth       => ":depth"
+
+    _parent_type  => ":parent-type"
+    _parent_field => ":parent-field"
+
+    _event       => ":event"
+    _enter       => ":enter"
+    _exit        => ":exit"
+
+    _order       => ":order"
+    _post        => ":post"
+    _pre_post    => ":pre-post"
+    _leaves_only => ":leaves-only"
+    _reverse     => ":reverse"
+    _max_depth   => ":max-depth"
+    _named_only  => ":named-only"
+    _with_depth  => ":with-depth"
+    _source      => ":source"
+
+    _skip_children => ":skip-children"
 }
 
 // -------------------------------------------------------------------------------------------------
@@ -110,6 +142,170 @@ impl RCursor {
     }
 }
 
+/// Cursor movements that `tree_sitter::TreeCursor` doesn't provide natively, implemented on top of
+/// the ones it does.
+pub(crate) trait CursorUtils {
+    fn goto_child_by_field_id(&mut self, field_id: u16) -> bool;
+
+    fn goto_first_named_child(&mut self) -> bool;
+
+    fn goto_next_named_sibling(&mut self) -> bool;
+
+    /// Return the index of the current node within its parent, or None if the cursor is on its
+    /// root node.
+    fn child_index(&self) -> Option<usize>;
+
+    /// Return the index of the current node among its parent's named children, or None if it's
+    /// anonymous, or the cursor is on its root node.
+    fn named_child_index(&self) -> Option<usize>;
+
+    /// Return the depth of the current node, relative to the cursor's root node.
+    ///
+    /// This walks up to the root node, so it's O(n) in the depth.
+    fn depth(&self) -> usize;
+
+    /// Move to the cursor's root node, i.e. the node it was created from, or last reset to.
+    fn goto_root(&mut self);
+
+    /// Move to the next node in depth-first pre-order. Return false if there is no such node, in
+    /// which case the cursor ends up on its root node.
+    fn goto_preorder_successor(&mut self) -> bool;
+
+    /// Return the pre-order index of the current node among the descendants of the cursor's root
+    /// node. The root node itself has index 0.
+    ///
+    /// This walks the tree from the root node, so it's O(n) in the index.
+    fn descendant_index(&self) -> usize;
+
+    /// Move to the descendant with the given pre-order index. Return false, without moving, if
+    /// there is no such node.
+    ///
+    /// This walks the tree from the root node, so it's O(n) in the index.
+    fn goto_descendant(&mut self, index: usize) -> bool;
+}
+
+/// Make the movement FIRST, then keep moving CURSOR forward through the siblings until PRED is
+/// satisfied. CURSOR is left unchanged if there is no such node.
+#[inline]
+fn goto_matching<'t, F, P>(cursor: &mut TreeCursor<'t>, first: F, pred: P) -> bool
+where
+    F: FnOnce(&mut TreeCursor<'t>) -> bool,
+    P: Fn(&TreeCursor<'t>) -> bool,
+{
+    let mut candidate = cursor.clone();
+    let mut found = first(&mut candidate);
+    while found && !pred(&candidate) {
+        found = candidate.goto_next_sibling();
+    }
+    if found {
+        *cursor = candidate;
+    }
+    found
+}
+
+impl<'t> CursorUtils for TreeCursor<'t> {
+    fn goto_child_by_field_id(&mut self, field_id: u16) -> bool {
+        goto_matching(self, Self::goto_first_child, |c| c.field_id() == Some(field_id))
+    }
+
+    fn goto_first_named_child(&mut self) -> bool {
+        goto_matching(self, Self::goto_first_child, |c| c.node().is_named())
+    }
+
+    fn goto_next_named_sibling(&mut self) -> bool {
+        goto_matching(self, Self::goto_next_sibling, |c| c.node().is_named())
+    }
+
+    fn child_index(&self) -> Option<usize> {
+        let node = self.node();
+        let mut cursor = self.clone();
+        if !cursor.goto_parent() {
+            return None;
+        }
+        cursor.goto_first_child();
+        let mut index = 0;
+        while cursor.node() != node {
+            if !cursor.goto_next_sibling() {
+                return None;
+            }
+            index += 1;
+        }
+        Some(index)
+    }
+
+    fn named_child_index(&self) -> Option<usize> {
+        let node = self.node();
+        if !node.is_named() {
+            return None;
+        }
+        let mut cursor = self.clone();
+        if !cursor.goto_parent() {
+            return None;
+        }
+        cursor.goto_first_child();
+        let mut index = 0;
+        while cursor.node() != node {
+            if cursor.node().is_named() {
+                index += 1;
+            }
+            if !cursor.goto_next_sibling() {
+                return None;
+            }
+        }
+        Some(index)
+    }
+
+    fn depth(&self) -> usize {
+        let mut cursor = self.clone();
+        let mut depth = 0;
+        while cursor.goto_parent() {
+            depth += 1;
+        }
+        depth
+    }
+
+    fn goto_root(&mut self) {
+        while self.goto_parent() {}
+    }
+
+    fn goto_preorder_successor(&mut self) -> bool {
+        if self.goto_first_child() {
+            return true;
+        }
+        loop {
+            if self.goto_next_sibling() {
+                return true;
+            }
+            if !self.goto_parent() {
+                return false;
+            }
+        }
+    }
+
+    fn descendant_index(&self) -> usize {
+        let node = self.node();
+        let mut cursor = self.clone();
+        cursor.goto_root();
+        let mut index = 0;
+        while cursor.node() != node && cursor.goto_preorder_successor() {
+            index += 1;
+        }
+        index
+    }
+
+    fn goto_descendant(&mut self, index: usize) -> bool {
+        let mut cursor = self.clone();
+        cursor.goto_root();
+        for _ in 0..index {
+            if !cursor.goto_preorder_successor() {
+                return false;
+            }
+        }
+        *self = cursor;
+        true
+    }
+}
+
 pub enum TreeOrNode<'e> {
     Tree(&'e Shared<Tree>),
     Node(&'e RefCell<RNode>),
@@ -128,6 +324,13 @@ impl<'e> FromLisp<'e> for TreeOrNode<'e> {
 }
 
 impl<'e> TreeOrNode<'e> {
+    pub(crate) fn tree(&self) -> Shared<Tree> {
+        match *self {
+            Self::Tree(tree) => tree.clone(),
+            Self::Node(node) => node.borrow().clone_tree(),
+        }
+    }
+
     fn walk(&self) -> RCursor {
         match *self {
             Self::Tree(tree) => RCursor::new(tree.clone(), |tree| tree.walk()),
@@ -153,6 +356,14 @@ fn make_cursor(tree_or_node: TreeOrNode) -> Result<RCursor> {
     Ok(tree_or_node.walk())
 }
 
+/// Return a new cursor that is on the same node as CURSOR.
+///
+/// The 2 cursors can be moved independently of each other.
+#[defun(user_ptr)]
+fn copy_cursor(cursor: &RCursor) -> Result<RCursor> {
+    Ok(cursor.clone())
+}
+
 /// Return the field id of CURSOR's current node.
 /// Return nil if the current node doesn't have a field.
 #[defun]
@@ -169,6 +380,43 @@ fn current_field(cursor: &RCursor) -> Result<Option<&'static GlobalRef>> {
     Ok(cursor.field_id().and_then(|id| language.info().field_name(id)))
 }
 
+/// Return the depth of CURSOR's current node, relative to the node CURSOR started from.
+///
+/// This takes time linear in the depth, since CURSOR's ancestors are walked to
+/// count them.
+#[defun]
+fn cursor_depth(cursor: &RCursor) -> Result<usize> {
+    Ok(cursor.borrow().depth())
+}
+
+/// Return the 0-based index of CURSOR's current node within its parent.
+/// Return nil if CURSOR is on the node it started from.
+#[defun]
+fn current_child_index(cursor: &RCursor) -> Result<Option<usize>> {
+    Ok(cursor.borrow().child_index())
+}
+
+/// Return the 0-based index of CURSOR's current node among its parent's named
+/// children. Return nil if the current node is anonymous, or if CURSOR is on the
+/// node it started from.
+#[defun]
+fn current_named_child_index(cursor: &RCursor) -> Result<Option<usize>> {
+    Ok(cursor.borrow().named_child_index())
+}
+
+/// Return the index of CURSOR's current node among all descendants of the node
+/// CURSOR started from, in depth-first pre-order. The starting node has index 0.
+///
+/// The index can be passed to `tsc-goto-descendant' later, to restore CURSOR's
+/// position.
+///
+/// This takes time linear in the index, since the nodes preceding the current one
+/// are walked to count them.
+#[defun]
+fn current_descendant_index(cursor: &RCursor) -> Result<usize> {
+    Ok(cursor.borrow().descendant_index())
+}
+
 macro_rules! defun_cursor_walks {
     ($($(#[$meta:meta])* $($lisp_name:literal)? fn $name:ident $( ( $( $param:ident $($into:ident)? : $itype:ty ),* ) )? -> $type:ty)*) => {
         $(
@@ -186,6 +434,10 @@ defun_cursor_walks! {
     /// Return t if CURSOR successfully moved, nil if there were no children.
     fn goto_first_child -> bool
 
+    /// Move CURSOR to the first named child of its current node.
+    /// Return t if CURSOR successfully moved, nil if there were no named children.
+    fn goto_first_named_child -> bool
+
     /// Move CURSOR to the parent node of its current node.
     /// Return t if CURSOR successfully moved, nil if it was already on the root node.
     fn goto_parent -> bool
@@ -194,9 +446,45 @@ defun_cursor_walks! {
     /// Return t if CURSOR successfully moved, nil if there was no next sibling node.
     fn goto_next_sibling -> bool
 
+    /// Move CURSOR to the next named sibling of its current node.
+    /// Return t if CURSOR successfully moved, nil if there was no next named sibling.
+    fn goto_next_named_sibling -> bool
+
     /// Move CURSOR to the first child that extends beyond the given BYTEPOS.
     /// Return the index of the child node if one was found, nil otherwise.
     "goto-first-child-for-byte" fn goto_first_child_for_byte(bytepos into: BytePos) -> Option<usize>
+
+    /// Move CURSOR to the first child that extends beyond the given POINT.
+    /// Return the index of the child node if one was found, nil otherwise.
+    ///
+    /// POINT should be a (LINE-NUMBER . BYTE-COLUMN) pair. See `tsc-parse-chunks' for
+    /// a more detailed explanation.
+    "goto-first-child-for-point" fn goto_first_child_for_point(point into: Point) -> Option<usize>
+
+    /// Move CURSOR to the descendant at the given pre-order INDEX, relative to the node
+    /// CURSOR started from. See `tsc-current-descendant-index'.
+    /// Return t if CURSOR successfully moved, nil if there was no such descendant.
+    ///
+    /// This takes time linear in INDEX, since the preceding nodes are walked.
+    fn goto_descendant(index: usize) -> bool
+
+    /// Move CURSOR to the first child of its current node that has the given numeric
+    /// FIELD-ID.
+    /// Return t if CURSOR successfully moved, nil if there was no such child.
+    fn goto_child_by_field_id(field_id: u16) -> bool
+}
+
+/// Move CURSOR to the first child of its current node that is associated with
+/// FIELD, which should be a keyword. For example: :body, :name.
+/// Return t if CURSOR successfully moved, nil if there was no such child.
+#[defun]
+fn goto_child_by_field(cursor: &mut RCursor, field: Value) -> Result<bool> {
+    let mut cursor = cursor.borrow_mut();
+    let language: Language = cursor.reft.language().into();
+    Ok(match language.info().field_id(field) {
+        Some(field_id) => cursor.goto_child_by_field_id(field_id),
+        None => false,
+    })
 }
 
 /// Re-initialize CURSOR to start at a different NODE.
@@ -205,6 +493,39 @@ fn reset_cursor(cursor: &mut RCursor, node: &RNode) -> Result<()> {
     Ok(cursor.borrow_mut().reset(*node.borrow()))
 }
 
+/// Return a snapshot of CURSOR's position, which can be restored later with
+/// `tsc-restore-cursor-state'.
+///
+/// The snapshot is a vector of [ROOT-NODE DESCENDANT-INDEX DEPTH], where ROOT-NODE
+/// is the node CURSOR started from. It does not reference CURSOR itself.
+///
+/// Like `tsc-current-descendant-index', this takes time linear in the number of
+/// nodes preceding CURSOR's current node.
+#[defun]
+fn cursor_state<'e>(env: &'e Env, cursor: &RCursor) -> Result<Vector<'e>> {
+    let current = cursor.borrow();
+    let mut root = current.clone();
+    root.goto_root();
+    let root = RNode::new(cursor.clone_tree(), |_| root.node());
+    env.vector((root, current.descendant_index(), current.depth()))
+}
+
+/// Move CURSOR to the position saved in STATE, which should have been returned by
+/// `tsc-cursor-state'. CURSOR is re-initialized to start from STATE's root node,
+/// which may belong to a different tree.
+///
+/// Return t if CURSOR successfully moved, nil if STATE's position could not be
+/// restored.
+#[defun]
+fn restore_cursor_state(cursor: &mut RCursor, state: Vector) -> Result<bool> {
+    let root = state.get::<&RefCell<RNode>>(0)?.borrow();
+    let index: usize = state.get(1)?;
+    let depth: usize = state.get(2)?;
+    *cursor = RCursor::new(root.clone_tree(), |_| root.borrow().walk());
+    let mut cursor = cursor.borrow_mut();
+    Ok(cursor.goto_descendant(index) && cursor.depth() == depth)
+}
+
 // -------------------------------------------------------------------------------------------------
 
 enum TraversalState {
@@ -216,16 +537,151 @@ enum TraversalState {
 
 use TraversalState::*;
 
+/// What happens to the node under the cursor after each traversal step.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Event {
+    /// The node is visited before its descendants.
+    Enter,
+    /// The node is visited after its descendants. LEAF is true if it turned out to have none.
+    Exit { leaf: bool },
+}
+
+impl IntoLisp<'_> for Event {
+    fn into_lisp(self, env: &Env) -> Result<Value> {
+        let symbol = match self {
+            Event::Enter => _enter,
+            Event::Exit { .. } => _exit,
+        };
+        Ok(symbol.bind(env))
+    }
+}
+
+/// The order in which nodes are yielded.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Order {
+    Pre,
+    Post,
+    /// Both pre-order and post-order. Nodes with descendants are yielded twice.
+    PrePost,
+}
+
+impl Order {
+    #[inline]
+    fn wants(self, event: Event) -> bool {
+        match self {
+            Order::Pre => event == Event::Enter,
+            Order::Post => matches!(event, Event::Exit { .. }),
+            Order::PrePost => event != Event::Exit { leaf: true },
+        }
+    }
+}
+
+/// Options controlling a traversal, given as a plist by the Lisp side.
+#[derive(Clone)]
+struct TraversalOptions {
+    order: Order,
+    leaves_only: bool,
+    named_only: bool,
+    reverse: bool,
+    start_byte: usize,
+    end_byte: usize,
+    max_depth: Option<usize>,
+    /// Whether the callback also receives the node's depth. This doesn't affect the traversal
+    /// itself, only how the callback is called.
+    with_depth: bool,
+    /// The source code that the traversed tree was parsed from. If given, the property `:text` is
+    /// extracted from it, instead of from the current buffer.
+    source: Option<String>,
+}
+
+impl Default for TraversalOptions {
+    fn default() -> Self {
+        Self {
+            order: Order::Pre,
+            leaves_only: false,
+            named_only: false,
+            reverse: false,
+            start_byte: 0,
+            end_byte: usize::MAX,
+            max_depth: None,
+            with_depth: false,
+            source: None,
+        }
+    }
+}
+
+impl FromLisp<'_> for TraversalOptions {
+    fn from_lisp(plist: Value) -> Result<Self> {
+        let env = plist.env;
+        let mut options = Self::default();
+        let mut rest = plist;
+        while rest.is_not_nil() {
+            let key: Value = rest.car()?;
+            rest = rest.cdr()?;
+            let value: Value = rest.car()?;
+            rest = rest.cdr()?;
+            if key.eq(_order.bind(env)) {
+                options.order = if value.eq(_post.bind(env)) {
+                    Order::Post
+                } else if value.eq(_pre_post.bind(env)) {
+                    Order::PrePost
+                } else {
+                    Order::Pre
+                };
+            } else if key.eq(_leaves_only.bind(env)) {
+                options.leaves_only = value.is_not_nil();
+            } else if key.eq(_named_only.bind(env)) {
+                options.named_only = value.is_not_nil();
+            } else if key.eq(_reverse.bind(env)) {
+                options.reverse = value.is_not_nil();
+            } else if key.eq(_start_byte.bind(env)) {
+                options.start_byte = value.into_rust::<BytePos>()?.into();
+            } else if key.eq(_end_byte.bind(env)) {
+                options.end_byte = value.into_rust::<BytePos>()?.into();
+            } else if key.eq(_max_depth.bind(env)) {
+                options.max_depth = value.into_rust()?;
+            } else if key.eq(_with_depth.bind(env)) {
+                options.with_depth = value.is_not_nil();
+            } else if key.eq(_source.bind(env)) {
+                options.source = value.into_rust()?;
+            }
+        }
+        Ok(options)
+    }
+}
+
+/// Where a node is, relative to a traversal's byte range.
+enum Placement {
+    Intersecting,
+    Disjoint,
+    /// Disjoint, and so are all the siblings that come after it, in traversal order.
+    Beyond,
+}
+
 struct DepthFirstIterator {
     cursor: RCursor,
+    /// In reverse traversal, the siblings that are yet to be visited, at each depth below the
+    /// iterator's root. Siblings are only linked forward, so they are collected in one pass when
+    /// the parent is descended into.
+    pending_siblings: Vec<Vec<RCursor>>,
     state: TraversalState,
     depth: usize,
+    event: Event,
+    skip_children: bool,
+    options: TraversalOptions,
 }
 
-// TODO: Provide a function to move backward.
 impl DepthFirstIterator {
-    fn new(tree_or_node: TreeOrNode) -> Self {
-        Self { cursor: tree_or_node.walk(), state: Start, depth: 0 }
+    fn new(tree_or_node: TreeOrNode, options: TraversalOptions) -> Self {
+        Self {
+            cursor: tree_or_node.walk(),
+            pending_siblings: vec![],
+            state: Start,
+            depth: 0,
+            event: Event::Enter,
+            skip_children: false,
+            options,
+        }
     }
 
     #[inline]
@@ -236,48 +692,167 @@ impl DepthFirstIterator {
     fn close(&mut self) {
         self.state = Done;
     }
-}
 
-impl Iterator for DepthFirstIterator {
-    type Item = (RNode, usize);
+    /// Don't descend into the current node's children. This has no effect unless the current
+    /// node was just entered.
+    fn skip_children(&mut self) {
+        if self.event == Event::Enter {
+            self.skip_children = true;
+        }
+    }
 
-    fn next(&mut self) -> Option<Self::Item> {
-        match self.state {
-            Start => {
-                self.state = Down;
-                self.item()
+    /// Return true if the node under the cursor should be yielded, after EVENT happened to it.
+    #[inline]
+    fn accepts(&self, event: Event) -> bool {
+        let options = &self.options;
+        if !options.order.wants(event) {
+            return false;
+        }
+        let node = self.cursor.borrow().node();
+        if options.named_only && !node.is_named() {
+            return false;
+        }
+        if options.leaves_only && node.child_count() > 0 {
+            return false;
+        }
+        true
+    }
+
+    /// Move the cursor to the first child to visit, which is the last child in reverse traversal.
+    /// Return false without moving if the traversal must not go deeper.
+    #[inline]
+    fn goto_first_child(&mut self) -> bool {
+        if self.skip_children {
+            self.skip_children = false;
+            return false;
+        }
+        if self.options.max_depth.map_or(false, |max_depth| self.depth >= max_depth) {
+            return false;
+        }
+        if !self.options.reverse {
+            return self.cursor.borrow_mut().goto_first_child();
+        }
+        let mut candidate = self.cursor.clone();
+        if !candidate.borrow_mut().goto_first_child() {
+            return false;
+        }
+        let mut siblings = vec![];
+        loop {
+            let current = candidate.clone();
+            if !candidate.borrow_mut().goto_next_sibling() {
+                break;
             }
-            Down => {
-                if self.cursor.borrow_mut().goto_first_child() {
-                    self.depth += 1;
-                    self.item()
-                } else {
-                    self.state = Right;
-                    self.next()
-                }
+            siblings.push(current);
+        }
+        self.cursor = candidate;
+        self.pending_siblings.push(siblings);
+        true
+    }
+
+    /// Move the cursor to the next sibling to visit, which is the previous sibling in reverse
+    /// traversal.
+    #[inline]
+    fn goto_next_sibling(&mut self) -> bool {
+        if !self.options.reverse {
+            return self.cursor.borrow_mut().goto_next_sibling();
+        }
+        match self.pending_siblings.last_mut().and_then(|siblings| siblings.pop()) {
+            Some(sibling) => {
+                self.cursor = sibling;
+                true
             }
-            Right => {
-                if self.cursor.borrow_mut().goto_next_sibling() {
+            None => false,
+        }
+    }
+
+    /// Move the cursor up to the parent, after its children have been handled.
+    #[inline]
+    fn ascend(&mut self) -> Option<Event> {
+        if self.options.reverse {
+            self.pending_siblings.pop();
+        }
+        if self.cursor.borrow_mut().goto_parent() {
+            self.depth -= 1;
+            self.state = Right;
+            Some(Event::Exit { leaf: false })
+        } else {
+            self.state = Done;
+            None
+        }
+    }
+
+    /// Return where the node under the cursor is, relative to the traversal's byte range.
+    #[inline]
+    fn placement(&self) -> Placement {
+        let TraversalOptions { start_byte, end_byte, reverse, .. } = self.options;
+        let node = self.cursor.borrow().node();
+        let (start, end) = (node.start_byte(), node.end_byte());
+        if start < end_byte && (end > start_byte || start >= start_byte) {
+            Placement::Intersecting
+        } else if (!reverse && start >= end_byte) || (reverse && end <= start_byte && start < start_byte) {
+            Placement::Beyond
+        } else {
+            Placement::Disjoint
+        }
+    }
+
+    /// Move the cursor one step, returning what happens to the node it lands on, or None if the
+    /// traversal is finished. Nodes outside of the traversal's byte range are skipped, together
+    /// with their descendants.
+    fn step(&mut self) -> Option<Event> {
+        loop {
+            match self.state {
+                Start => {
                     self.state = Down;
-                    self.item()
-                } else if self.cursor.borrow_mut().goto_parent() {
-                    self.depth -= 1;
-                    self.next()
-                } else {
-                    self.state = Done;
-                    self.next()
                 }
+                Down => {
+                    if self.goto_first_child() {
+                        self.depth += 1;
+                    } else {
+                        self.state = Right;
+                        return Some(Event::Exit { leaf: true });
+                    }
+                }
+                Right => {
+                    if self.goto_next_sibling() {
+                        self.state = Down;
+                    } else {
+                        return self.ascend();
+                    }
+                }
+                Done => return None,
+            }
+            // The cursor just landed on a node that has not been visited.
+            match self.placement() {
+                Placement::Intersecting => return Some(Event::Enter),
+                Placement::Disjoint => self.state = Right,
+                // The remaining siblings are out of range as well.
+                Placement::Beyond => return self.ascend(),
+            }
+        }
+    }
+}
+
+impl Iterator for DepthFirstIterator {
+    type Item = (RNode, usize);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let event = self.step()?;
+            if self.accepts(event) {
+                self.event = event;
+                return self.item();
             }
-            Done => None,
         }
     }
 }
 
 /// Create a new depth-first iterator from the given TREE-OR-NODE.
-/// The traversal is pre-order.
+///
+/// OPTIONS is a plist controlling the traversal. See `tsc-traverse-iter'.
 #[defun(user_ptr)]
-fn _iter(tree_or_node: TreeOrNode) -> Result<DepthFirstIterator> {
-    Ok(DepthFirstIterator::new(tree_or_node))
+fn _iter(tree_or_node: TreeOrNode, options: TraversalOptions) -> Result<DepthFirstIterator> {
+    Ok(DepthFirstIterator::new(tree_or_node, options))
 }
 
 /// Move ITERATOR to the next node.
@@ -294,28 +869,330 @@ fn _iter_close(iterator: &mut DepthFirstIterator) -> Result<()> {
     Ok(iterator.close())
 }
 
+// -------------------------------------------------------------------------------------------------
+
+/// An iterator that walks 2 syntax trees in lock-step, yielding the pairs of corresponding nodes
+/// whose structure differs, i.e. whose types or numbers of children are not the same. The
+/// descendants of such a pair are not compared.
+///
+/// Subtrees that are shared between the 2 trees (e.g. the parts of a tree that were reused when
+/// re-parsing after an edit) are skipped without being walked.
+struct DiffIterator {
+    old: RCursor,
+    new: RCursor,
+    started: bool,
+    descend: bool,
+    done: bool,
+}
+
+impl DiffIterator {
+    fn new(old: TreeOrNode, new: TreeOrNode) -> Self {
+        Self { old: old.walk(), new: new.walk(), started: false, descend: false, done: false }
+    }
+
+    /// Move both cursors to the next pair of corresponding nodes that are not descendants of the
+    /// current pair. Return false if there is no such pair.
+    fn goto_next_pair(&mut self) -> bool {
+        let mut old = self.old.borrow_mut();
+        let mut new = self.new.borrow_mut();
+        loop {
+            // The cursors only ever descend into nodes with the same number of children, so they
+            // have the same siblings, and reach their starting nodes at the same time.
+            if old.goto_next_sibling() && new.goto_next_sibling() {
+                return true;
+            }
+            if !(old.goto_parent() && new.goto_parent()) {
+                return false;
+            }
+        }
+    }
+}
+
+impl Iterator for DiffIterator {
+    type Item = ();
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if self.done {
+                return None;
+            }
+            if self.started {
+                if self.descend && self.old.borrow_mut().goto_first_child() {
+                    self.new.borrow_mut().goto_first_child();
+                } else if !self.goto_next_pair() {
+                    self.done = true;
+                    return None;
+                }
+            }
+            self.started = true;
+            let old = self.old.borrow().node();
+            let new = self.new.borrow().node();
+            if old.id() == new.id() {
+                self.descend = false;
+                continue;
+            }
+            if old.kind_id() != new.kind_id()
+                || old.child_count() != new.child_count()
+                || old.is_missing() != new.is_missing()
+            {
+                self.descend = false;
+                return Some(());
+            }
+            self.descend = true;
+        }
+    }
+}
+
+/// Create a new iterator that walks OLD and NEW, each of which is a tree or a node,
+/// in lock-step. See `tsc-diff-iter'.
+#[defun(user_ptr)]
+fn _diff_iter(old: TreeOrNode, new: TreeOrNode) -> Result<DiffIterator> {
+    Ok(DiffIterator::new(old, new))
+}
+
+/// Move ITERATOR to the next pair of nodes whose structure differs, and return them as a cons
+/// cell (OLD-NODE . NEW-NODE). Return nil if there is no such pair.
+#[defun]
+fn _diff_iter_next<'e>(iterator: &mut DiffIterator, env: &'e Env) -> Result<Value<'e>> {
+    if iterator.next().is_none() {
+        return ().into_lisp(env);
+    }
+    let old = RNode::new(iterator.old.clone_tree(), |_| iterator.old.borrow().node());
+    let new = RNode::new(iterator.new.clone_tree(), |_| iterator.new.borrow().node());
+    env.cons(old, new)
+}
+
+/// A node property, resolved from its keyword once, so that retrieving it for a large number of
+/// nodes doesn't involve comparing keywords.
+#[derive(Clone, Copy)]
+enum Prop<'e> {
+    Type,
+    NamedP,
+    ExtraP,
+    ErrorP,
+    MissingP,
+    HasErrorP,
+    StartByte,
+    StartPoint,
+    EndByte,
+    EndPoint,
+    Range,
+    ByteRange,
+    Text,
+    Id,
+    KindId,
+    FieldId,
+    ChildCount,
+    NamedChildCount,
+    Field,
+    ParentType,
+    ParentField,
+    Depth,
+    Event,
+    /// A Lisp function, whose value is the result of calling it with the node.
+    Function(Value<'e>),
+    /// Not a valid property. Its value is always nil.
+    Unknown,
+}
+
+impl<'e> FromLisp<'e> for Prop<'e> {
+    fn from_lisp(prop: Value<'e>) -> Result<Self> {
+        let env = prop.env;
+        macro_rules! resolve {
+            ($($name:ident => $variant:ident,)*) => {
+                $(if prop.eq($name.bind(env)) {
+                    return Ok(Self::$variant);
+                })*
+            };
+        }
+        resolve! {
+            _type              => Type,
+            _named_p           => NamedP,
+            _extra_p           => ExtraP,
+            _error_p           => ErrorP,
+            _missing_p         => MissingP,
+            _has_error_p       => HasErrorP,
+            _start_byte        => StartByte,
+            _start_point       => StartPoint,
+            _end_byte          => EndByte,
+            _end_point         => EndPoint,
+            _range             => Range,
+            _byte_range        => ByteRange,
+            _text              => Text,
+            _id                => Id,
+            _kind_id           => KindId,
+            _field_id          => FieldId,
+            _child_count       => ChildCount,
+            _named_child_count => NamedChildCount,
+            _field             => Field,
+            _parent_type       => ParentType,
+            _parent_field      => ParentField,
+            _depth             => Depth,
+            _event             => Event,
+        }
+        if env.call(functionp, [prop])?.is_not_nil() {
+            return Ok(Self::Function(prop));
+        }
+        // FIX: Signal an error instead.
+        Ok(Self::Unknown)
+    }
+}
+
+/// What to retrieve for each node, as specified by a PROPS argument: the node itself (nil), a
+/// single property (a keyword), multiple properties (a vector of keywords), or a plist of multiple
+/// properties (a list of keywords).
+enum Props<'e> {
+    Node,
+    Single(Prop<'e>),
+    Multiple(Vec<Prop<'e>>),
+    Plist(Vec<(Value<'e>, Prop<'e>)>),
+}
+
+impl<'e> FromLisp<'e> for Props<'e> {
+    fn from_lisp(value: Value<'e>) -> Result<Self> {
+        if !value.is_not_nil() {
+            return Ok(Self::Node);
+        }
+        if let Ok(props) = value.into_rust::<Vector>() {
+            let props = props.into_iter().map(|prop| prop.into_rust()).collect::<Result<_>>()?;
+            return Ok(Self::Multiple(props));
+        }
+        let prop = value.into_rust()?;
+        if !matches!(prop, Prop::Unknown) || value.car::<Value>().is_err() {
+            return Ok(Self::Single(prop));
+        }
+        let mut props = vec![];
+        let mut rest = value;
+        while rest.is_not_nil() {
+            let key: Value = rest.car()?;
+            props.push((key, key.into_rust()?));
+            rest = rest.cdr()?;
+        }
+        Ok(Self::Plist(props))
+    }
+}
+
+/// Where to write a node's properties: a vector, or a record (e.g. an instance of a type defined
+/// by `cl-defstruct'), whose slots after the type tag are filled in order.
 #[derive(Clone, Copy)]
-enum VectorOrKeyword<'e> {
+enum Output<'e> {
     Vector(Vector<'e>),
-    Keyword(Value<'e>),
+    Record(Value<'e>),
 }
 
-impl<'e> FromLisp<'e> for VectorOrKeyword<'e> {
+impl<'e> FromLisp<'e> for Output<'e> {
     fn from_lisp(value: Value<'e>) -> Result<Self> {
-        if let Ok(value) = value.into_rust::<Vector>() {
-            Ok(Self::Vector(value))
+        if let Ok(vector) = value.into_rust::<Vector>() {
+            return Ok(Self::Vector(vector));
+        }
+        let env = value.env;
+        if env.call(recordp, [value])?.is_not_nil() {
+            Ok(Self::Record(value))
         } else {
-            // TODO: Verify that it's a valid node property
-            Ok(Self::Keyword(value))
+            env.signal(wrong_type_argument, (vectorp, value))
+        }
+    }
+}
+
+impl<'e> IntoLisp<'e> for Output<'e> {
+    fn into_lisp(self, env: &'e Env) -> Result<Value<'e>> {
+        match self {
+            Self::Vector(vector) => vector.into_lisp(env),
+            Self::Record(record) => Ok(record),
+        }
+    }
+}
+
+impl<'e> Output<'e> {
+    #[inline]
+    fn set(&self, i: usize, value: Value<'e>) -> Result<()> {
+        match self {
+            Self::Vector(vector) => vector.set(i, value),
+            // The emacs-module API only supports vectors, so this goes through Lisp.
+            Self::Record(record) => record.env.call(aset, (*record, i + 1, value)).map(|_| ()),
+        }
+    }
+}
+
+/// Return the node itself, or its properties as specified by PROPS, retrieving each property with
+/// GET. If PROPS is a vector, and OUTPUT is non-nil, the properties are written into OUTPUT. If
+/// PROPS is a list, a fresh plist is returned.
+#[inline]
+fn collect<'e, N, G>(
+    props: &Props<'e>,
+    output: Option<Output<'e>>,
+    env: &'e Env,
+    node: N,
+    get: G,
+) -> Result<Value<'e>>
+where
+    N: FnOnce() -> RNode,
+    G: Fn(Prop<'e>) -> Result<Value<'e>>,
+{
+    match props {
+        Props::Node => node().into_lisp(env),
+        Props::Single(prop) => get(*prop),
+        Props::Multiple(props) => {
+            let result = match output {
+                None => Output::Vector(env.make_vector(props.len(), ())?),
+                Some(output) => output,
+            };
+            for (i, prop) in props.iter().enumerate() {
+                result.set(i, get(*prop)?)?;
+            }
+            result.into_lisp(env)
+        }
+        Props::Plist(props) => {
+            let mut result = ().into_lisp(env)?;
+            for (key, prop) in props.iter().rev() {
+                result = env.cons(*key, env.cons(get(*prop)?, result)?)?;
+            }
+            Ok(result)
         }
     }
 }
 
+impl DepthFirstIterator {
+    /// Return the property PROP of the current node.
+    #[inline]
+    fn get<'e>(&self, prop: Prop<'e>, env: &'e Env) -> Result<Value<'e>> {
+        match prop {
+            Prop::Depth => self.depth.into_lisp(env),
+            Prop::Event => self.event.into_lisp(env),
+            Prop::Text => match &self.options.source {
+                Some(source) => {
+                    node::text_in(env, self.cursor.borrow().node(), source)?.into_lisp(env)
+                }
+                None => get(prop, self.cursor.borrow().node(), &self.cursor, env),
+            },
+            _ => get(prop, self.cursor.borrow().node(), &self.cursor, env),
+        }
+    }
+
+    /// Return the current node, or its properties, as specified by PROPS.
+    #[inline]
+    fn current<'e>(
+        &self,
+        props: &Props<'e>,
+        output: Option<Output<'e>>,
+        env: &'e Env,
+    ) -> Result<Value<'e>> {
+        collect(
+            props,
+            output,
+            env,
+            || RNode::new(self.cursor.clone_tree(), |_| self.cursor.borrow().node()),
+            |prop| self.get(prop, env),
+        )
+    }
+}
+
 /// Return the properties of ITERATOR's current node, or the node itself.
 ///
 /// If PROPS is a vector of property names, return a vector containing the node's
 /// corresponding properties. If OUTPUT is also non-nil, it must be a vector of the
-/// same length, where the properties will be written into.
+/// same length, where the properties will be written into. OUTPUT can also be a
+/// record with that many slots, e.g. an instance of a `cl-defstruct' type.
 ///
 /// If PROPS is a single property name, return that property.
 ///
@@ -325,31 +1202,11 @@ impl<'e> FromLisp<'e> for VectorOrKeyword<'e> {
 #[defun]
 fn _iter_current_node<'e>(
     iterator: &mut DepthFirstIterator,
-    props: Option<VectorOrKeyword<'e>>,
-    output: Option<Vector<'e>>,
+    props: Props<'e>,
+    output: Option<Output<'e>>,
     env: &'e Env,
 ) -> Result<Value<'e>> {
-    let cursor = &iterator.cursor;
-    match props {
-        Some(VectorOrKeyword::Keyword(prop)) if prop.eq(_depth.bind(env)) => {
-            iterator.depth.into_lisp(env)
-        }
-        _ => {
-            let result = _current_node(cursor, props, output, env)?;
-            if let Some(VectorOrKeyword::Vector(props)) = props {
-                if let Some(output) = output {
-                    for (i, prop) in props.into_iter().enumerate() {
-                        if prop.eq(_depth.bind(env)) {
-                            output.set(i, iterator.depth)?;
-                        }
-                    }
-                } else {
-                    todo!()
-                }
-            }
-            Ok(result)
-        }
-    }
+    iterator.current(&props, output, env)
 }
 
 /// Move ITERATOR to the next node, and retrieve its properties, or the node itself.
@@ -358,58 +1215,99 @@ fn _iter_current_node<'e>(
 #[defun]
 fn _iter_next_node<'e>(
     iterator: &mut DepthFirstIterator,
-    props: Option<VectorOrKeyword<'e>>,
-    output: Option<Vector<'e>>,
+    props: Props<'e>,
+    output: Option<Output<'e>>,
     env: &'e Env,
 ) -> Result<Option<Value<'e>>> {
     if iterator.next().is_some() {
-        Ok(Some(_iter_current_node(iterator, props, output, env)?))
+        Ok(Some(iterator.current(&props, output, env)?))
     } else {
         Ok(None)
     }
 }
 
-fn get<'e>(prop: Value<'e>, node: Node, cursor: &RCursor) -> Result<Value<'e>> {
-    macro_rules! sugar {
-        ($prop:ident, $env:ident) => {
-            macro_rules! eq {
-                ($name:ident) => {
-                    $prop.eq($name.bind($env))
-                };
+/// Move ITERATOR forward, writing the properties of as many nodes as OUTPUT can hold into it.
+/// Return the number of nodes written. A number smaller than OUTPUT's capacity means the
+/// traversal has finished.
+///
+/// If PROPS is a vector of K property names, OUTPUT is treated as a flat sequence of K-slot
+/// chunks, where the I-th node's properties are written into the slots from I*K to (I+1)*K-1.
+/// Otherwise, each node takes a single slot, which holds its property PROPS, or the node itself
+/// if PROPS is nil.
+///
+/// This is a batched version of `tsc--iter-next-node', which crosses the module boundary once
+/// per batch, instead of once per node.
+#[defun]
+fn _iter_next_nodes<'e>(
+    iterator: &mut DepthFirstIterator,
+    props: Props<'e>,
+    output: Vector<'e>,
+    env: &'e Env,
+) -> Result<usize> {
+    let stride = match &props {
+        Props::Multiple(props) => props.len().max(1),
+        _ => 1,
+    };
+    let capacity = output.len() / stride;
+    let mut count = 0;
+    while count < capacity && iterator.next().is_some() {
+        let offset = count * stride;
+        match &props {
+            Props::Multiple(props) => {
+                for (i, prop) in props.iter().enumerate() {
+                    output.set(offset + i, iterator.get(*prop, env)?)?;
+                }
             }
-        };
+            _ => output.set(offset, iterator.current(&props, None, env)?)?,
+        }
+        count += 1;
     }
-    let env = prop.env;
-    sugar!(prop, env);
-    if eq!(_type) {
-        node.lisp_type().into_lisp(env)
-    } else if eq!(_byte_range) {
-        node.lisp_byte_range(env)
-    } else if eq!(_start_byte) {
-        node.lisp_start_byte().into_lisp(env)
-    } else if eq!(_end_byte) {
-        node.lisp_end_byte().into_lisp(env)
-    } else if eq!(_field) {
-        current_field(cursor)?.into_lisp(env)
-    } else if eq!(_named_p) {
-        node.is_named().into_lisp(env)
-    } else if eq!(_extra_p) {
-        node.is_extra().into_lisp(env)
-    } else if eq!(_error_p) {
-        node.is_error().into_lisp(env)
-    } else if eq!(_missing_p) {
-        node.is_missing().into_lisp(env)
-    } else if eq!(_has_error_p) {
-        node.has_error().into_lisp(env)
-    } else if eq!(_start_point) {
-        node.lisp_start_point().into_lisp(env)
-    } else if eq!(_end_point) {
-        node.lisp_end_point().into_lisp(env)
-    } else if eq!(_range) {
-        node.lisp_range().into_lisp(env)
-    } else {
-        // FIX: Signal an error instead.
-        ().into_lisp(env)
+    Ok(count)
+}
+
+/// Return the property PROP of NODE, which is CURSOR's current node.
+fn get<'e>(prop: Prop<'e>, node: Node, cursor: &RCursor, env: &'e Env) -> Result<Value<'e>> {
+    match prop {
+        Prop::Type => node.lisp_type().into_lisp(env),
+        Prop::ByteRange => node.lisp_byte_range(env),
+        Prop::StartByte => node.lisp_start_byte().into_lisp(env),
+        Prop::EndByte => node.lisp_end_byte().into_lisp(env),
+        Prop::Field => current_field(cursor)?.into_lisp(env),
+        Prop::NamedP => node.is_named().into_lisp(env),
+        Prop::ExtraP => node.is_extra().into_lisp(env),
+        Prop::ErrorP => node.is_error().into_lisp(env),
+        Prop::MissingP => node.is_missing().into_lisp(env),
+        Prop::HasErrorP => node.has_error().into_lisp(env),
+        Prop::StartPoint => node.lisp_start_point().into_lisp(env),
+        Prop::EndPoint => node.lisp_end_point().into_lisp(env),
+        Prop::Range => node.lisp_range().into_lisp(env),
+        Prop::KindId => node.kind_id().into_lisp(env),
+        Prop::FieldId => cursor.borrow().field_id().into_lisp(env),
+        Prop::Text => {
+            let range = (node.lisp_start_byte(), node.lisp_end_byte());
+            env.call(tsc__buffer_substring_no_properties, range)
+        }
+        Prop::Id => node.id().into_lisp(env),
+        Prop::ChildCount => node.child_count().into_lisp(env),
+        Prop::NamedChildCount => node.named_child_count().into_lisp(env),
+        Prop::ParentType | Prop::ParentField => {
+            let mut parent: TreeCursor = cursor.borrow().clone();
+            if !parent.goto_parent() {
+                return ().into_lisp(env);
+            }
+            if let Prop::ParentType = prop {
+                parent.node().lisp_type().into_lisp(env)
+            } else {
+                let language: Language = node.language().into();
+                parent.field_id().and_then(|id| language.info().field_name(id)).into_lisp(env)
+            }
+        }
+        Prop::Function(func) => {
+            let node = RNode::new(cursor.clone_tree(), |_| node).into_lisp(env)?;
+            func.call([node])
+        }
+        // Only iterators keep track of these.
+        Prop::Depth | Prop::Event | Prop::Unknown => ().into_lisp(env),
     }
 }
 
@@ -417,7 +1315,8 @@ fn get<'e>(prop: Value<'e>, node: Node, cursor: &RCursor) -> Result<Value<'e>> {
 ///
 /// If PROPS is a vector of property names, return a vector containing the node's
 /// corresponding properties. If OUTPUT is also non-nil, it must be a vector of the
-/// same length, where the properties will be written into.
+/// same length, where the properties will be written into. OUTPUT can also be a
+/// record with that many slots, e.g. an instance of a `cl-defstruct' type.
 ///
 /// If PROPS is a single property name, return that property.
 ///
@@ -427,25 +1326,46 @@ fn get<'e>(prop: Value<'e>, node: Node, cursor: &RCursor) -> Result<Value<'e>> {
 #[defun]
 fn _current_node<'e>(
     cursor: &RCursor,
-    props: Option<VectorOrKeyword<'e>>,
-    output: Option<Vector<'e>>,
+    props: Props<'e>,
+    output: Option<Output<'e>>,
     env: &'e Env,
 ) -> Result<Value<'e>> {
     let node = cursor.borrow().node();
-    match props {
-        None => RNode::new(cursor.clone_tree(), |_| node).into_lisp(env),
-        Some(VectorOrKeyword::Vector(props)) => {
-            let result = match output {
-                None => env.make_vector(props.len(), ())?,
-                Some(output) => output,
-            };
-            for (i, prop) in props.into_iter().enumerate() {
-                result.set(i, get(prop, node, cursor)?)?;
-            }
-            result.into_lisp(env)
-        }
-        Some(VectorOrKeyword::Keyword(prop)) => get(prop, node, cursor),
+    collect(
+        &props,
+        output,
+        env,
+        || RNode::new(cursor.clone_tree(), |_| node),
+        |prop| get(prop, node, cursor, env),
+    )
+}
+
+/// Traverse TREE-OR-NODE, calling F with the iterator, and the value to report for each visited
+/// node: either the node itself, or its properties, as specified by PROPS.
+///
+/// PROPS is resolved once, before the traversal starts. If it is a vector, the same output vector
+/// is reused for all the nodes.
+fn traverse<'e, F>(
+    env: &'e Env,
+    tree_or_node: TreeOrNode,
+    props: Props<'e>,
+    options: TraversalOptions,
+    mut f: F,
+) -> Result<()>
+where
+    F: FnMut(&mut DepthFirstIterator, Value<'e>) -> Result<()>,
+{
+    let mut iterator = DepthFirstIterator::new(tree_or_node, options);
+    let output = match &props {
+        Props::Multiple(props) => Some(Output::Vector(env.make_vector(props.len(), ())?)),
+        _ => None,
+    };
+    // Can't use a for loop because we need to access the cursor to process each item.
+    while iterator.next().is_some() {
+        let result = iterator.current(&props, output, env)?;
+        f(&mut iterator, result)?;
     }
+    Ok(())
 }
 
 /// Actual logic of `tsc-traverse-mapc'. The wrapper is needed because
@@ -454,50 +1374,23 @@ fn _current_node<'e>(
 fn _traverse_mapc(
     func: Value,
     tree_or_node: TreeOrNode,
-    props: Option<VectorOrKeyword>,
+    props: Props,
+    options: TraversalOptions,
 ) -> Result<()> {
-    let mut iterator = DepthFirstIterator::new(tree_or_node);
     let env = func.env;
-    let mut output = None;
-    let mut depth_indexes = Vec::with_capacity(1);
-    let mut depth = false;
-    match props {
-        Some(VectorOrKeyword::Vector(props)) => {
-            output = Some(env.make_vector(props.len(), ())?);
-            for (i, prop) in props.into_iter().enumerate() {
-                if prop.eq(_depth.bind(env)) {
-                    depth_indexes.push(i)
-                }
-            }
-        }
-        Some(VectorOrKeyword::Keyword(prop)) if prop.eq(_depth.bind(env)) => {
-            depth = true;
-        }
-        _ => {}
-    }
-    // Can't use a for loop because we need to access the cursor to process each item.
-    let mut item: Option<(RNode, usize)> = iterator.next();
-    while item.is_some() {
-        let result = if depth {
-            iterator.depth.into_lisp(env)?
+    let with_depth = options.with_depth;
+    traverse(env, tree_or_node, props, options, |iterator, result| {
+        // Safety: the returned value is only compared against a symbol, before anything else can
+        // trigger garbage collection.
+        let returned = if with_depth {
+            unsafe { func.call_unprotected((result, iterator.depth))? }
         } else {
-            let result = _current_node(&iterator.cursor, props, output, env)?;
-            if let Some(output) = output {
-                for i in &depth_indexes {
-                    output.set(*i, iterator.depth)?;
-                }
-            }
-            result
+            unsafe { func.call_unprotected([result])? }
         };
-
-        // Safety: the returned value is unused.
-        unsafe {
-            func.call_unprotected([result])?;
+        if returned.eq(_skip_children.bind(env)) {
+            iterator.skip_children();
         }
 
-        // // Safety: the returned value is unused.
-        // unsafe { func.call_unprotected((result, depth))?; }
-
         // // 0
         // unsafe { func.call_unprotected([])?; }
 
@@ -517,11 +1410,127 @@ fn _traverse_mapc(
         // use emacs::call::IntoLispArgs;
         // (result, depth).into_lisp_args(env)?;
 
-        item = iterator.next();
+        Ok(())
+    })
+}
+
+/// Actual logic of `tsc-traverse-fold'. The wrapper is needed because
+/// `emacs-module-rs' doesn't currently support optional arguments.
+#[defun]
+fn _traverse_fold<'e>(
+    func: Value<'e>,
+    init: Value<'e>,
+    tree_or_node: TreeOrNode<'e>,
+    props: Props<'e>,
+    options: TraversalOptions,
+) -> Result<Value<'e>> {
+    let mut acc = init;
+    let with_depth = options.with_depth;
+    traverse(func.env, tree_or_node, props, options, |iterator, result| {
+        acc = if with_depth {
+            func.call((acc, result, iterator.depth))?
+        } else {
+            func.call((acc, result))?
+        };
+        Ok(())
+    })?;
+    Ok(acc)
+}
+
+// -------------------------------------------------------------------------------------------------
+
+/// Return a vector of the syntax errors in TREE-OR-NODE: its ERROR nodes and missing
+/// nodes, in order. Each element has the form [NODE START-BYTEPOS END-BYTEPOS MISSING].
+///
+/// MISSING is nil for ERROR nodes. For missing nodes, it is the type of the node that
+/// the parser inserted to recover from the error, e.g. ";" if a semicolon is missing.
+///
+/// Subtrees without syntax errors are skipped, which is much faster than traversing
+/// the whole tree. The descendants of ERROR nodes are not included.
+#[defun]
+fn collect_error_nodes<'e>(env: &'e Env, tree_or_node: TreeOrNode) -> Result<Vector<'e>> {
+    let mut rcursor = tree_or_node.walk();
+    let tree = rcursor.clone_tree();
+    let mut errors = vec![];
+    let cursor = &mut rcursor.borrow_mut();
+    'traversal: loop {
+        let node = cursor.node();
+        let descend = if node.is_error() || node.is_missing() {
+            let error = RNode::new(tree.clone(), |_| node);
+            let (beg, end) = (node.lisp_start_byte(), node.lisp_end_byte());
+            let missing = if node.is_missing() { Some(node.lisp_type()) } else { None };
+            errors.push(env.vector((error, beg, end, missing))?);
+            false
+        } else {
+            node.has_error()
+        };
+        if descend && cursor.goto_first_child() {
+            continue;
+        }
+        while !cursor.goto_next_sibling() {
+            if !cursor.goto_parent() {
+                break 'traversal;
+            }
+        }
     }
-    // for (_, depth) in iterator {
-    //     let result = _current_node(&iterator.cursor.clone(), props, output, env)?;
-    //     func.call((result, depth))?;
-    // }
-    Ok(())
+    let result = env.make_vector(errors.len(), ())?;
+    for (i, error) in errors.into_iter().enumerate() {
+        result.set(i, error)?;
+    }
+    Ok(result)
+}
+
+/// A region of source code, given either as byte positions, or as points.
+enum Region {
+    Bytes(usize, usize),
+    Points(tree_sitter::Point, tree_sitter::Point),
+}
+
+impl Region {
+    fn new(beg: Value, end: Value) -> Result<Self> {
+        if let (Ok(beg), Ok(end)) = (beg.into_rust::<BytePos>(), end.into_rust::<BytePos>()) {
+            return Ok(Self::Bytes(beg.into(), end.into()));
+        }
+        let (beg, end): (Point, Point) = (beg.into_rust()?, end.into_rust()?);
+        Ok(Self::Points(beg.into(), end.into()))
+    }
+
+    /// Move CURSOR to the first child that extends beyond the region's start.
+    fn goto_first_child(&self, cursor: &mut TreeCursor) -> bool {
+        match *self {
+            Self::Bytes(beg, _) => cursor.goto_first_child_for_byte(beg).is_some(),
+            Self::Points(beg, _) => cursor.goto_first_child_for_point(beg).is_some(),
+        }
+    }
+
+    fn is_within(&self, node: Node) -> bool {
+        match *self {
+            Self::Bytes(beg, end) => node.start_byte() <= beg && end <= node.end_byte(),
+            Self::Points(beg, end) => node.start_position() <= beg && end <= node.end_position(),
+        }
+    }
+}
+
+/// Return the smallest named node within TREE-OR-NODE that spans the region from
+/// BEG to END. BEG and END should be either byte positions, or points in the form of
+/// (LINE-NUMBER . BYTE-COLUMN).
+///
+/// Return nil if TREE-OR-NODE doesn't span the region.
+#[defun]
+fn node_at_region(tree_or_node: TreeOrNode, beg: Value, end: Value) -> Result<Option<RNode>> {
+    let region = Region::new(beg, end)?;
+    let mut rcursor = tree_or_node.walk();
+    let tree = rcursor.clone_tree();
+    let cursor = &mut rcursor.borrow_mut();
+    let mut found = None;
+    while region.is_within(cursor.node()) {
+        let node = cursor.node();
+        if node.is_named() {
+            found = Some(RNode::new(tree.clone(), |_| node));
+        }
+        if !region.goto_first_child(cursor) {
+            break;
+        }
+    }
+    Ok(found)
 }
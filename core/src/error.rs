@@ -8,6 +8,13 @@ emacs::define_errors! {
 
     tsc_invalid_ranges "Invalid parsing ranges" (tsc_error)
 
+    tsc_parse_failed "Parsing failed" (tsc_error)
+    tsc_parse_no_language "Parser has no language" (tsc_parse_failed)
+    tsc_parse_timeout "Parsing timed out" (tsc_parse_failed)
+    tsc_parse_cancelled "Parsing was cancelled" (tsc_parse_failed)
+
+    tsc_registry_unknown_key "Parser registry has no entry for key" (tsc_error)
+
     tsc_query_invalid "Invalid query" (tsc_error)
     tsc_query_invalid_syntax "Query syntax error" (tsc_query_invalid)
     tsc_query_invalid_node_type "Query contains invalid node type" (tsc_query_invalid)
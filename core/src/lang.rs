@@ -1,6 +1,8 @@
 use std::{mem, os, collections::HashMap, sync::Mutex};
 
-use emacs::{defun, Result, ResultExt, GlobalRef, Value, Env, IntoLisp, FromLisp, ErrorKind};
+use emacs::{
+    defun, Result, ResultExt, GlobalRef, Value, Vector, Env, IntoLisp, FromLisp, ErrorKind,
+};
 
 use libloading::{Library, Symbol};
 use once_cell::sync::Lazy;
@@ -78,6 +80,13 @@ impl LangInfo {
             self.field_names.get(id as usize - 1)
         }
     }
+
+    /// Return the numeric id of the field whose name keyword is FIELD.
+    #[inline]
+    pub(crate) fn field_id(&self, field: Value) -> Option<u16> {
+        let env = field.env;
+        self.field_names.iter().position(|name| field.eq(name.bind(env))).map(|i| i as u16 + 1)
+    }
 }
 
 // TODO: Consider optimizing for accessing language's metadata, i.e. making Language a big wrapper
@@ -87,13 +96,23 @@ static LANG_INFOS: Lazy<Mutex<HashMap<usize, LangInfo>>> = Lazy::new(|| Mutex::n
 
 /// Load the shared lib FILE and return the language under SYMBOL-NAME.
 /// The language's name symbol is set to LANG-SYMBOL.
+///
+/// Signal `tsc-lang-abi-too-old' or `tsc-lang-abi-too-new' if the language's ABI
+/// version is outside of `tsc-supported-abi-range'.
 #[defun]
 fn _load_language(file: String, symbol_name: String, lang_symbol: Value) -> Result<Language> {
     let env = lang_symbol.env;
     let lib = unsafe { Library::new(&file) }.or_signal(env, error::tsc_lang_load_failed)?;
-    let tree_sitter_lang: Symbol<'_, unsafe extern "C" fn() -> _> =
+    let tree_sitter_lang: Symbol<'_, unsafe extern "C" fn() -> *const os::raw::c_void> =
         unsafe { lib.get(symbol_name.as_bytes()) }.or_signal(env, error::tsc_lang_load_failed)?;
-    let language: tree_sitter::Language = unsafe { tree_sitter_lang() };
+    let ptr = unsafe { tree_sitter_lang() };
+    if ptr.is_null() {
+        return env.signal(error::tsc_lang_load_failed, (
+            format!("{} returned a null language", symbol_name), file
+        ));
+    }
+    // Safety: tree_sitter::Language has the same representation as the opaque pointer type.
+    let language: tree_sitter::Language = unsafe { mem::transmute(ptr) };
     let version = language.version();
     if version < MIN_COMPATIBLE_LANGUAGE_VERSION {
         return env.signal(error::tsc_lang_abi_too_old, (
@@ -155,6 +174,19 @@ fn lang_node_type(language: Language, type_id: u16) -> Result<Option<&'static Gl
     Ok(language.info().node_type(type_id))
 }
 
+/// Return all node types defined in LANGUAGE, as a vector indexed by their numeric ids.
+///
+/// See `tsc-lang-node-type' for how node types are represented.
+#[defun]
+fn lang_node_types<'e>(env: &'e Env, language: Language) -> Result<Vector<'e>> {
+    let node_types = &language.info().node_types;
+    let vector = env.make_vector(node_types.len(), ())?;
+    for (i, node_type) in node_types.iter().enumerate() {
+        vector.set(i, node_type.bind(env))?;
+    }
+    Ok(vector)
+}
+
 /// Return a field's name keyword, given its numeric FIELD-ID in LANGUAGE.
 #[defun]
 fn lang_field(language: Language, field_id: u16) -> Result<Option<&'static GlobalRef>> {
@@ -202,6 +234,10 @@ defun_lang_methods! {
     /// Return t if the numeric TYPE-ID identifies a named node type in LANGUAGE.
     "lang-node-type-named-p" fn node_kind_is_named(type_id: u16) -> bool
 
+    /// Return t if the numeric TYPE-ID identifies a visible node type in LANGUAGE.
+    /// Invisible (auxiliary) node types never appear in syntax trees.
+    "lang-node-type-visible-p" fn node_kind_is_visible(type_id: u16) -> bool
+
     /// Return the numeric id of FIELD-NAME in LANGUAGE.
     "-lang-field-id-for-name" fn field_id_for_name(field_name: String) -> Option<u16>
 }
@@ -6,10 +6,14 @@ mod types;
 mod error;
 mod lang;
 mod parser;
+mod background;
+mod registry;
 mod tree;
+mod batch;
 mod node;
 mod cursor;
 mod query;
+mod cache;
 
 emacs::plugin_is_GPL_compatible! {}
 
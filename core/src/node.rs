@@ -2,14 +2,16 @@ use std::{
     cell::{Ref, RefCell, RefMut},
     mem,
     ops::{Deref, DerefMut},
+    rc::Rc,
 };
 
-use emacs::{defun, Env, IntoLisp, Result, Value, GlobalRef};
-use tree_sitter::{InputEdit, Node, Tree};
+use emacs::{defun, Env, IntoLisp, Result, Value, GlobalRef, Vector};
+use tree_sitter::{InputEdit, Node, Tree, TreeCursor};
 
 use crate::{
     types::{self, BytePos, Point, Shared, Range},
     lang::Language,
+    error,
 };
 
 // -------------------------------------------------------------------------------------------------
@@ -201,6 +203,11 @@ defun_node_props! {
     /// Return NODE's numeric type-id.
     "node-type-id" fn kind_id -> u16
 
+    /// Return a number that uniquely identifies NODE within its syntax tree.
+    /// Different node objects referring to the same node have the same id, which makes
+    /// it suitable as a hash table key. Nodes from different trees may share ids.
+    "node-id" fn id -> usize
+
     // Predicates ----------------------------------------------------------------------------------
 
     /// Return t if NODE is 'named'.
@@ -261,12 +268,124 @@ fn node_byte_range<'e>(env: &'e Env, node: &RNode) -> Result<Value<'e>> {
     node.borrow().lisp_byte_range(env)
 }
 
+/// Return NODE's text in SOURCE, the source code its tree was parsed from, signaling an error if
+/// NODE's byte range is not within SOURCE, or doesn't fall on character boundaries.
+pub(crate) fn text_in<'s>(env: &Env, node: Node, source: &'s str) -> Result<&'s str> {
+    match source.get(node.start_byte()..node.end_byte()) {
+        Some(text) => Ok(text),
+        None => env.signal(error::tsc_error, ("Node is not from the given source code", )),
+    }
+}
+
+/// Return NODE's text in SOURCE, the string its syntax tree was parsed from.
+///
+/// See `tsc-node-text'.
+#[defun]
+fn _node_text_in_string(env: &Env, node: &RNode, source: String) -> Result<String> {
+    Ok(text_in(env, *node.borrow(), &source)?.to_owned())
+}
+
+// UTF-16 positions, for trees parsed by `tsc-parse-string-utf16'. Such trees measure positions in
+// bytes of UTF-16 text, i.e. 2 bytes per code unit.
+
+/// Return NODE's (START . END) offsets, in UTF-16 code units, counting from 0.
+/// NODE must be from a tree parsed by `tsc-parse-string-utf16'.
+#[defun]
+fn node_utf16_range<'e>(env: &'e Env, node: &RNode) -> Result<Value<'e>> {
+    let node = node.borrow();
+    env.cons(node.start_byte() / 2, node.end_byte() / 2)
+}
+
+fn utf16_point(point: tree_sitter::Point) -> Point {
+    tree_sitter::Point { row: point.row, column: point.column / 2 }.into()
+}
+
+/// Return NODE's start point, in the form of (LINE-NUMBER . UTF16-COLUMN).
+/// UTF16-COLUMN counts UTF-16 code units from 0, like LSP positions' `character'.
+/// NODE must be from a tree parsed by `tsc-parse-string-utf16'.
+#[defun]
+fn node_utf16_start_point(node: &RNode) -> Result<Point> {
+    Ok(utf16_point(node.borrow().start_position()))
+}
+
+/// Return NODE's end point, in the form of (LINE-NUMBER . UTF16-COLUMN).
+/// NODE must be from a tree parsed by `tsc-parse-string-utf16'.
+#[defun]
+fn node_utf16_end_point(node: &RNode) -> Result<Point> {
+    Ok(utf16_point(node.borrow().end_position()))
+}
+
+/// Return t if NODE contains BYTEPOS, i.e. NODE starts at or before BYTEPOS, and ends
+/// after it.
+#[defun]
+fn node_contains_byte_p(node: &RNode, bytepos: BytePos) -> Result<bool> {
+    let node = node.borrow();
+    let byte: usize = bytepos.into();
+    Ok(node.start_byte() <= byte && byte < node.end_byte())
+}
+
+/// Return t if NODE contains POINT, i.e. NODE starts at or before POINT, and ends
+/// after it. POINT should be in the form of (LINE-NUMBER . BYTE-COLUMN).
+#[defun]
+fn node_contains_point_p(node: &RNode, point: Point) -> Result<bool> {
+    let node = node.borrow();
+    let point: tree_sitter::Point = point.into();
+    Ok(node.start_position() <= point && point < node.end_position())
+}
+
+/// Return t if NODE overlaps the range from BEG-BYTEPOS to END-BYTEPOS.
+/// An empty range overlaps NODE if it is inside NODE, or at NODE's start.
+#[defun]
+fn node_overlaps_range_p(
+    node: &RNode,
+    beg_bytepos: BytePos,
+    end_bytepos: BytePos,
+) -> Result<bool> {
+    let node = node.borrow();
+    let (beg, end): (usize, usize) = (beg_bytepos.into(), end_bytepos.into());
+    Ok(node.start_byte() < end.max(beg + 1) && beg < node.end_byte())
+}
+
 /// Return t if two nodes are identical.
 #[defun]
 fn node_eq(node1: &RNode, node2: &RNode) -> Result<bool> {
     Ok(node1 == node2)
 }
 
+/// Return t if NODE1 and NODE2 have the same structure, regardless of their positions.
+/// That is, their descendants must have the same types, and be associated with the
+/// same fields. The nodes must be from syntax trees of the same language.
+#[defun]
+fn node_structurally_equal_p(node1: &RNode, node2: &RNode) -> Result<bool> {
+    let (node1, node2) = (node1.borrow(), node2.borrow());
+    let (cursor1, cursor2) = (&mut node1.walk(), &mut node2.walk());
+    loop {
+        let (a, b) = (cursor1.node(), cursor2.node());
+        if a.kind_id() != b.kind_id()
+            || a.is_missing() != b.is_missing()
+            || a.child_count() != b.child_count()
+            || cursor1.field_id() != cursor2.field_id()
+        {
+            return Ok(false);
+        }
+        // The 2 nodes have the same number of children, so the 2 cursors always move in lock-step.
+        if cursor1.goto_first_child() {
+            cursor2.goto_first_child();
+            continue;
+        }
+        loop {
+            if cursor1.goto_next_sibling() {
+                cursor2.goto_next_sibling();
+                break;
+            }
+            if !cursor1.goto_parent() {
+                return Ok(true);
+            }
+            cursor2.goto_parent();
+        }
+    }
+}
+
 /// Apply FUNCTION to each of NODE's children, for side effects only.
 #[defun]
 fn mapc_children(function: Value, node: &RNode) -> Result<()> {
@@ -280,7 +399,68 @@ fn mapc_children(function: Value, node: &RNode) -> Result<()> {
     Ok(())
 }
 
-// TODO: named_children.
+/// Return a vector of NODE's children.
+#[defun]
+fn node_children<'e>(env: &'e Env, node: &RNode) -> Result<Vector<'e>> {
+    let inner = node.borrow();
+    let cursor = &mut inner.walk();
+    let children = env.make_vector(inner.child_count(), ())?;
+    for (i, child) in inner.children(cursor).enumerate() {
+        children.set(i, node.map(|_| child))?;
+    }
+    Ok(children)
+}
+
+/// Return a vector of NODE's named children.
+#[defun]
+fn node_named_children<'e>(env: &'e Env, node: &RNode) -> Result<Vector<'e>> {
+    let inner = node.borrow();
+    let cursor = &mut inner.walk();
+    let children = env.make_vector(inner.named_child_count(), ())?;
+    for (i, child) in inner.named_children(cursor).enumerate() {
+        children.set(i, node.map(|_| child))?;
+    }
+    Ok(children)
+}
+
+/// Return an alist of NODE's children, in the form of ((FIELD . CHILD) ...).
+/// FIELD is the keyword of the field that CHILD is associated with, or nil if there
+/// is no such field.
+#[defun]
+fn node_children_with_fields<'e>(env: &'e Env, node: &RNode) -> Result<Value<'e>> {
+    let inner = node.borrow();
+    let language: Language = inner.language().into();
+    let cursor = &mut inner.walk();
+    let mut pairs = Vec::with_capacity(inner.child_count());
+    if cursor.goto_first_child() {
+        loop {
+            let field = cursor.field_id().and_then(|id| language.info().field_name(id));
+            let child = node.map(|_| cursor.node());
+            pairs.push(env.cons(field, child)?);
+            if !cursor.goto_next_sibling() {
+                break;
+            }
+        }
+    }
+    let mut alist = ().into_lisp(env)?;
+    for pair in pairs.into_iter().rev() {
+        alist = env.cons(pair, alist)?;
+    }
+    Ok(alist)
+}
+
+/// Return the field associated with NODE's child at the given 0-based index, as a
+/// keyword. Return nil if the child is not associated with a field.
+#[defun]
+fn field_name_for_child(node: &RNode, i: u32) -> Result<Option<&'static GlobalRef>> {
+    let inner = node.borrow();
+    let language: Language = inner.language().into();
+    Ok(inner
+        .field_name_for_child(i)
+        .and_then(|name| inner.language().field_id_for_name(name))
+        .and_then(|id| language.info().field_name(id)))
+}
+
 // TODO: children_by_field_name.
 // TODO: children_by_field_id.
 
@@ -333,11 +513,299 @@ defun_node_navs! {
     "get-named-descendant-for-point-range" fn named_descendant_for_point_range(start into: Point, end into: Point)
 }
 
+/// Return NODE's closest ancestor whose type is in the list TYPES.
+/// If MAX-DEPTH is non-nil, look at most that many levels up.
+///
+/// See `tsc-get-ancestor-of-type'.
+#[defun]
+fn _get_ancestor_of_type(
+    node: &RNode,
+    types: Value,
+    max_depth: Option<usize>,
+) -> Result<Option<RNode>> {
+    let env = types.env;
+    let mut symbols = vec![];
+    let mut strings = vec![];
+    let mut rest = types;
+    while rest.is_not_nil() {
+        let node_type: Value = rest.car()?;
+        match node_type.into_rust::<String>() {
+            Ok(string) => strings.push(string),
+            Err(_) => symbols.push(node_type),
+        }
+        rest = rest.cdr()?;
+    }
+    let mut ancestor = node.borrow().parent();
+    let mut depth = 1;
+    while let Some(current) = ancestor {
+        if max_depth.map_or(false, |max_depth| depth > max_depth) {
+            break;
+        }
+        let found = if current.is_named() {
+            let lisp_type = current.lisp_type().bind(env);
+            symbols.iter().any(|symbol| symbol.eq(lisp_type))
+        } else {
+            strings.iter().any(|string| string == current.kind())
+        };
+        if found {
+            return Ok(Some(node.map(|_| current)));
+        }
+        ancestor = current.parent();
+        depth += 1;
+    }
+    Ok(None)
+}
+
+/// Return the deepest node that is NODE1 or one of its ancestors, and is also NODE2 or
+/// one of its ancestors. Return nil if the 2 nodes are from different syntax trees.
+///
+/// See `tsc-lowest-common-ancestor'.
+#[defun]
+fn _lowest_common_ancestor(node1: &RNode, node2: &RNode) -> Result<Option<RNode>> {
+    // Node equality only compares ids, which are shared by copies of a tree.
+    if !Rc::ptr_eq(&node1.tree, &node2.tree) {
+        return Ok(None);
+    }
+    let mut ancestors1 = vec![*node1.borrow()];
+    while let Some(parent) = ancestors1[ancestors1.len() - 1].parent() {
+        ancestors1.push(parent);
+    }
+    let mut this = Some(*node2.borrow());
+    while let Some(node) = this {
+        if ancestors1.contains(&node) {
+            return Ok(Some(node1.map(|_| node)));
+        }
+        this = node.parent();
+    }
+    Ok(None)
+}
+
+/// Return the path from the root node of NODE's syntax tree to NODE, as a list of steps.
+///
+/// A step is the field keyword of the child to descend into, if the child is the
+/// first one associated with that field. Otherwise, it is the child's 0-based index.
+/// Fields make a path more likely to still be valid after the source code is edited.
+///
+/// See `tsc-resolve-node-path'.
+#[defun]
+fn node_path<'e>(env: &'e Env, node: &RNode) -> Result<Value<'e>> {
+    let inner = node.borrow();
+    let language: Language = inner.language().into();
+    let mut path = ().into_lisp(env)?;
+    let mut this = *inner;
+    while let Some(parent) = this.parent() {
+        let cursor = &mut parent.walk();
+        cursor.goto_first_child();
+        let mut i = 0;
+        while cursor.node() != this && cursor.goto_next_sibling() {
+            i += 1;
+        }
+        let field = cursor
+            .field_id()
+            .filter(|id| parent.child_by_field_id(*id) == Some(this))
+            .and_then(|id| language.info().field_name(id));
+        path = match field {
+            Some(field) => env.cons(field, path)?,
+            None => env.cons(i, path)?,
+        };
+        this = parent;
+    }
+    Ok(path)
+}
+
+/// Follow PATH from NODE, and return the final node. Return nil if a step cannot be
+/// followed.
+///
+/// See `tsc-resolve-node-path'.
+#[defun]
+fn _resolve_node_path(node: &RNode, path: Value) -> Result<Option<RNode>> {
+    let inner = node.borrow();
+    let language: Language = inner.language().into();
+    let mut this = *inner;
+    let mut rest = path;
+    while rest.is_not_nil() {
+        let step: Value = rest.car()?;
+        let child = match step.into_rust::<usize>() {
+            Ok(i) => this.child(i),
+            Err(_) => language.info().field_id(step).and_then(|id| this.child_by_field_id(id)),
+        };
+        match child {
+            Some(child) => this = child,
+            None => return Ok(None),
+        }
+        rest = rest.cdr()?;
+    }
+    Ok(Some(node.map(|_| this)))
+}
+
 defun_node_props! {
     /// Return the sexp representation of NODE, in a string.
     "node-to-sexp" fn to_sexp -> String
 }
 
+/// Options for `write_pretty_sexp`.
+struct SexpOptions {
+    max_depth: Option<usize>,
+    ranges: bool,
+    fields: bool,
+}
+
+/// Write the indented sexp representation of CURSOR's current node into OUT. Like
+/// `Node::to_sexp`, only named nodes and missing nodes are included.
+fn write_pretty_sexp(cursor: &mut TreeCursor, options: &SexpOptions, out: &mut String) {
+    // The depth of the cursor's node, relative to the starting node. Only included nodes are
+    // descended into, so this is also the indentation level.
+    let mut depth = 0;
+    // Whether the cursor's node has not been written yet, i.e. it was not reached by going back up.
+    let mut entered = true;
+    loop {
+        let node = cursor.node();
+        if entered && (depth == 0 || node.is_named() || node.is_missing()) {
+            if depth > 0 {
+                out.push('\n');
+            }
+            out.push_str(&"  ".repeat(depth));
+            if let (true, Some(field)) = (options.fields, cursor.field_name()) {
+                out.push_str(field);
+                out.push_str(": ");
+            }
+            out.push('(');
+            if node.is_missing() {
+                out.push_str("MISSING ");
+            }
+            if node.is_named() {
+                out.push_str(node.kind());
+            } else {
+                out.push_str(&format!("{:?}", node.kind()));
+            }
+            if options.ranges {
+                // Emacs byte positions are 1-based.
+                out.push_str(&format!(" [{}-{}]", node.start_byte() + 1, node.end_byte() + 1));
+            }
+            if node.named_child_count() > 0 {
+                if options.max_depth.map_or(false, |max_depth| depth >= max_depth) {
+                    out.push_str(" ...");
+                } else if cursor.goto_first_child() {
+                    depth += 1;
+                    continue;
+                }
+            }
+            out.push(')');
+        }
+        if depth == 0 {
+            break;
+        }
+        entered = cursor.goto_next_sibling();
+        if !entered {
+            cursor.goto_parent();
+            depth -= 1;
+            out.push(')');
+        }
+    }
+}
+
+/// Write S into OUT as a JSON string literal.
+fn write_json_string(s: &str, out: &mut String) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+/// Write the JSON representation of CURSOR's current node, and its descendants, into OUT.
+/// Positions use Emacs's conventions, like the sexp representation.
+fn write_json(cursor: &mut TreeCursor, named_only: bool, out: &mut String) {
+    // See `write_pretty_sexp`.
+    let mut depth = 0;
+    let mut entered = true;
+    // Whether no node has been written at the cursor's level yet.
+    let mut first = true;
+    loop {
+        let node = cursor.node();
+        if entered && (depth == 0 || !named_only || node.is_named()) {
+            if !first {
+                out.push(',');
+            }
+            first = false;
+            out.push_str("{\"type\":");
+            write_json_string(node.kind(), out);
+            out.push_str(&format!(",\"named\":{}", node.is_named()));
+            if let Some(field) = cursor.field_name() {
+                out.push_str(",\"field\":");
+                write_json_string(field, out);
+            }
+            if node.is_missing() {
+                out.push_str(",\"missing\":true");
+            }
+            let (start, end) = (node.lisp_start_point(), node.lisp_end_point());
+            out.push_str(&format!(
+                ",\"start_byte\":{},\"end_byte\":{},\"start_point\":[{},{}],\"end_point\":[{},{}]",
+                node.start_byte() + 1,
+                node.end_byte() + 1,
+                start.line_number(),
+                start.byte_column(),
+                end.line_number(),
+                end.byte_column(),
+            ));
+            out.push_str(",\"children\":[");
+            if cursor.goto_first_child() {
+                depth += 1;
+                first = true;
+                continue;
+            }
+            out.push_str("]}");
+        }
+        if depth == 0 {
+            break;
+        }
+        entered = cursor.goto_next_sibling();
+        if !entered {
+            cursor.goto_parent();
+            depth -= 1;
+            out.push_str("]}");
+            // The parent was written, even if none of its children were.
+            first = false;
+        }
+    }
+}
+
+/// Return the JSON representation of NODE, in a string.
+///
+/// See `tsc-node-to-json'.
+#[defun]
+fn _node_to_json(node: &RNode, named_only: Option<Value>) -> Result<String> {
+    let node = node.borrow();
+    let mut out = String::new();
+    write_json(&mut node.walk(), named_only.is_some(), &mut out);
+    Ok(out)
+}
+
+/// Return the indented sexp representation of NODE, in a string.
+///
+/// See `tsc-node-to-pretty-sexp'.
+#[defun]
+fn _node_to_pretty_sexp(
+    node: &RNode,
+    max_depth: Option<usize>,
+    ranges: Option<Value>,
+    fields: Option<Value>,
+) -> Result<String> {
+    let options = SexpOptions { max_depth, ranges: ranges.is_some(), fields: fields.is_some() };
+    let node = node.borrow();
+    let mut out = String::new();
+    write_pretty_sexp(&mut node.walk(), &options, &mut out);
+    Ok(out)
+}
+
 /// Edit NODE to keep it in sync with source code that has been edited.
 ///
 /// You must describe the edit both in terms of byte positions and in terms of
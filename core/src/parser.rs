@@ -1,19 +1,50 @@
-use std::{cell::RefCell, rc::Rc};
+use std::{
+    cell::{Cell, RefCell},
+    rc::Rc,
+    sync::{
+        Arc,
+        atomic::{AtomicUsize, Ordering},
+    },
+};
+#[cfg(unix)]
+use std::fs::File;
 
-use emacs::{defun, Result, Value, Vector, Env, ResultExt};
-use tree_sitter::{Parser, Tree};
+use emacs::{defun, Result, Value, Vector, Env, GlobalRef, IntoLisp, ResultExt};
+use tree_sitter::{LogType, Parser, Tree};
 
 use crate::{
     types::{BytePos, Point, Range, Shared},
     lang::Language,
+    tree::stamped,
     error,
 };
 
-fn shared<T>(t: T) -> Shared<T> {
-    Rc::new(RefCell::new(t))
+impl_pred!(parser_p, &RefCell<Parser>);
+
+emacs::use_symbols! {
+    point_max position_bytes byte_to_position buffer_substring_no_properties
+    set tsc_last_parse_failure
+    _parse => ":parse"
+    _lex   => ":lex"
 }
 
-impl_pred!(parser_p, &RefCell<Parser>);
+thread_local! {
+    /// The Lisp environment of the ongoing parse. Loggers use it to call Lisp functions, since
+    /// tree-sitter's logging callback doesn't provide one.
+    static PARSE_ENV: Cell<Option<*const Env>> = Cell::new(None);
+
+    /// The Lisp functions of all loggers created by `_set_logger'. A logger function that is only
+    /// referenced from here belongs to a logger that was dropped, and can be freed.
+    static LOGGER_FUNCTIONS: RefCell<Vec<Rc<GlobalRef>>> = RefCell::new(vec![]);
+}
+
+/// Call F, making ENV available to the logger of the parser it uses.
+fn with_parse_env<T>(env: &Env, f: impl FnOnce() -> T) -> T {
+    let previous = PARSE_ENV.with(|current| current.replace(Some(env as *const Env)));
+    let result = f();
+    PARSE_ENV.with(|current| current.set(previous));
+    result
+}
 
 /// Create a new parser.
 #[defun(user_ptr)]
@@ -39,29 +70,101 @@ fn language(parser: &Parser) -> Result<Option<Language>> {
 // TODO: Add a version that reuses a single byte buffer to avoid multiple allocations. Also allow
 // `parse` to pass a soft size limit to the input function.
 
-// TODO: Add parse_buffer.
-
 /// Parse source code chunks generated by INPUT-FUNCTION with PARSER; return a tree.
+/// See `tsc-parse-chunks'.
+#[defun]
+fn _parse_chunks(
+    parser: &mut Parser,
+    input_function: Value,
+    old_tree: Option<&Shared<Tree>>,
+    keep_old_tree: Option<Value>,
+) -> Result<Shared<Tree>> {
+    let keep_old_tree = keep_old_tree.is_some();
+    parse_input(input_function.env, parser, old_tree, keep_old_tree, |byte, point| {
+        let bytepos: BytePos = byte.into();
+        let point: Point = point.into();
+        let chunk = input_function.call((bytepos, point.line_number(), point.byte_column()))?;
+        Ok(chunk.into_rust::<Option<String>>()?.unwrap_or_default())
+    })
+}
+
+/// The number of bytes `_parse_buffer' reads from the buffer at a time.
+const BUFFER_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Use PARSER to parse the current buffer's text, returning a tree.
 ///
-/// INPUT-FUNCTION should take 3 parameters: (BYTEPOS LINE-NUMBER BYTE-COLUMN), and
-/// return a fragment of the source code, starting from the position identified by
-/// either BYTEPOS or (LINE-NUMBER . BYTE-COLUMN). It should return an empty string
-/// to signal the end of the source code.
-///
-/// BYTEPOS is Emacs's 1-based byte position.
-///
-/// LINE-NUMBER is the number returned by `line-number-at-pos', which counts from 1.
-///
-/// BYTE-COLUMN counts from 0, likes Emacs's `current-column'. However, unlike that
-/// function, it counts bytes, instead of displayed glyphs.
+/// This must be called with narrowing disabled. See `tsc-parse-buffer'.
+#[defun]
+fn _parse_buffer(
+    env: &Env,
+    parser: &mut Parser,
+    old_tree: Option<&Shared<Tree>>,
+    keep_old_tree: Option<Value>,
+) -> Result<Shared<Tree>> {
+    parse_buffer(env, parser, old_tree, keep_old_tree.is_some())
+}
+
+/// Use PARSER to parse only the part of the current buffer's text within RANGE. Afterward, PARSER
+/// includes whole documents again, since the tree-sitter version we use can't read back the
+/// ranges it had before.
 ///
-/// If you have already parsed an earlier version of this document, and it has since
-/// been edited, pass the previously parsed OLD-TREE so that its unchanged parts can
-/// be reused. This will save time and memory. For this to work correctly, you must
-/// have already edited it using `tsc-edit-tree' function in a way that exactly
-/// matches the source code changes.
+/// This must be called with narrowing disabled. See `tsc-parse-region'.
 #[defun]
-fn parse_chunks(parser: &mut Parser, input_function: Value, old_tree: Option<&Shared<Tree>>) -> Result<Shared<Tree>> {
+fn _parse_region(
+    env: &Env,
+    parser: &mut Parser,
+    range: Range,
+    old_tree: Option<&Shared<Tree>>,
+) -> Result<Shared<Tree>> {
+    set_ranges(env, parser, &[range.into()])?;
+    let result = parse_buffer(env, parser, old_tree, false);
+    if result.is_err() {
+        // A resumed parse would use different ranges.
+        parser.reset();
+    }
+    set_ranges(env, parser, &[])?;
+    result
+}
+
+/// Use PARSER to parse the current buffer's text, which must not be narrowed.
+///
+/// Each chunk is copied out of the buffer with `buffer-substring-no-properties', since modules
+/// have no direct access to buffer text.
+pub(crate) fn parse_buffer(
+    env: &Env,
+    parser: &mut Parser,
+    old_tree: Option<&Shared<Tree>>,
+    keep_old_tree: bool,
+) -> Result<Shared<Tree>> {
+    let max_pos = env.call(point_max, [] as [Value; 0])?;
+    let end_byte: usize = env.call(position_bytes, [max_pos])?.into_rust::<BytePos>()?.into();
+    parse_input(env, parser, old_tree, keep_old_tree, |byte, _| {
+        if byte >= end_byte {
+            return Ok(String::new());
+        }
+        let beg = env.call(byte_to_position, [BytePos::from(byte).into_lisp(env)?])?;
+        let end = match byte + BUFFER_CHUNK_SIZE {
+            end if end >= end_byte => max_pos,
+            end => env.call(byte_to_position, [BytePos::from(end).into_lisp(env)?])?,
+        };
+        env.call(buffer_substring_no_properties, (beg, end))?.into_rust()
+    })
+}
+
+/// Parse the text returned by READ with PARSER, like `Parser::parse_with`, but handling errors
+/// returned by READ. If KEEP_OLD_TREE is true, OLD_TREE is returned if parsing fails.
+fn parse_input<F, T>(
+    env: &Env,
+    parser: &mut Parser,
+    old_tree: Option<&Shared<Tree>>,
+    keep_old_tree: bool,
+    mut read: F,
+) -> Result<Shared<Tree>>
+where
+    F: FnMut(usize, tree_sitter::Point) -> Result<T>,
+    T: AsRef<[u8]> + Default,
+{
+    let fallback = if keep_old_tree { old_tree } else { None };
     let old_tree = match old_tree {
         Some(v) => Some(v.try_borrow()?),
         _ => None,
@@ -70,60 +173,279 @@ fn parse_chunks(parser: &mut Parser, input_function: Value, old_tree: Option<&Sh
         Some(r) => Some(&**r),
         _ => None,
     };
+    // If there is a cancellation flag, it is used to stop parsing as soon as reading fails, e.g.
+    // because `while-no-input' was interrupted by user input.
+    let flag = unsafe { parser.cancellation_flag() }.map(|flag| flag as *const AtomicUsize);
     // This is used to hold potential error, because the callback cannot return a Result, and
     // unwinding across FFI boundary during a panic is UB (future Rust versions will abort).
     // See https://github.com/rust-lang/rust/issues/52652.
     let mut input_error = None;
-    let input = &mut |byte: usize, point: tree_sitter::Point| -> String {
-        let bytepos: BytePos = byte.into();
-        let point: Point = point.into();
-        input_function.call((bytepos, point.line_number(), point.byte_column()))
-            .and_then(|v| v.into_rust())
-            .unwrap_or_else(|e| {
-                input_error = Some(e);
-                "".to_owned()
-            })
+    // The flag's value before it was set to stop parsing. It is restored afterwards, so that a
+    // cancellation requested in the meantime is kept.
+    let mut previous_flag = None;
+    let input = &mut |byte: usize, point: tree_sitter::Point| -> T {
+        if input_error.is_some() {
+            return T::default();
+        }
+        read(byte, point).unwrap_or_else(|e| {
+            if let Some(flag) = flag {
+                // Safety: The flag's token is kept alive while it's attached to the parser.
+                previous_flag = Some(unsafe { (*flag).swap(1, Ordering::SeqCst) });
+            }
+            input_error = Some(e);
+            T::default()
+        })
     };
-    // TODO: Support error cases (None).
-    let tree = parser.parse_with(input, old_tree).unwrap();
+    let tree = with_parse_env(env, || parser.parse_with(input, old_tree));
     match input_error {
-        None => Ok(shared(tree)),
-        Some(e) => Err(e),
+        None => parse_result(env, parser, tree, fallback),
+        Some(e) => {
+            if let (Some(flag), Some(previous)) = (flag, previous_flag) {
+                unsafe { (*flag).store(previous, Ordering::SeqCst) };
+                parser.reset();
+            }
+            Err(e)
+        }
     }
 }
 
 /// Use PARSER to parse the INPUT string, returning a tree.
 #[defun]
-fn parse_string(parser: &mut Parser, input: String) -> Result<Shared<Tree>> {
-    let tree = parser.parse(input, None).unwrap();
-    Ok(shared(tree))
+fn parse_string(env: &Env, parser: &mut Parser, input: String) -> Result<Shared<Tree>> {
+    let tree = with_parse_env(env, || parser.parse(input, None));
+    parse_result(env, parser, tree, None)
 }
 
-/// Instruct PARSER to start the next parse from the beginning.
+/// Use PARSER to parse the concatenation of the vector of strings CHUNKS.
+/// See `tsc-parse-strings'.
+#[defun]
+fn _parse_strings(
+    env: &Env,
+    parser: &mut Parser,
+    chunks: Vector,
+    old_tree: Option<&Shared<Tree>>,
+) -> Result<Shared<Tree>> {
+    let mut strings = Vec::with_capacity(chunks.len());
+    for i in 0..chunks.len() {
+        let chunk: String = chunks.get(i)?;
+        // An empty chunk would be taken as the end of the input.
+        if !chunk.is_empty() {
+            strings.push(chunk);
+        }
+    }
+    // The byte offset of each chunk's start. This is strictly increasing.
+    let mut starts = Vec::with_capacity(strings.len());
+    let mut end = 0;
+    for chunk in &strings {
+        starts.push(end);
+        end += chunk.len();
+    }
+    let (strings, starts) = (&strings, &starts);
+    parse_input(env, parser, old_tree, false, move |byte, _| {
+        if byte >= end {
+            return Ok(&[][..]);
+        }
+        let i = match starts.binary_search(&byte) {
+            Ok(i) => i,
+            Err(i) => i - 1,
+        };
+        // The chunk is sliced as bytes, since the byte requested may not be on a char boundary,
+        // e.g. if the old tree's edits didn't match the actual changes.
+        Ok(strings[i].as_bytes().get(byte - starts[i]..).unwrap_or_default())
+    })
+}
+
+/// Use PARSER to parse the INPUT string, encoded as UTF-16, returning a tree.
+///
+/// The resulting tree's positions are measured in bytes of UTF-16 text, i.e. 2 bytes
+/// per UTF-16 code unit, so the usual node position functions don't return buffer
+/// positions. Use `tsc-node-utf16-range', `tsc-node-utf16-start-point' and
+/// `tsc-node-utf16-end-point' instead. This is mainly useful to interoperate with
+/// the Language Server Protocol, which measures columns in UTF-16 code units.
+#[defun]
+fn parse_string_utf16(env: &Env, parser: &mut Parser, input: String) -> Result<Shared<Tree>> {
+    let input: Vec<u16> = input.encode_utf16().collect();
+    let tree = with_parse_env(env, || parser.parse_utf16(&input, None));
+    parse_result(env, parser, tree, None)
+}
+
+/// Convert a parse's TREE into a Lisp-shareable tree, or signal why there is none.
 ///
-/// If PARSER previously failed because of a timeout or a cancellation, then by
-/// default, it will resume where it left off on the next parse. If you don't want
-/// to resume, and instead intend to use PARSER to parse some other code, you must
-/// call this function first.
+/// If FALLBACK is given, it is returned instead of signaling, and `tsc-last-parse-failure' is set
+/// to the error symbol that would have been signaled.
+fn parse_result(
+    env: &Env,
+    parser: &mut Parser,
+    tree: Option<Tree>,
+    fallback: Option<&Shared<Tree>>,
+) -> Result<Shared<Tree>> {
+    let (failure, timeout) = match tree {
+        Some(tree) => {
+            if fallback.is_some() {
+                env.call(set, (tsc_last_parse_failure, ()))?;
+            }
+            return Ok(stamped(tree));
+        }
+        None if parser.language().is_none() => (error::tsc_parse_no_language, None),
+        None if unsafe { parser.cancellation_flag() }.map_or(false, is_set) => {
+            // The input was most likely edited in the meantime, so there's no point resuming.
+            parser.reset();
+            (error::tsc_parse_cancelled, None)
+        }
+        None if parser.timeout_micros() > 0 => {
+            (error::tsc_parse_timeout, Some(parser.timeout_micros()))
+        }
+        None => (error::tsc_parse_failed, None),
+    };
+    match (fallback, timeout) {
+        (Some(old_tree), _) => {
+            env.call(set, (tsc_last_parse_failure, failure))?;
+            Ok(old_tree.clone())
+        }
+        (None, Some(timeout)) => env.signal(failure, (timeout, )),
+        (None, None) => env.signal(failure, ()),
+    }
+}
+
+/// Make PARSER call LOGGER with its log messages. See `tsc-set-logger'.
+#[defun]
+fn _set_logger(env: &Env, parser: &mut Parser, logger: Option<Value>) -> Result<()> {
+    match logger {
+        Some(logger) => {
+            let logger = Rc::new(logger.make_global_ref());
+            LOGGER_FUNCTIONS.with(|functions| functions.borrow_mut().push(logger.clone()));
+            parser.set_logger(Some(Box::new(move |log_type, message| {
+                PARSE_ENV.with(|env| {
+                    // Without an environment, e.g. if the parse is not run by a defun from this
+                    // module, Lisp cannot be called, so the message is dropped.
+                    let env = match env.get() {
+                        // Safety: The environment is valid for the whole parse.
+                        Some(env) => unsafe { &*env },
+                        None => return,
+                    };
+                    let log_type = match log_type {
+                        LogType::Parse => _parse,
+                        LogType::Lex => _lex,
+                    };
+                    // Errors cannot be propagated through tree-sitter, so they are ignored.
+                    let _ = logger.bind(env).call((log_type, message));
+                })
+            })));
+        }
+        None => parser.set_logger(None),
+    }
+    free_logger_functions(env)
+}
+
+/// Free the global references to the Lisp functions of loggers that were dropped, i.e. replaced,
+/// or dropped together with their parsers. This cannot be done when they are dropped, since that
+/// requires an environment.
+fn free_logger_functions(env: &Env) -> Result<()> {
+    let dropped: Vec<_> = LOGGER_FUNCTIONS.with(|functions| {
+        let mut functions = functions.borrow_mut();
+        let (dropped, alive) = functions.drain(..).partition(|f| Rc::strong_count(f) == 1);
+        *functions = alive;
+        dropped
+    });
+    for function in dropped {
+        if let Ok(function) = Rc::try_unwrap(function) {
+            function.free(env)?;
+        }
+    }
+    Ok(())
+}
+
+/// Make PARSER write DOT graphs of its internal parse stacks to FILE, during each
+/// parse. If FILE is nil, stop writing the graphs. See `tsc-parser-print-dot-graphs'.
+#[cfg(unix)]
+#[defun]
+fn _print_dot_graphs(parser: &mut Parser, file: Option<String>) -> Result<()> {
+    match file {
+        Some(file) => parser.print_dot_graphs(&File::create(file)?),
+        None => parser.stop_printing_dot_graphs(),
+    }
+    Ok(())
+}
+
+/// Instruct PARSER to start the next parse from the beginning.
 ///
-/// Note: timeout and cancellation are not yet properly supported.
+/// If PARSER previously failed because of a timeout, then by default, it will
+/// resume where it left off on the next parse. If you don't want to resume, and
+/// instead intend to use PARSER to parse some other code, you must call this
+/// function first.
 #[defun]
-fn _reset_parser(parser: &mut Parser) -> Result<()> {
+fn reset_parser(parser: &mut Parser) -> Result<()> {
     Ok(parser.reset())
 }
 
 /// Return the duration in microseconds that PARSER is allowed to take each parse.
-/// Note: timeout and cancellation are not yet properly supported.
+/// Return 0 if there is no limit.
 #[defun]
-fn _timeout_micros(parser: &Parser) -> Result<u64> {
+fn parse_timeout(parser: &Parser) -> Result<u64> {
     Ok(parser.timeout_micros())
 }
 
 /// Set MAX-DURATION in microseconds that PARSER is allowed to take each parse.
-/// Note: timeout and cancellation are not yet properly supported.
+///
+/// If a parse takes longer than that, it is paused, and the parsing function
+/// signals `tsc-parse-timeout'. Calling the parsing function again with the same
+/// input resumes the parse, unless `tsc-reset-parser' was called. MAX-DURATION of
+/// nil or 0 means no limit, which is the default.
+#[defun]
+fn set_parse_timeout(parser: &mut Parser, max_duration: Option<u64>) -> Result<()> {
+    Ok(parser.set_timeout_micros(max_duration.unwrap_or(0)))
+}
+
+/// A flag that cancels the parses of the parsers it is attached to, when set.
+pub struct CancellationToken(Arc<AtomicUsize>);
+
+impl_pred!(cancellation_token_p, &RefCell<CancellationToken>);
+
+fn is_set(flag: &AtomicUsize) -> bool {
+    flag.load(Ordering::SeqCst) != 0
+}
+
+/// Create a new cancellation token, to be attached to parsers with
+/// `tsc-set-cancellation-token'.
+#[defun(user_ptr)]
+fn make_cancellation_token() -> Result<CancellationToken> {
+    Ok(CancellationToken(Arc::new(AtomicUsize::new(0))))
+}
+
+/// Cancel the ongoing and future parses of the parsers TOKEN is attached to.
+///
+/// These parses signal `tsc-parse-cancelled', until TOKEN is reset with
+/// `tsc-reset-cancellation-token'.
 #[defun]
-fn _set_timeout_micros(parser: &mut Parser, max_duration: u64) -> Result<()> {
-    Ok(parser.set_timeout_micros(max_duration))
+fn cancel_token(token: &CancellationToken) -> Result<()> {
+    Ok(token.0.store(1, Ordering::SeqCst))
+}
+
+/// Return t if TOKEN was cancelled, and has not been reset since.
+#[defun]
+fn token_cancelled_p(token: &CancellationToken) -> Result<bool> {
+    Ok(is_set(&token.0))
+}
+
+/// Reset TOKEN, so that parsers it is attached to can parse again.
+#[defun]
+fn reset_cancellation_token(token: &CancellationToken) -> Result<()> {
+    Ok(token.0.store(0, Ordering::SeqCst))
+}
+
+/// Attach the cancellation TOKEN to PARSER. See `tsc-set-cancellation-token'.
+///
+/// PARSER only holds a raw pointer to TOKEN's flag. The caller must keep TOKEN alive while it's
+/// attached.
+#[defun]
+fn _set_cancellation_token(
+    parser: &mut Parser,
+    token: Option<&RefCell<CancellationToken>>,
+) -> Result<()> {
+    let flag = token.map(|token| Arc::as_ptr(&token.borrow().0));
+    // Safety: The Lisp side keeps TOKEN, which owns the flag, alive while it's attached.
+    unsafe { parser.set_cancellation_flag(flag.map(|flag| &*flag)) };
+    Ok(())
 }
 
 /// Set the RANGES of text that PARSER should include when parsing.
@@ -136,13 +458,29 @@ fn _set_timeout_micros(parser: &mut Parser, max_duration: u64) -> Result<()> {
 /// This is useful for parsing multi-language documents.
 #[defun]
 fn set_included_ranges(parser: &mut Parser, ranges: Vector) -> Result<()> {
+    let included = included_ranges(ranges)?;
+    set_ranges(ranges.value().env, parser, &included)
+}
+
+/// Convert a vector of RANGES into tree-sitter ranges.
+pub(crate) fn included_ranges(ranges: Vector) -> Result<Vec<tree_sitter::Range>> {
     let len = ranges.len();
-    let included = &mut Vec::with_capacity(len);
+    let mut included = Vec::with_capacity(len);
     for i in 0..len {
         let range: Range = ranges.get(i)?;
         included.push(range.into());
     }
-    parser.set_included_ranges(included).or_else(|error| {
-        ranges.value().env.signal(error::tsc_invalid_ranges, (error.0, ))
+    Ok(included)
+}
+
+/// Set the RANGES of text that PARSER should include, signaling `tsc-invalid-ranges' if they are
+/// not ordered or overlapping.
+pub(crate) fn set_ranges(
+    env: &Env,
+    parser: &mut Parser,
+    ranges: &[tree_sitter::Range],
+) -> Result<()> {
+    parser.set_included_ranges(ranges).or_else(|error| {
+        env.signal(error::tsc_invalid_ranges, (error.0, ))
     })
 }
@@ -1,15 +1,61 @@
-use std::{cell::RefCell, iter};
+use std::{
+    cell::{Cell, RefCell},
+    collections::{hash_map::DefaultHasher, BTreeMap, HashMap, HashSet},
+    hash::{Hash, Hasher},
+    iter, mem,
+    rc::Rc,
+};
 
-use emacs::{defun, Env, Error, GlobalRef, IntoLisp, Result, Value, Vector};
-use tree_sitter::{Node, QueryCursor, QueryErrorKind, TextProvider};
+use emacs::{defun, Env, Error, FromLisp, GlobalRef, IntoLisp, Result, Value, Vector};
+use once_cell::sync::Lazy;
+use regex::Regex;
+use tree_sitter::{
+    CaptureQuantifier, Node, QueryCapture, QueryCursor, QueryError, QueryErrorKind, QueryMatch,
+    QueryPredicateArg,
+    TextProvider, Tree,
+};
 
 use crate::{
-    types::{BytePos, Point},
+    types::{self, BytePos, Point},
     lang::Language,
     node::{RNode, LispUtils},
+    tree::Borrowed,
     error,
 };
 
+emacs::use_symbols! {
+    assoc cdr list symbol_value tsc_query_predicate_functions
+    stringp symbol_name tsc__query_regexp_match_p
+
+    _zero         => ":zero"
+    _zero_or_one  => ":zero-or-one"
+    _zero_or_more => ":zero-or-more"
+    _one          => ":one"
+    _one_or_more  => ":one-or-more"
+
+    _syntax    => ":syntax"
+    _node_type => ":node-type"
+    _field     => ":field"
+    _capture   => ":capture"
+    _predicate => ":predicate"
+    _structure => ":structure"
+    _language  => ":language"
+
+    _capture_tag   => ":capture-tag"
+    _capture_index => ":capture-index"
+    _pattern_index => ":pattern-index"
+    _start_byte    => ":start-byte"
+    _end_byte      => ":end-byte"
+    _node          => ":node"
+    _text          => ":text"
+
+    _kind    => ":kind"
+    _name    => ":name"
+    _message => ":message"
+    _point   => ":point"
+    _bytepos => ":bytepos"
+}
+
 fn vec_to_vector<'e, T: IntoLisp<'e>>(env: &'e Env, vec: Vec<T>) -> Result<Vector<'e>> {
     let vector = env.make_vector(vec.len(), ())?;
     for (i, v) in vec.into_iter().enumerate() {
@@ -21,13 +67,343 @@ fn vec_to_vector<'e, T: IntoLisp<'e>>(env: &'e Env, vec: Vec<T>) -> Result<Vecto
 // -------------------------------------------------------------------------------------------------
 // Query
 
-struct Query {
-    pub(crate) raw: tree_sitter::Query,
+pub struct Query {
+    /// Shared with the query cache, and other queries created from the same source.
+    pub(crate) raw: Rc<tree_sitter::Query>,
     pub(crate) capture_tags: Vec<GlobalRef>,
+    /// Whether each capture is returned in query results.
+    enabled: Vec<bool>,
+    /// The predicates of each pattern that tree-sitter doesn't evaluate itself.
+    predicates: Vec<Vec<Predicate>>,
+    /// The offsets in the source where `emacs-` was inserted before compiling. See
+    /// `with_emacs_regexp_predicates`.
+    insertions: Vec<usize>,
 }
 
 impl_pred!(query_p, &RefCell<Query>);
 
+impl Query {
+    fn is_enabled(&self, capture_index: u32) -> bool {
+        self.enabled[capture_index as usize]
+    }
+
+    /// Return whether the match M, found under NODE, satisfies the predicates of its pattern.
+    ///
+    /// Text predicates (`#eq?`, `#match?` and their negations) are evaluated by tree-sitter, so
+    /// only the others are checked here.
+    fn satisfies_predicates(
+        &self,
+        m: &QueryMatch,
+        node: &RNode,
+        text_function: Value,
+    ) -> Result<bool> {
+        for predicate in &self.predicates[m.pattern_index] {
+            if !predicate.is_satisfied_by(m, node, text_function)? {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+}
+
+enum Predicate {
+    /// `#any-of?` and `#not-any-of?`: whether the text of each node captured by CAPTURE is one of
+    /// VALUES.
+    AnyOf { capture: u32, values: Vec<Box<str>>, positive: bool },
+    /// `#any-eq?` and `#any-not-eq?`: whether the text of any node captured by CAPTURE is equal to
+    /// OPERAND.
+    AnyEq { capture: u32, operand: Operand, positive: bool },
+    /// `#any-match?` and `#any-not-match?`: whether the text of any node captured by CAPTURE
+    /// matches REGEX.
+    AnyMatch { capture: u32, regex: Regex, positive: bool },
+    /// `#match?` and its variants, when `tsc-query-regexp-syntax' is `emacs': whether the text of
+    /// each node (or any node, if ANY) captured by CAPTURE matches the Emacs REGEXP.
+    EmacsMatch { capture: u32, regexp: GlobalRef, positive: bool, any: bool },
+    /// A custom predicate, registered with `tsc-query-define-predicate'.
+    Lisp { function: GlobalRef, args: Vec<Operand> },
+}
+
+enum Operand {
+    Capture(u32),
+    String(Box<str>),
+}
+
+impl From<&QueryPredicateArg> for Operand {
+    fn from(arg: &QueryPredicateArg) -> Self {
+        match arg {
+            QueryPredicateArg::Capture(capture) => Operand::Capture(*capture),
+            QueryPredicateArg::String(value) => Operand::String(value.clone()),
+        }
+    }
+}
+
+type ParseResult<T> = std::result::Result<T, String>;
+
+impl Predicate {
+    /// Parse the general PREDICATE. Return None if it's not one that should be evaluated in Rust.
+    fn new(env: &Env, predicate: &tree_sitter::QueryPredicate) -> ParseResult<Option<Self>> {
+        let operator = &*predicate.operator;
+        let capture = || match predicate.args.first() {
+            Some(QueryPredicateArg::Capture(capture)) => Ok(*capture),
+            _ => Err(format!("First argument to #{} must be a capture", operator)),
+        };
+        let operand = || match &predicate.args[..] {
+            [_, operand] => Ok(Operand::from(operand)),
+            _ => Err(format!("Wrong number of arguments to #{}", operator)),
+        };
+        let predicate = match operator {
+            "any-of?" | "not-any-of?" => {
+                let capture = capture()?;
+                let values = predicate.args[1..].iter().map(|value| match value {
+                    QueryPredicateArg::String(value) => Ok(value.clone()),
+                    QueryPredicateArg::Capture(_) => {
+                        Err(format!("Arguments to #{} must be strings", operator))
+                    }
+                }).collect::<ParseResult<_>>()?;
+                Predicate::AnyOf { capture, values, positive: operator == "any-of?" }
+            }
+            "any-eq?" | "any-not-eq?" => {
+                let capture = capture()?;
+                Predicate::AnyEq { capture, operand: operand()?, positive: operator == "any-eq?" }
+            }
+            "any-match?" | "any-not-match?" => {
+                let capture = capture()?;
+                let regex = match operand()? {
+                    Operand::String(regex) => {
+                        Regex::new(&regex).map_err(|error| error.to_string())?
+                    }
+                    Operand::Capture(_) => {
+                        return Err(format!("Second argument to #{} must be a string", operator));
+                    }
+                };
+                Predicate::AnyMatch { capture, regex, positive: operator == "any-match?" }
+            }
+            "emacs-match?" | "emacs-not-match?" | "emacs-any-match?" | "emacs-any-not-match?" => {
+                let capture = capture()?;
+                let regexp = match operand()? {
+                    Operand::String(regexp) => {
+                        (&*regexp).into_lisp(env).map_err(|error| error.to_string())?
+                    }
+                    Operand::Capture(_) => {
+                        return Err(format!("Second argument to #{} must be a string", operator));
+                    }
+                };
+                Predicate::EmacsMatch {
+                    capture,
+                    regexp: regexp.make_global_ref(),
+                    positive: !operator.contains("not-"),
+                    any: operator.contains("any-"),
+                }
+            }
+            _ => return Ok(None),
+        };
+        Ok(Some(predicate))
+    }
+
+    /// Return a custom predicate if there's a Lisp function registered for PREDICATE's operator.
+    fn lisp(env: &Env, predicate: &tree_sitter::QueryPredicate) -> Result<Option<Self>> {
+        let functions = env.call(symbol_value, [tsc_query_predicate_functions])?;
+        let entry = env.call(assoc, (&*predicate.operator, functions))?;
+        if !entry.is_not_nil() {
+            return Ok(None);
+        }
+        let function = env.call(cdr, [entry])?.make_global_ref();
+        let args = predicate.args.iter().map(Operand::from).collect();
+        Ok(Some(Predicate::Lisp { function, args }))
+    }
+
+    fn is_satisfied_by(&self, m: &QueryMatch, node: &RNode, text_function: Value) -> Result<bool> {
+        let texts = |capture: u32| {
+            m.nodes_for_capture_index(capture)
+                .map(|node| node_text(text_function, node))
+                .collect::<Result<Vec<_>>>()
+        };
+        match self {
+            Predicate::AnyOf { capture, values, positive } => {
+                for text in texts(*capture)? {
+                    if values.iter().any(|value| **value == text) != *positive {
+                        return Ok(false);
+                    }
+                }
+                Ok(true)
+            }
+            Predicate::AnyEq { capture, operand, positive } => {
+                let others = match operand {
+                    Operand::Capture(other) => texts(*other)?,
+                    Operand::String(value) => vec![value.to_string()],
+                };
+                Ok(texts(*capture)?.iter().any(|text| others.contains(text) == *positive))
+            }
+            Predicate::AnyMatch { capture, regex, positive } => {
+                Ok(texts(*capture)?.iter().any(|text| regex.is_match(text) == *positive))
+            }
+            Predicate::EmacsMatch { capture, regexp, positive, any } => {
+                let env = text_function.env;
+                let regexp = regexp.bind(env);
+                for captured in m.nodes_for_capture_index(*capture) {
+                    let text = text_function
+                        .call((captured.lisp_start_byte(), captured.lisp_end_byte()))?;
+                    let matched = env.call(tsc__query_regexp_match_p, (regexp, text))?.is_not_nil();
+                    if (matched == *positive) == *any {
+                        return Ok(*any);
+                    }
+                }
+                Ok(!*any)
+            }
+            Predicate::Lisp { function, args } => {
+                let env = text_function.env;
+                let mut values = Vec::with_capacity(args.len());
+                for arg in args {
+                    values.push(match arg {
+                        Operand::Capture(capture) => {
+                            match m.nodes_for_capture_index(*capture).next() {
+                                Some(captured) => node.map(|_| captured).into_lisp(env)?,
+                                None => ().into_lisp(env)?,
+                            }
+                        }
+                        Operand::String(value) => (&**value).into_lisp(env)?,
+                    });
+                }
+                Ok(function.bind(env).call(&values[..])?.is_not_nil())
+            }
+        }
+    }
+}
+
+fn node_text(text_function: Value, node: Node) -> Result<String> {
+    text_function.call((node.lisp_start_byte(), node.lisp_end_byte()))?.into_rust()
+}
+
+/// Return the point at byte OFFSET in SOURCE.
+fn point_at(source: &str, offset: usize) -> Point {
+    let before = &source[..offset];
+    let row = before.matches('\n').count();
+    let column = offset - before.rfind('\n').map_or(0, |i| i + 1);
+    tree_sitter::Point { row, column }.into()
+}
+
+/// Recently compiled queries, so that e.g. the highlighting query of a language is compiled only
+/// once, instead of once per buffer.
+struct QueryCache {
+    capacity: usize,
+    /// Keyed by language id and the source's hash, least recently used first.
+    entries: Vec<(usize, u64, String, Rc<tree_sitter::Query>)>,
+}
+
+const DEFAULT_QUERY_CACHE_CAPACITY: usize = 32;
+
+thread_local! {
+    static QUERY_CACHE: RefCell<QueryCache> = RefCell::new(QueryCache {
+        capacity: DEFAULT_QUERY_CACHE_CAPACITY,
+        entries: vec![],
+    });
+}
+
+impl QueryCache {
+    fn get(&mut self, language: usize, hash: u64, source: &str) -> Option<Rc<tree_sitter::Query>> {
+        let i = self.entries.iter().position(|(l, h, s, _)| {
+            *l == language && *h == hash && s == source
+        })?;
+        let entry = self.entries.remove(i);
+        let raw = entry.3.clone();
+        self.entries.push(entry);
+        Some(raw)
+    }
+
+    fn insert(&mut self, language: usize, hash: u64, source: String, raw: Rc<tree_sitter::Query>) {
+        self.entries.push((language, hash, source, raw));
+        self.evict();
+    }
+
+    fn evict(&mut self) {
+        let excess = self.entries.len().saturating_sub(self.capacity);
+        self.entries.drain(..excess);
+    }
+}
+
+/// Set the maximum number of compiled queries kept for reuse by `tsc-make-query' to
+/// CAPACITY. The least recently used ones are discarded first. 0 disables caching.
+#[defun]
+fn set_query_cache_capacity(capacity: usize) -> Result<()> {
+    QUERY_CACHE.with(|cache| {
+        let mut cache = cache.borrow_mut();
+        cache.capacity = capacity;
+        cache.evict();
+    });
+    Ok(())
+}
+
+/// Return the number of compiled queries kept for reuse by `tsc-make-query'.
+#[defun]
+fn query_cache_count() -> Result<usize> {
+    Ok(QUERY_CACHE.with(|cache| cache.borrow().entries.len()))
+}
+
+/// Discard all the compiled queries kept for reuse by `tsc-make-query'.
+#[defun]
+fn clear_query_cache() -> Result<()> {
+    QUERY_CACHE.with(|cache| cache.borrow_mut().entries.clear());
+    Ok(())
+}
+
+static EMACS_REGEXP_PREDICATE: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"^#(?:any-)?(?:not-)?match\?").unwrap()
+});
+
+const EMACS_PREFIX: &str = "emacs-";
+
+/// Rename `#match?` and its variants in SOURCE to the predicates that use Emacs regexps, leaving
+/// string literals and comments alone. Return the new source, and the offsets in SOURCE where the
+/// prefix was inserted.
+fn with_emacs_regexp_predicates(source: &str) -> (String, Vec<usize>) {
+    let mut result = String::with_capacity(source.len());
+    let mut insertions = vec![];
+    let mut in_string = false;
+    let mut in_comment = false;
+    let mut escaped = false;
+    for (i, c) in source.char_indices() {
+        match c {
+            _ if in_comment => in_comment = c != '\n',
+            _ if escaped => escaped = false,
+            '\\' if in_string => escaped = true,
+            '"' => in_string = !in_string,
+            ';' if !in_string => in_comment = true,
+            '#' if !in_string && EMACS_REGEXP_PREDICATE.is_match(&source[i..]) => {
+                result.push(c);
+                result.push_str(EMACS_PREFIX);
+                insertions.push(i + 1);
+                continue;
+            }
+            _ => {}
+        }
+        result.push(c);
+    }
+    (result, insertions)
+}
+
+/// Map OFFSET in a source rewritten with INSERTIONS back to the original source. Offsets within
+/// an inserted prefix are mapped to where it was inserted.
+fn original_offset(insertions: &[usize], offset: usize) -> usize {
+    let mut shift = 0;
+    for &position in insertions {
+        let inserted = position + shift;
+        if offset < inserted {
+            break;
+        }
+        if offset < inserted + EMACS_PREFIX.len() {
+            return position;
+        }
+        shift += EMACS_PREFIX.len();
+    }
+    offset - shift
+}
+
+/// Map OFFSET in an original source to the source rewritten with INSERTIONS.
+fn rewritten_offset(insertions: &[usize], offset: usize) -> usize {
+    let count = insertions.iter().take_while(|&&position| position <= offset).count();
+    offset + EMACS_PREFIX.len() * count
+}
+
 /// Create a new query from a SOURCE containing one or more S-expression patterns.
 ///
 /// The query is associated with LANGUAGE, and can only be run on syntax nodes
@@ -37,34 +413,201 @@ impl_pred!(query_p, &RefCell<Query>);
 /// in query results. It should take a capture name defined in SOURCE's patterns
 /// (e.g. "function.builtin"), and return a tag value. If the return value is nil,
 /// the associated capture name is disabled.
+///
+/// Compiled queries are cached, so creating the same query again is cheap. See
+/// `tsc-set-query-cache-capacity'.
+///
+/// If EMACS-REGEXPS is non-nil, the regular expressions of `#match?' and its
+/// variants use Emacs's syntax, instead of Rust's. See `tsc-query-regexp-syntax'.
 #[defun(user_ptr)]
-fn _make_query(language: Language, source: String, tag_assigner: Value) -> Result<Query> {
-    let mut raw = tree_sitter::Query::new(language.into(), &source).or_else(|err| {
-        let symbol = match err.kind {
-            QueryErrorKind::Syntax => error::tsc_query_invalid_syntax,
-            QueryErrorKind::NodeType => error::tsc_query_invalid_node_type,
-            QueryErrorKind::Field => error::tsc_query_invalid_field,
-            QueryErrorKind::Capture => error::tsc_query_invalid_capture,
-            QueryErrorKind::Predicate => error::tsc_query_invalid_predicate,
-            QueryErrorKind::Structure => error::tsc_query_invalid_structure,
-            QueryErrorKind::Language => error::tsc_lang_abi_error,
-        };
-        let byte_pos: BytePos = err.offset.into();
-        let point: Point = tree_sitter::Point { row: err.row, column: err.column }.into();
-        // TODO: Character position?
-        // TODO: Convert named node types and field names to symbols and keywords?
-        tag_assigner.env.signal(symbol, (err.message, point, byte_pos))
-    })?;
-    let capture_names = raw.capture_names().to_vec();
+fn _make_query(
+    language: Language,
+    source: String,
+    tag_assigner: Value,
+    emacs_regexps: Option<Value>,
+) -> Result<Query> {
+    let env = tag_assigner.env;
+    // Offsets reported to Lisp are mapped back to SOURCE.
+    let (compiled, insertions) = match emacs_regexps {
+        Some(_) => with_emacs_regexp_predicates(&source),
+        None => (source.clone(), vec![]),
+    };
+    let mut hasher = DefaultHasher::new();
+    compiled.hash(&mut hasher);
+    let hash = hasher.finish();
+    let cached = QUERY_CACHE.with(|cache| cache.borrow_mut().get(language.id(), hash, &compiled));
+    let raw = match cached {
+        Some(raw) => raw,
+        None => {
+            let raw = tree_sitter::Query::new(language.into(), &compiled).or_else(|err| {
+                let offset = original_offset(&insertions, err.offset);
+                signal_query_error(env, err, point_at(&source, offset), offset)
+            })?;
+            let raw = Rc::new(raw);
+            QUERY_CACHE.with(|cache| {
+                cache.borrow_mut().insert(language.id(), hash, compiled, raw.clone())
+            });
+            raw
+        }
+    };
     let mut capture_tags = vec![];
-    for name in &capture_names {
+    let mut enabled = vec![];
+    for name in raw.capture_names() {
         let value = tag_assigner.call((name, ))?;
-        if !value.is_not_nil() {
-            raw.disable_capture(name);
-        }
+        enabled.push(value.is_not_nil());
         capture_tags.push(value.make_global_ref())
     }
-    Ok(Query { raw, capture_tags })
+    let mut predicates = Vec::with_capacity(raw.pattern_count());
+    for i in 0..raw.pattern_count() {
+        let mut pattern_predicates = vec![];
+        for predicate in raw.general_predicates(i) {
+            match Predicate::new(env, predicate) {
+                Ok(Some(predicate)) => pattern_predicates.push(predicate),
+                Ok(None) => {
+                    if let Some(predicate) = Predicate::lisp(tag_assigner.env, predicate)? {
+                        pattern_predicates.push(predicate);
+                    }
+                }
+                Err(message) => {
+                    let offset = original_offset(&insertions, raw.start_byte_for_pattern(i));
+                    let byte_pos: BytePos = offset.into();
+                    return tag_assigner.env.signal(error::tsc_query_invalid_predicate, (
+                        message, point_at(&source, offset), byte_pos, _predicate,
+                    ));
+                }
+            }
+        }
+        predicates.push(pattern_predicates);
+    }
+    Ok(Query { raw, capture_tags, enabled, predicates, insertions })
+}
+
+/// Signal the error ERR, which happened at POINT and byte OFFSET in a query's source.
+fn signal_query_error<T>(env: &Env, err: QueryError, point: Point, offset: usize) -> Result<T> {
+    let (symbol, kind) = match err.kind {
+        QueryErrorKind::Syntax => (error::tsc_query_invalid_syntax, _syntax),
+        QueryErrorKind::NodeType => (error::tsc_query_invalid_node_type, _node_type),
+        QueryErrorKind::Field => (error::tsc_query_invalid_field, _field),
+        QueryErrorKind::Capture => (error::tsc_query_invalid_capture, _capture),
+        QueryErrorKind::Predicate => (error::tsc_query_invalid_predicate, _predicate),
+        QueryErrorKind::Structure => (error::tsc_query_invalid_structure, _structure),
+        QueryErrorKind::Language => (error::tsc_lang_abi_error, _language),
+    };
+    let byte_pos: BytePos = offset.into();
+    // TODO: Character position?
+    // TODO: Convert named node types and field names to symbols and keywords?
+    env.signal(symbol, (err.message, point, byte_pos, kind))
+}
+
+/// Return the end of the parenthesized S-expression starting at START in SOURCE.
+fn sexp_end(source: &str, start: usize) -> usize {
+    let mut depth = 0;
+    let mut in_string = false;
+    let mut escaped = false;
+    for (i, c) in source[start..].char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if in_string => escaped = true,
+            '"' => in_string = !in_string,
+            '(' if !in_string => depth += 1,
+            ')' if !in_string => {
+                depth -= 1;
+                if depth == 0 {
+                    return start + i + 1;
+                }
+            }
+            _ => {}
+        }
+    }
+    source.len()
+}
+
+/// Check the query SOURCE against LANGUAGE, and return a list of the problems found. See
+/// `tsc-validate-query'.
+///
+/// tree-sitter stops at the first error, so each unknown name is replaced with a placeholder of
+/// the same length, and the query is compiled again, until there are no more such errors.
+#[defun]
+fn _validate_query<'e>(env: &'e Env, language: Language, source: String) -> Result<Value<'e>> {
+    let mut source = source;
+    // (KIND, NAME, MESSAGE, OFFSET).
+    let mut problems: Vec<(_, Option<String>, Option<String>, usize)> = vec![];
+    loop {
+        let err = match tree_sitter::Query::new(language.into(), &source) {
+            Ok(query) => {
+                for i in 0..query.pattern_count() {
+                    for predicate in query.general_predicates(i) {
+                        if let Err(message) = Predicate::new(env, predicate) {
+                            let offset = query.start_byte_for_pattern(i);
+                            problems.push((_predicate, None, Some(message), offset));
+                        }
+                    }
+                }
+                break;
+            }
+            Err(err) => err,
+        };
+        let offset = err.offset;
+        let bytes = source.as_bytes();
+        let before = offset.checked_sub(1).map(|i| bytes[i]);
+        let name_len = source[offset..]
+            .find(|c: char| c.is_whitespace() || "()[]\":".contains(c))
+            .unwrap_or(source.len() - offset);
+        let name_end = offset + name_len;
+        // The unknown name ends at NAME-END. The range from START to END is replaced with
+        // PLACEHOLDER, padded with spaces.
+        let (kind, name_end, start, end, placeholder) = match err.kind {
+            // An anonymous node type, e.g. "fn".
+            QueryErrorKind::NodeType if before == Some(b'"') => {
+                let name_end = source[offset..].find('"').map_or(source.len(), |i| offset + i);
+                (_node_type, name_end, offset - 1, (name_end + 1).min(source.len()), "_")
+            }
+            QueryErrorKind::NodeType => (_node_type, name_end, offset, name_end, "_"),
+            QueryErrorKind::Field => {
+                // A negated field, e.g. !body, or a field followed by a colon, e.g. body:.
+                let start = if before == Some(b'!') { offset - 1 } else { offset };
+                let colon = (bytes.get(name_end) == Some(&b':')) as usize;
+                (_field, name_end, start, name_end + colon, "")
+            }
+            // An undeclared capture used in a predicate, which is removed as a whole.
+            QueryErrorKind::Capture => match source[..offset].rfind("(#") {
+                Some(start) => (_capture, name_end, start, sexp_end(&source, start), ""),
+                None => (_capture, name_end, offset, offset, ""),
+            },
+            kind => {
+                let kind = match kind {
+                    QueryErrorKind::Syntax => _syntax,
+                    QueryErrorKind::Predicate => _predicate,
+                    QueryErrorKind::Structure => _structure,
+                    _ => _language,
+                };
+                problems.push((kind, None, Some(err.message), offset));
+                break;
+            }
+        };
+        if name_end <= offset || end <= start || end - start < placeholder.len() {
+            problems.push((kind, None, Some(err.message), offset));
+            break;
+        }
+        problems.push((kind, Some(source[offset..name_end].to_owned()), None, offset));
+        let padding = " ".repeat(end - start - placeholder.len());
+        source.replace_range(start..end, &format!("{}{}", placeholder, padding));
+    }
+    let mut list = ().into_lisp(env)?;
+    for (kind, name, message, offset) in problems.into_iter().rev() {
+        let bytepos: BytePos = offset.into();
+        let mut plist = env.cons(_bytepos, env.cons(bytepos, ())?)?;
+        plist = env.cons(_point, env.cons(point_at(&source, offset), plist)?)?;
+        if let Some(message) = message {
+            plist = env.cons(_message, env.cons(message, plist)?)?;
+        }
+        if let Some(name) = name {
+            plist = env.cons(_name, env.cons(name, plist)?)?;
+        }
+        plist = env.cons(_kind, env.cons(kind, plist)?)?;
+        list = env.cons(plist, list)?;
+    }
+    Ok(list)
 }
 
 macro_rules! defun_query_methods {
@@ -79,17 +622,86 @@ macro_rules! defun_query_methods {
     };
 }
 
-defun_query_methods! {
-    /// Return the byte position where the NTH pattern starts in QUERY's source.
-    "-query-start-byte-for-pattern" fn start_byte_for_pattern(nth: usize) -> BytePos; into
+/// Return the byte position where the NTH pattern starts in QUERY's source.
+#[defun(name = "query-start-byte-for-pattern")]
+fn query_start_byte_for_pattern(query: &Query, nth: usize) -> Result<BytePos> {
+    Ok(original_offset(&query.insertions, query.raw.start_byte_for_pattern(nth)).into())
+}
 
+defun_query_methods! {
     /// Return the number of patterns in QUERY.
     "query-count-patterns" fn pattern_count -> usize
 }
 
-/// Return the names of the captures used in QUERY.
+/// Return t if the query step at BYTEPOS in QUERY's source is definite.
+///
+/// A step is definite if, once the query has matched the steps before it, the node
+/// matching this step is guaranteed to be present, e.g. because the grammar always
+/// produces it. BYTEPOS should point to the start of the step, e.g. of a node type
+/// or a capture name.
+#[defun]
+fn query_step_definite_p(query: &Query, bytepos: BytePos) -> Result<bool> {
+    let offset = rewritten_offset(&query.insertions, bytepos.into());
+    Ok(query.raw.is_pattern_guaranteed_at_step(offset))
+}
+
+/// Return the properties set by the `#set!' directives of QUERY's NTH pattern, as
+/// a plist.
+///
+/// For example, the pattern ((string_content) @injection.content (#set!
+/// injection.language "sql")) has the properties (:injection.language "sql"). A
+/// property set without a value, e.g. (#set! injection.combined), has the value t.
+/// Since the properties are the same for all of a pattern's matches, they can be
+/// looked up using the PATTERN-INDEX of the matches returned by `tsc-query-matches'.
+#[defun]
+fn query_pattern_properties<'e>(env: &'e Env, query: &Query, nth: usize) -> Result<Value<'e>> {
+    let mut plist = ().into_lisp(env)?;
+    for property in query.raw.property_settings(nth).iter().rev() {
+        let value = match &property.value {
+            Some(value) => (&**value).into_lisp(env)?,
+            None => true.into_lisp(env)?,
+        };
+        plist = env.cons(value, plist)?;
+        plist = env.cons(env.intern(&format!(":{}", property.key))?, plist)?;
+    }
+    Ok(plist)
+}
+
+/// Return how many times each capture can occur in a match of QUERY's NTH pattern.
+///
+/// The result is a vector, in the same order as the capture tags returned by
+/// `tsc-query-capture-tags'. Each element is one of:
+///  - `:zero': the capture is not used in the pattern.
+///  - `:zero-or-one': the capture is optional, e.g. @c in ((a)? @c).
+///  - `:zero-or-more': e.g. @c in ((a)* @c).
+///  - `:one': the capture occurs exactly once.
+///  - `:one-or-more': e.g. @c in ((a)+ @c).
+///
+/// This tells whether the captured nodes should be treated as a single value, or
+/// as a list.
+#[defun]
+fn query_capture_quantifiers<'e>(env: &'e Env, query: &Query, nth: usize) -> Result<Vector<'e>> {
+    let quantifiers = query.raw.capture_quantifiers(nth);
+    let vector = env.make_vector(quantifiers.len(), ())?;
+    for (i, quantifier) in quantifiers.iter().enumerate() {
+        vector.set(i, match quantifier {
+            CaptureQuantifier::Zero => _zero,
+            CaptureQuantifier::ZeroOrOne => _zero_or_one,
+            CaptureQuantifier::ZeroOrMore => _zero_or_more,
+            CaptureQuantifier::One => _one,
+            CaptureQuantifier::OneOrMore => _one_or_more,
+        })?;
+    }
+    Ok(vector)
+}
+
+/// Return the names of the captures used in QUERY, as a vector of strings.
+///
+/// A capture's position in this vector is its index, which is used by e.g.
+/// `tsc-query-captures-into'. Resolving capture names to indices once allows
+/// comparing small integers, instead of strings or tags, when processing results.
 #[defun]
-fn _query_capture_names(query: Value) -> Result<Vector> {
+fn query_capture_names(query: Value) -> Result<Vector> {
     let env = query.env;
     let query = query.into_ref::<Query>()?;
     let names = query.raw.capture_names();
@@ -100,6 +712,13 @@ fn _query_capture_names(query: Value) -> Result<Vector> {
     Ok(vec)
 }
 
+/// Return the index of the capture named NAME in QUERY, or nil if there's no such
+/// capture. NAME should not have the prefix "@". See `tsc-query-capture-names'.
+#[defun]
+fn query_capture_index(query: &Query, name: String) -> Result<Option<u32>> {
+    Ok(query.raw.capture_index_for_name(&name))
+}
+
 /// Return all of QUERY's available capture tags.
 /// See `tsc-make-query' for an explanation of capture tagging.
 #[defun(mod_in_name = true)]
@@ -113,11 +732,12 @@ fn capture_tags<'e>(env: &'e Env, query: &Query) -> Result<Vector<'e>> {
 
 /// Disable a certain capture within QUERY, by specifying its NAME.
 ///
-/// This prevents the capture from being returned in matches, and also avoids any
-/// resource usage associated with recording the capture.
+/// This prevents the capture from being returned in matches.
 #[defun]
 fn _disable_capture(query: &mut Query, name: String) -> Result<()> {
-    query.raw.disable_capture(&name);
+    if let Some(i) = query.raw.capture_names().iter().position(|n| *n == name) {
+        query.enabled[i] = false;
+    }
     Ok(())
 }
 
@@ -149,130 +769,704 @@ fn text_callback<'e>(
     }
 }
 
-#[defun]
-fn _query_cursor_matches<'e>(
+/// Execute QUERY on NODE, calling F with each match that satisfies QUERY's predicates, in the order
+/// they were found.
+fn for_each_match<'e, F>(
     cursor: &mut QueryCursor,
     query: &Query,
     node: &RNode,
     text_function: Value<'e>,
-) -> Result<Vector<'e>> {
-    let raw = &query.raw;
+    mut f: F,
+) -> Result<()>
+where
+    F: FnMut(&QueryMatch) -> Result<()>,
+{
     let error = RefCell::new(None);
     let matches = cursor.matches(
-        raw,
+        &query.raw,
         node.borrow().clone(),
         text_callback(text_function, &error),
     );
-    let mut vec = vec![];
-    let env = text_function.env;
     for m in matches {
         if let Some(error) = error.borrow_mut().take() {
             return Err(error);
         }
-        let captures = env.make_vector(m.captures.len(), ())?;
-        for (ci, c) in m.captures.iter().enumerate() {
-            let captured_node = node.map(|_| c.node);
-            let capture = env.cons(
-                &query.capture_tags[c.index as usize],
-                captured_node
-            )?;
-            captures.set(ci, capture)?;
+        if query.satisfies_predicates(&m, node, text_function)? {
+            f(&m)?;
         }
-        let _match = env.cons(m.pattern_index, captures)?;
-        vec.push(_match);
     }
-    vec_to_vector(env, vec)
+    match error.borrow_mut().take() {
+        Some(error) => Err(error),
+        None => Ok(()),
+    }
 }
 
-// TODO: Make _query_cursor_captures accept a `capture_type` instead, e.g. node type, byte range.
-#[defun]
-fn _query_cursor_captures_1<'e>(
+/// Execute QUERY on NODE, calling F with each enabled capture of the matches that satisfy QUERY's
+/// predicates, together with its match, in the order the captures appear.
+fn for_each_capture<'e, F>(
     cursor: &mut QueryCursor,
-    query: Value<'e>,
+    query: &Query,
     node: &RNode,
     text_function: Value<'e>,
-) -> Result<Vector<'e>> {
-    let query = query.into_rust::<&RefCell<Query>>()?.borrow();
-    let raw = &query.raw;
+    mut f: F,
+) -> Result<()>
+where
+    F: FnMut(&QueryMatch, QueryCapture) -> Result<()>,
+{
     let error = RefCell::new(None);
     let captures = cursor.captures(
-        raw,
+        &query.raw,
         node.borrow().clone(),
         text_callback(text_function, &error),
     );
-    let mut vec = vec![];
-    let env = text_function.env;
+    // A match is yielded once per capture, but its predicates are evaluated only once, since
+    // they can be costly, or call into Lisp. Keyed by match id.
+    let mut verdicts: HashMap<u32, bool> = HashMap::new();
     for (m, capture_index) in captures {
         if let Some(error) = error.borrow_mut().take() {
             return Err(error);
         }
+        let satisfied = match verdicts.get(&m.id()) {
+            Some(&satisfied) => satisfied,
+            None => {
+                let satisfied = query.satisfies_predicates(&m, node, text_function)?;
+                verdicts.insert(m.id(), satisfied);
+                satisfied
+            }
+        };
+        if capture_index + 1 == m.captures.len() {
+            verdicts.remove(&m.id());
+        }
+        if !satisfied {
+            continue;
+        }
         let c = m.captures[capture_index];
+        if query.is_enabled(c.index) {
+            f(&m, c)?;
+        }
+    }
+    match error.borrow_mut().take() {
+        Some(error) => Err(error),
+        None => Ok(()),
+    }
+}
+
+#[defun]
+fn _query_cursor_matches<'e>(
+    cursor: &mut QueryCursor,
+    query: &Query,
+    node: &RNode,
+    text_function: Value<'e>,
+) -> Result<Vector<'e>> {
+    let env = text_function.env;
+    let mut vec = vec![];
+    for_each_match(cursor, query, node, text_function, |m| {
+        vec.push(match_to_lisp(env, query, node, m)?);
+        Ok(())
+    })?;
+    vec_to_vector(env, vec)
+}
+
+/// Convert M, found under NODE, to a (PATTERN-INDEX . CAPTURES) cons cell.
+fn match_to_lisp<'e>(
+    env: &'e Env,
+    query: &Query,
+    node: &RNode,
+    m: &QueryMatch,
+) -> Result<Value<'e>> {
+    let mut captures = vec![];
+    for c in m.captures.iter().filter(|c| query.is_enabled(c.index)) {
+        let captured_node = node.map(|_| c.node);
         let capture = env.cons(
             &query.capture_tags[c.index as usize],
-            c.node.lisp_byte_range(env)?,
+            captured_node
         )?;
-        vec.push((m.pattern_index, capture));
+        captures.push(capture);
     }
-    // Prioritize captures from earlier patterns.
-    vec.sort_unstable_by_key(|(i, _)| *i);
-    let vector = env.make_vector(vec.len(), ())?;
-    for (i, (_, v)) in vec.into_iter().enumerate() {
-        vector.set(i, v)?;
+    env.cons(m.pattern_index, vec_to_vector(env, captures)?)
+}
+
+/// Execute QUERY on NODE, and return its matches grouped by pattern.
+/// See `tsc-query-matches-by-pattern'.
+#[defun]
+fn _query_cursor_matches_by_pattern<'e>(
+    cursor: &mut QueryCursor,
+    query: &Query,
+    node: &RNode,
+    text_function: Value<'e>,
+) -> Result<Vector<'e>> {
+    let env = text_function.env;
+    let mut groups = vec![vec![]; query.raw.pattern_count()];
+    for_each_match(cursor, query, node, text_function, |m| {
+        let mut plist = vec![];
+        for c in m.captures.iter().filter(|c| query.is_enabled(c.index)) {
+            plist.push(query.capture_tags[c.index as usize].bind(env));
+            plist.push(node.map(|_| c.node).into_lisp(env)?);
+        }
+        groups[m.pattern_index].push(env.call(list, &plist[..])?);
+        Ok(())
+    })?;
+    let vector = env.make_vector(groups.len(), ())?;
+    for (i, group) in groups.into_iter().enumerate() {
+        vector.set(i, env.call(list, &group[..])?)?;
     }
     Ok(vector)
 }
 
+// TODO: Make _query_cursor_captures accept a `capture_type` instead, e.g. node type, byte range.
 #[defun]
-fn _query_cursor_captures<'e>(
+fn _query_cursor_captures_1<'e>(
     cursor: &mut QueryCursor,
     query: Value<'e>,
     node: &RNode,
     text_function: Value<'e>,
+    deduplicate: Option<Value<'e>>,
 ) -> Result<Vector<'e>> {
     let query = query.into_rust::<&RefCell<Query>>()?.borrow();
-    let raw = &query.raw;
-    let error = RefCell::new(None);
-    let captures = cursor.captures(
-        raw,
-        node.borrow().clone(),
-        text_callback(text_function, &error),
-    );
+    let env = text_function.env;
     let mut vec = vec![];
+    for_each_capture(cursor, &query, node, text_function, |m, c| {
+        let capture = env.cons(
+            &query.capture_tags[c.index as usize],
+            c.node.lisp_byte_range(env)?,
+        )?;
+        vec.push((m.pattern_index, c.node.start_byte(), c.node.end_byte(), capture));
+        Ok(())
+    })?;
+    let vec = if deduplicate.is_some() {
+        deduplicated(vec)
+    } else {
+        // Prioritize captures from earlier patterns.
+        vec.sort_unstable_by_key(|(i, ..)| *i);
+        vec.into_iter().map(|(.., v)| v).collect()
+    };
+    vec_to_vector(env, vec)
+}
+
+#[defun]
+fn _query_cursor_captures<'e>(
+    cursor: &mut QueryCursor,
+    query: Value<'e>,
+    node: &RNode,
+    text_function: Value<'e>,
+    deduplicate: Option<Value<'e>>,
+) -> Result<Vector<'e>> {
+    let query = query.into_rust::<&RefCell<Query>>()?.borrow();
+    captures(cursor, &query, node, text_function, deduplicate.is_some())
+}
+
+/// Return a new query cursor with the same match limit as CURSOR, for executing a query over a
+/// narrower byte range. CURSOR itself cannot be used for that, because its byte range cannot be
+/// read back, to be restored afterward.
+fn scratch_cursor(cursor: &QueryCursor) -> QueryCursor {
+    let mut scratch = QueryCursor::new();
+    scratch.set_match_limit(cursor.match_limit());
+    scratch
+}
+
+/// Execute QUERY on NODE, limited to NODE's byte range, and return the captures.
+/// See `tsc-query-captures-in-node'.
+#[defun]
+fn _query_captures_in_node<'e>(
+    cursor: &QueryCursor,
+    query: &Query,
+    node: &RNode,
+    text_function: Value<'e>,
+    deduplicate: Option<Value<'e>>,
+) -> Result<Vector<'e>> {
+    let range = {
+        let node = node.borrow();
+        node.start_byte()..node.end_byte()
+    };
+    let mut limited = scratch_cursor(cursor);
+    limited.set_byte_range(range);
+    captures(&mut limited, query, node, text_function, deduplicate.is_some())
+}
+
+fn captures<'e>(
+    cursor: &mut QueryCursor,
+    query: &Query,
+    node: &RNode,
+    text_function: Value<'e>,
+    deduplicate: bool,
+) -> Result<Vector<'e>> {
+    let vec = collect_captures(cursor, query, node, text_function)?;
+    let vec = if deduplicate {
+        deduplicated(vec)
+    } else {
+        vec.into_iter().map(|(.., v)| v).collect()
+    };
+    vec_to_vector(text_function.env, vec)
+}
+
+/// Execute QUERY on NODE, and return the captures, in the order they appear, as tuples of the form
+/// (PATTERN-INDEX, START-BYTE, END-BYTE, (CAPTURE-TAG . CAPTURED-NODE)).
+pub(crate) fn collect_captures<'e>(
+    cursor: &mut QueryCursor,
+    query: &Query,
+    node: &RNode,
+    text_function: Value<'e>,
+) -> Result<Vec<(usize, usize, usize, Value<'e>)>> {
     let env = text_function.env;
-    for (m, capture_index) in captures {
-        if let Some(error) = error.borrow_mut().take() {
-            return Err(error);
-        }
-        let c = m.captures[capture_index];
+    let mut vec = vec![];
+    for_each_capture(cursor, query, node, text_function, |m, c| {
         let captured_node = node.map(|_| c.node);
         let capture = env.cons(
             &query.capture_tags[c.index as usize],
             captured_node
         )?;
-        vec.push(capture);
+        vec.push((m.pattern_index, c.node.start_byte(), c.node.end_byte(), capture));
+        Ok(())
+    })?;
+    Ok(vec)
+}
+
+/// Execute QUERY on NEW-TREE, within the ranges whose syntactic structure changed since OLD-TREE,
+/// extended by PADDING bytes on both sides, and return the captures. See
+/// `tsc-query-changed-captures'.
+#[defun]
+fn _query_changed_captures<'e>(
+    cursor: &QueryCursor,
+    old_tree: Value<'e>,
+    new_tree: Borrowed<'e, Tree>,
+    query: &Query,
+    text_function: Value<'e>,
+    padding: Option<usize>,
+) -> Result<Vector<'e>> {
+    let padding = padding.unwrap_or(0);
+    let mut ranges: Vec<(usize, usize)> = vec![];
+    {
+        let old_tree = old_tree.into_rust::<Borrowed<Tree>>()?.borrow();
+        for range in old_tree.changed_ranges(&*new_tree.borrow()) {
+            let start = range.start_byte.saturating_sub(padding);
+            let end = range.end_byte.saturating_add(padding);
+            match ranges.last_mut() {
+                Some((_, last_end)) if start <= *last_end => *last_end = end.max(*last_end),
+                _ => ranges.push((start, end)),
+            }
+        }
     }
+    let root = RNode::new(new_tree.clone(), |tree| tree.root_node());
+    let env = text_function.env;
+    let mut limited = scratch_cursor(cursor);
+    // A match intersecting multiple ranges is found once for each of them.
+    let mut seen = HashSet::new();
+    let mut vec = vec![];
+    for (start, end) in ranges {
+        limited.set_byte_range(start..end);
+        for_each_capture(&mut limited, query, &root, text_function, |m, c| {
+            if seen.insert((m.pattern_index, c.index, c.node.id())) {
+                let capture = env.cons(
+                    &query.capture_tags[c.index as usize],
+                    root.map(|_| c.node),
+                )?;
+                vec.push(capture);
+            }
+            Ok(())
+        })?;
+    }
+    vec_to_vector(env, vec)
+}
 
-    // XXX
-    let vector = env.make_vector(vec.len(), ())?;
-    for (i, v) in vec.into_iter().enumerate() {
-        vector.set(i, v)?;
+/// Return the face associated with each of QUERY's captures in FACES, an alist of (CAPTURE-NAME .
+/// FACE), where CAPTURE-NAME is a string or a symbol.
+fn capture_faces<'e>(query: &Query, faces: Value<'e>) -> Result<Vec<Option<Value<'e>>>> {
+    let env = faces.env;
+    let names = query.raw.capture_names();
+    let mut result = vec![None; names.len()];
+    let mut rest = faces;
+    while rest.is_not_nil() {
+        let pair: Value = rest.car()?;
+        rest = rest.cdr()?;
+        let key: Value = pair.car()?;
+        let name: String = if env.call(stringp, [key])?.is_not_nil() {
+            key.into_rust()?
+        } else {
+            env.call(symbol_name, [key])?.into_rust()?
+        };
+        if let Some(i) = names.iter().position(|n| *n == name) {
+            // Earlier entries take precedence, like with `assoc'.
+            if result[i].is_none() {
+                result[i] = Some(pair.cdr()?);
+            }
+        }
+    }
+    Ok(result)
+}
+
+/// Execute QUERY on NODE, and return the spans to highlight, according to FACES, as a flat vector
+/// [BEG-BYTE END-BYTE FACE ...]. See `tsc-query-highlight-spans'.
+#[defun]
+fn _query_cursor_highlight_spans<'e>(
+    cursor: &mut QueryCursor,
+    query: &Query,
+    node: &RNode,
+    text_function: Value<'e>,
+    faces: Value<'e>,
+) -> Result<Vector<'e>> {
+    let env = text_function.env;
+    let faces = capture_faces(query, faces)?;
+    let mut spans = vec![];
+    for_each_capture(cursor, query, node, text_function, |m, c| {
+        if let Some(face) = faces[c.index as usize] {
+            spans.push((m.pattern_index, c.node.lisp_start_byte(), c.node.lisp_end_byte(), face));
+        }
+        Ok(())
+    })?;
+    // Prioritize spans from earlier patterns, like `tsc--query-cursor-captures-1'.
+    spans.sort_by_key(|(i, ..)| *i);
+    let vector = env.make_vector(spans.len() * 3, ())?;
+    for (i, (_, beg, end, face)) in spans.into_iter().enumerate() {
+        vector.set(i * 3, beg)?;
+        vector.set(i * 3 + 1, end)?;
+        vector.set(i * 3 + 2, face)?;
     }
     Ok(vector)
 }
 
+/// A property of a capture, which can be written into an output vector, without allocating a cons
+/// cell per capture.
+#[derive(Clone, Copy)]
+enum CaptureProp {
+    Tag,
+    Index,
+    PatternIndex,
+    StartByte,
+    EndByte,
+    Node,
+    /// The captured node's text, as returned by the text function.
+    Text,
+    /// Not a valid property. Its value is always nil.
+    Unknown,
+}
+
+impl FromLisp<'_> for CaptureProp {
+    fn from_lisp(prop: Value) -> Result<Self> {
+        let env = prop.env;
+        macro_rules! resolve {
+            ($($name:ident => $variant:ident,)*) => {
+                $(if prop.eq($name.bind(env)) {
+                    return Ok(Self::$variant);
+                })*
+            };
+        }
+        resolve! {
+            _capture_tag   => Tag,
+            _capture_index => Index,
+            _pattern_index => PatternIndex,
+            _start_byte    => StartByte,
+            _end_byte      => EndByte,
+            _node          => Node,
+            _text          => Text,
+        }
+        Ok(Self::Unknown)
+    }
+}
+
+/// Execute QUERY on NODE, writing the properties of the captures into OUTPUT, instead of returning
+/// fresh (CAPTURE-TAG . CAPTURED-NODE) pairs. See `tsc-query-captures-into'.
+///
+/// PROPS is a vector of K capture property names. OUTPUT is treated as a flat sequence of K-slot
+/// chunks, where the I-th capture's properties are written into the slots from I*K to (I+1)*K-1.
+/// Return the number of captures found, which may be larger than OUTPUT's capacity, in which case
+/// the extra captures are not written.
+#[defun]
+fn _query_cursor_captures_into<'e>(
+    cursor: &mut QueryCursor,
+    query: &Query,
+    node: &RNode,
+    text_function: Value<'e>,
+    props: Vector<'e>,
+    output: Vector<'e>,
+) -> Result<usize> {
+    let env = text_function.env;
+    let props = props.into_iter().map(|prop| prop.into_rust()).collect::<Result<Vec<_>>>()?;
+    let stride = props.len().max(1);
+    let capacity = output.len() / stride;
+    let mut count = 0;
+    for_each_capture(cursor, query, node, text_function, |m, c| {
+        if count < capacity {
+            let offset = count * stride;
+            for (i, prop) in props.iter().enumerate() {
+                let value = match prop {
+                    CaptureProp::Tag => query.capture_tags[c.index as usize].bind(env),
+                    CaptureProp::Index => c.index.into_lisp(env)?,
+                    CaptureProp::PatternIndex => m.pattern_index.into_lisp(env)?,
+                    CaptureProp::StartByte => c.node.lisp_start_byte().into_lisp(env)?,
+                    CaptureProp::EndByte => c.node.lisp_end_byte().into_lisp(env)?,
+                    CaptureProp::Node => node.map(|_| c.node).into_lisp(env)?,
+                    CaptureProp::Text => {
+                        text_function.call((c.node.lisp_start_byte(), c.node.lisp_end_byte()))?
+                    }
+                    CaptureProp::Unknown => ().into_lisp(env)?,
+                };
+                output.set(offset + i, value)?;
+            }
+        }
+        count += 1;
+        Ok(())
+    })?;
+    Ok(count)
+}
+
+/// Sort CAPTURES, of the form (PATTERN-INDEX, START-BYTE, END-BYTE, CAPTURE), by start byte,
+/// dropping those that overlap a capture from an earlier pattern, or an earlier capture from the
+/// same pattern. Captures starting at the same byte are considered overlapping.
+fn deduplicated<T>(mut captures: Vec<(usize, usize, usize, T)>) -> Vec<T> {
+    // Stable, to keep the order in which captures from the same pattern were found.
+    captures.sort_by_key(|(i, ..)| *i);
+    let mut kept: BTreeMap<usize, (usize, T)> = BTreeMap::new();
+    for (_, start, end, capture) in captures {
+        let overlaps_before = kept.range(..=start).next_back()
+            .map_or(false, |(&s, &(e, _))| s == start || e > start);
+        let overlaps_after = kept.range(start + 1..).next()
+            .map_or(false, |(&s, _)| s < end);
+        if !overlaps_before && !overlaps_after {
+            kept.insert(start, (end, capture));
+        }
+    }
+    kept.into_iter().map(|(_, (_, capture))| capture).collect()
+}
+
 /// Limit CURSOR's query executions to the range of byte positions, from BEG to END.
+///
+/// Only matches that intersect the range are returned. This makes querying e.g.
+/// just the visible part of a large buffer much cheaper. If END is nil, the range
+/// extends to the end of the document. The limit persists across executions, until
+/// it is changed again.
 #[defun]
-fn _query_cursor_set_byte_range(cursor: &mut QueryCursor, beg: BytePos, end: BytePos) -> Result<()> {
-    cursor.set_byte_range(beg.into()..end.into());
+fn query_cursor_set_byte_range(
+    cursor: &mut QueryCursor,
+    beg: BytePos,
+    end: Option<BytePos>,
+) -> Result<()> {
+    let end = end.map_or(usize::MAX, |end| end.into());
+    cursor.set_byte_range(beg.into()..end);
     Ok(())
 }
 
 /// Limit CURSOR's query executions to the point range, from BEG to END.
 ///
 /// A "point" in this context is a (LINE-NUMBER . BYTE-COLUMN) pair. See
-/// `tsc-parse-chunks' for a more detailed explanation.
+/// `tsc-parse-chunks' for a more detailed explanation. If END is nil, the range
+/// extends to the end of the document. See `tsc-query-cursor-set-byte-range'.
 #[defun]
-fn _query_cursor_set_point_range(cursor: &mut QueryCursor, beg: Point, end: Point) -> Result<()> {
-    cursor.set_point_range(beg.into()..end.into());
+fn query_cursor_set_point_range(
+    cursor: &mut QueryCursor,
+    beg: Point,
+    end: Option<Point>,
+) -> Result<()> {
+    let end = end.map_or(
+        tree_sitter::Point { row: usize::MAX, column: usize::MAX },
+        |end| end.into(),
+    );
+    cursor.set_point_range(beg.into()..end);
     Ok(())
 }
+
+/// Limit the number of in-progress matches CURSOR can keep track of to LIMIT.
+///
+/// If LIMIT is nil, there is no limit. When the limit is reached, the oldest
+/// in-progress matches are dropped, so some results may be missing. This bounds
+/// memory usage on pathological inputs. See `tsc-query-cursor-exceeded-match-limit-p'.
+#[defun]
+fn query_cursor_set_match_limit(cursor: &mut QueryCursor, limit: Option<u32>) -> Result<()> {
+    cursor.set_match_limit(limit.unwrap_or(u32::MAX));
+    Ok(())
+}
+
+/// Return CURSOR's limit on in-progress matches, or nil if there is no limit.
+#[defun]
+fn query_cursor_match_limit(cursor: &QueryCursor) -> Result<Option<u32>> {
+    Ok(Some(cursor.match_limit()).filter(|&limit| limit != u32::MAX))
+}
+
+/// Return t if CURSOR's last query execution dropped some in-progress matches, due
+/// to its limit being reached. See `tsc-query-cursor-set-match-limit'.
+#[defun]
+fn query_cursor_exceeded_match_limit_p(cursor: &QueryCursor) -> Result<bool> {
+    Ok(cursor.did_exceed_match_limit())
+}
+
+// -------------------------------------------------------------------------------------------------
+// Streaming matches.
+
+thread_local! {
+    /// The text function of the ongoing `_query_next_match` call. Iterators outlive the Lisp
+    /// environments they were created in, so their text providers cannot capture it.
+    static TEXT_FUNCTION: Cell<Option<Value<'static>>> = Cell::new(None);
+}
+
+/// An iterator over a query's matches under a node, which are found lazily.
+struct QueryMatchIterator {
+    /// Borrows `cursor`, `raw`, and `node`'s tree. Fields are dropped in declaration order, so this
+    /// must be the first one.
+    matches: Box<dyn Iterator<Item = QueryMatch<'static, 'static>>>,
+    /// The last error signaled by the text function.
+    error: Rc<RefCell<Option<Error>>>,
+    _cursor: Box<QueryCursor>,
+    raw: Rc<tree_sitter::Query>,
+    node: RNode,
+    /// The start byte of the latest match, or of the node, initially.
+    position: usize,
+    done: bool,
+}
+
+impl QueryMatchIterator {
+    /// Return the next match satisfying QUERY's predicates, and its start byte.
+    fn next_match<'e>(
+        &mut self,
+        query: &Query,
+        text_function: Value<'e>,
+    ) -> Result<Option<(usize, Value<'e>)>> {
+        if self.done {
+            return Ok(None);
+        }
+        // Fail early if the tree is being mutated.
+        let _tree = self.node.borrow();
+        loop {
+            let m = match self.matches.next() {
+                Some(m) => m,
+                None => {
+                    self.done = true;
+                    return self.error.borrow_mut().take().map_or(Ok(None), Err);
+                }
+            };
+            if let Some(error) = self.error.borrow_mut().take() {
+                return Err(error);
+            }
+            if query.satisfies_predicates(&m, &self.node, text_function)? {
+                if let Some(start) = m.captures.iter().map(|c| c.node.start_byte()).min() {
+                    self.position = start;
+                }
+                let m = match_to_lisp(text_function.env, query, &self.node, &m)?;
+                return Ok(Some((self.position, m)));
+            }
+        }
+    }
+
+    /// Call F on the iterator, with TEXT-FUNCTION providing the nodes' texts.
+    fn with_text_function<'e, T, F>(
+        &mut self,
+        query: &Query,
+        text_function: Value<'e>,
+        f: F,
+    ) -> Result<T>
+    where
+        F: FnOnce(&mut Self) -> Result<T>,
+    {
+        if !Rc::ptr_eq(&self.raw, &query.raw) {
+            return text_function.env.signal(
+                error::tsc_error,
+                ("Query does not match the iterator's", ),
+            );
+        }
+        // SAFETY: The text function is only used during this call, and is cleared before it
+        // returns.
+        let static_function = unsafe { mem::transmute::<Value<'e>, Value<'static>>(text_function) };
+        let previous = TEXT_FUNCTION.with(|f| f.replace(Some(static_function)));
+        let result = f(self);
+        TEXT_FUNCTION.with(|f| f.set(previous));
+        result
+    }
+}
+
+/// Create an iterator over QUERY's matches under NODE. See `tsc-query-matches-iter'.
+#[defun(user_ptr)]
+fn _query_matches_iter(query: &Query, node: &RNode) -> Result<QueryMatchIterator> {
+    let mut cursor = Box::new(QueryCursor::new());
+    let raw = query.raw.clone();
+    let node = node.clone();
+    let position = node.borrow().start_byte();
+    let error = Rc::new(RefCell::new(None));
+    let text_error = error.clone();
+    let text = move |child: Node| {
+        let text = match TEXT_FUNCTION.with(|f| f.get()) {
+            Some(text_function) => node_text(text_function, child).unwrap_or_else(|e| {
+                text_error.borrow_mut().replace(e);
+                "".to_owned()
+            }),
+            None => "".to_owned(),
+        };
+        iter::once(text.into_bytes())
+    };
+    // SAFETY: The cursor, the compiled query, and the syntax tree are kept alive, at stable
+    // addresses, by the iterator itself, which drops `matches` before them.
+    let matches = unsafe {
+        let cursor: &'static mut QueryCursor = &mut *(cursor.as_mut() as *mut QueryCursor);
+        let raw = types::erase_lifetime(raw.as_ref());
+        let inner: Node<'static> = mem::transmute(*node.borrow());
+        Box::new(cursor.matches(raw, inner, text))
+    };
+    Ok(QueryMatchIterator {
+        matches,
+        error,
+        _cursor: cursor,
+        raw,
+        node,
+        position,
+        done: false,
+    })
+}
+
+/// Return the next match of ITERATOR, or nil if there are no more matches.
+///
+/// QUERY must be the query ITERATOR was created for. A match is a cons cell
+/// (PATTERN-INDEX . CAPTURES), like those returned by `tsc-query-matches'.
+#[defun]
+fn _query_next_match<'e>(
+    iterator: &mut QueryMatchIterator,
+    query: &Query,
+    text_function: Value<'e>,
+) -> Result<Option<Value<'e>>> {
+    iterator.with_text_function(query, text_function, |iterator| {
+        Ok(iterator.next_match(query, text_function)?.map(|(_, m)| m))
+    })
+}
+
+/// Return the next matches of the resumable query CURSOR, as a vector.
+///
+/// QUERY must be the query CURSOR was created for. TEXT-FUNCTION is the same as for
+/// `tsc-query-matches'. If MAX-COUNT is non-nil, return at most that many matches.
+/// If MAX-BYTES is non-nil, stop after the first match that starts at least that
+/// many bytes past the position reached by the previous call. See
+/// `tsc-query-matches-start'.
+#[defun]
+fn query_matches_continue<'e>(
+    cursor: &mut QueryMatchIterator,
+    query: &Query,
+    text_function: Value<'e>,
+    max_count: Option<usize>,
+    max_bytes: Option<usize>,
+) -> Result<Vector<'e>> {
+    cursor.with_text_function(query, text_function, |cursor| {
+        let limit = max_bytes.map(|bytes| cursor.position.saturating_add(bytes));
+        let mut matches = vec![];
+        while max_count.map_or(true, |max| matches.len() < max) {
+            match cursor.next_match(query, text_function)? {
+                Some((start, m)) => {
+                    matches.push(m);
+                    if limit.map_or(false, |limit| start >= limit) {
+                        break;
+                    }
+                }
+                None => break,
+            }
+        }
+        vec_to_vector(text_function.env, matches)
+    })
+}
+
+/// Return t if the resumable query CURSOR has no more matches.
+#[defun]
+fn query_matches_done_p(cursor: &QueryMatchIterator) -> Result<bool> {
+    Ok(cursor.done)
+}
+
+/// Return the byte position reached by the resumable query CURSOR: the start of its
+/// latest match, or the start of its node, if it has found no matches yet. This is
+/// useful for reporting progress.
+#[defun]
+fn query_matches_position(cursor: &QueryMatchIterator) -> Result<BytePos> {
+    Ok(cursor.position.into())
+}
@@ -0,0 +1,262 @@
+use std::cell::RefCell;
+
+use emacs::{defun, Env, Result, ResultExt, Value, Vector};
+use tree_sitter::{InputEdit, Parser, QueryCursor, Tree};
+
+use crate::{
+    types::{BytePos, Point, Range, Shared},
+    lang::Language,
+    parser::{included_ranges, parse_buffer, set_ranges},
+    node::RNode,
+    query::{collect_captures, Query},
+    error,
+};
+
+emacs::use_symbols! {
+    list symbol_name
+}
+
+struct Entry {
+    key: String,
+    language: Language,
+    parser: Parser,
+    tree: Option<Shared<Tree>>,
+    /// Empty if the whole buffer is included.
+    ranges: Vec<tree_sitter::Range>,
+}
+
+/// The parsers and syntax trees of a buffer's languages: usually a host language, covering the
+/// whole buffer, plus injected languages, covering only some ranges of it.
+pub struct Registry {
+    /// In registration order.
+    entries: Vec<Entry>,
+}
+
+impl_pred!(parser_registry_p, &RefCell<Registry>);
+
+fn key_name(key: Value) -> Result<String> {
+    key.env.call(symbol_name, [key])?.into_rust()
+}
+
+impl Registry {
+    fn position(&self, name: &str) -> Option<usize> {
+        self.entries.iter().position(|entry| entry.key == name)
+    }
+
+    fn entry(&mut self, key: Value) -> Result<&mut Entry> {
+        match self.position(&key_name(key)?) {
+            Some(i) => Ok(&mut self.entries[i]),
+            None => key.env.signal(error::tsc_registry_unknown_key, (key, )),
+        }
+    }
+}
+
+/// Create a new, empty parser registry.
+///
+/// A registry manages the parsers and syntax trees of a multi-language buffer: the
+/// host language, and the languages injected into some of its ranges. Each one is
+/// registered under a KEY symbol, with `tsc-registry-register'.
+#[defun(user_ptr)]
+fn make_parser_registry() -> Result<Registry> {
+    Ok(Registry { entries: vec![] })
+}
+
+/// Register a parser for LANGUAGE in REGISTRY under KEY. See `tsc-registry-register'.
+#[defun]
+fn _registry_register(
+    registry: &mut Registry,
+    key: Value,
+    language: Language,
+    ranges: Option<Vector>,
+) -> Result<()> {
+    let env = key.env;
+    let ranges = match ranges {
+        Some(ranges) => included_ranges(ranges)?,
+        None => vec![],
+    };
+    let mut parser = Parser::new();
+    parser.set_language(language.into()).or_signal(env, error::tsc_lang_abi_error)?;
+    set_ranges(env, &mut parser, &ranges)?;
+    let entry = Entry { key: key_name(key)?, language, parser, tree: None, ranges };
+    match registry.position(&entry.key) {
+        Some(i) => registry.entries[i] = entry,
+        None => registry.entries.push(entry),
+    }
+    Ok(())
+}
+
+/// Remove the entry registered under KEY from REGISTRY.
+/// Return t if there was such an entry, nil otherwise.
+#[defun]
+fn registry_unregister(registry: &mut Registry, key: Value) -> Result<bool> {
+    match registry.position(&key_name(key)?) {
+        Some(i) => {
+            registry.entries.remove(i);
+            Ok(true)
+        }
+        None => Ok(false),
+    }
+}
+
+/// Return the list of keys registered in REGISTRY, in registration order.
+#[defun]
+fn registry_keys<'e>(env: &'e Env, registry: &Registry) -> Result<Value<'e>> {
+    let keys = registry.entries.iter()
+        .map(|entry| env.intern(&entry.key))
+        .collect::<Result<Vec<_>>>()?;
+    env.call(list, &keys[..])
+}
+
+/// Return the language registered under KEY in REGISTRY.
+#[defun]
+fn registry_language(registry: &mut Registry, key: Value) -> Result<Language> {
+    Ok(registry.entry(key)?.language)
+}
+
+/// Return the latest syntax tree parsed for KEY in REGISTRY, or nil if it hasn't
+/// been parsed yet.
+#[defun]
+fn registry_tree(registry: &mut Registry, key: Value) -> Result<Option<Shared<Tree>>> {
+    Ok(registry.entry(key)?.tree.clone())
+}
+
+/// Return the ranges included when parsing KEY in REGISTRY, as a vector.
+/// An empty vector means the whole buffer is included.
+#[defun]
+fn registry_ranges<'e>(env: &'e Env, registry: &mut Registry, key: Value) -> Result<Vector<'e>> {
+    let ranges = &registry.entry(key)?.ranges;
+    let vector = env.make_vector(ranges.len(), ())?;
+    for (i, range) in ranges.iter().enumerate() {
+        vector.set(i, Range::from(*range))?;
+    }
+    Ok(vector)
+}
+
+/// Set the RANGES included when parsing KEY in REGISTRY.
+///
+/// RANGES should be a vector, like for `tsc-set-included-ranges'. An empty vector
+/// means the whole buffer is included. The new ranges take effect on the next
+/// parse.
+#[defun]
+fn registry_set_ranges(registry: &mut Registry, key: Value, ranges: Vector) -> Result<()> {
+    let env = key.env;
+    let included = included_ranges(ranges)?;
+    let entry = registry.entry(key)?;
+    set_ranges(env, &mut entry.parser, &included)?;
+    entry.ranges = included;
+    Ok(())
+}
+
+/// Apply an edit to all syntax trees in REGISTRY. See `tsc-edit-tree'.
+///
+/// The included ranges are not adjusted. They should be updated with
+/// `tsc-registry-set-ranges' before the injected languages are parsed again,
+/// usually after the host language has been re-parsed.
+#[defun]
+fn registry_edit(
+    registry: &Registry,
+    start_bytepos: BytePos,
+    old_end_bytepos: BytePos,
+    new_end_bytepos: BytePos,
+    start_point: Point,
+    old_end_point: Point,
+    new_end_point: Point,
+) -> Result<()> {
+    let edit = InputEdit {
+        start_byte: start_bytepos.into(),
+        old_end_byte: old_end_bytepos.into(),
+        new_end_byte: new_end_bytepos.into(),
+        start_position: start_point.into(),
+        old_end_position: old_end_point.into(),
+        new_end_position: new_end_point.into(),
+    };
+    for tree in registry.entries.iter().filter_map(|entry| entry.tree.as_ref()) {
+        tree.borrow_mut().edit(&edit);
+    }
+    Ok(())
+}
+
+/// Parse the current buffer's text for KEY in REGISTRY. See `tsc-registry-parse-buffer'.
+///
+/// This must be called with narrowing disabled.
+#[defun]
+fn _registry_parse_buffer(
+    env: &Env,
+    registry: &mut Registry,
+    key: Value,
+    keep_old_tree: Option<Value>,
+) -> Result<Shared<Tree>> {
+    let entry = registry.entry(key)?;
+    let tree = parse_buffer(env, &mut entry.parser, entry.tree.as_ref(), keep_old_tree.is_some())?;
+    entry.tree = Some(tree.clone());
+    Ok(tree)
+}
+
+/// Return the key of the innermost language at BYTEPOS in REGISTRY.
+///
+/// This is the entry with the smallest included range containing BYTEPOS, or, if
+/// there's none, the first registered entry covering the whole buffer. Return nil
+/// if no entry covers BYTEPOS.
+#[defun]
+fn registry_key_at<'e>(
+    env: &'e Env,
+    registry: &Registry,
+    bytepos: BytePos,
+) -> Result<Option<Value<'e>>> {
+    let byte: usize = bytepos.into();
+    let innermost = registry.entries.iter()
+        .filter_map(|entry| {
+            entry.ranges.iter()
+                .filter(|range| range.start_byte <= byte && byte < range.end_byte)
+                .map(|range| (range.end_byte - range.start_byte, entry))
+                .min_by_key(|&(size, _)| size)
+        })
+        .min_by_key(|&(size, _)| size)
+        .map(|(_, entry)| entry)
+        .or_else(|| registry.entries.iter().find(|entry| entry.ranges.is_empty()));
+    innermost.map(|entry| env.intern(&entry.key)).transpose()
+}
+
+/// Execute QUERIES on the syntax trees in REGISTRY, and return the captures, sorted by position.
+/// See `tsc-registry-query-captures'.
+///
+/// This must be called with narrowing disabled.
+#[defun]
+fn _registry_query_captures<'e>(
+    registry: &mut Registry,
+    queries: Value<'e>,
+    text_function: Value<'e>,
+    beg: Option<BytePos>,
+    end: Option<BytePos>,
+) -> Result<Vector<'e>> {
+    let env = text_function.env;
+    let mut cursor = QueryCursor::new();
+    let beg = beg.map_or(0, |beg| beg.into());
+    let end = end.map_or(usize::MAX, |end| end.into());
+    cursor.set_byte_range(beg..end);
+    let mut captures = vec![];
+    let mut rest = queries;
+    while rest.is_not_nil() {
+        let pair: Value = rest.car()?;
+        rest = rest.cdr()?;
+        let key: Value = pair.car()?;
+        let query: &RefCell<Query> = pair.cdr()?;
+        let tree = match &registry.entry(key)?.tree {
+            Some(tree) => tree.clone(),
+            None => continue,
+        };
+        let root = RNode::new(tree, |tree| tree.root_node());
+        for (_, start, _, capture) in
+            collect_captures(&mut cursor, &query.borrow(), &root, text_function)?
+        {
+            captures.push((start, env.cons(key, capture)?));
+        }
+    }
+    // Stable, so that captures at the same position are in the order of QUERIES.
+    captures.sort_by_key(|(start, _)| *start);
+    let vector = env.make_vector(captures.len(), ())?;
+    for (i, (_, capture)) in captures.into_iter().enumerate() {
+        vector.set(i, capture)?;
+    }
+    Ok(vector)
+}
@@ -1,11 +1,19 @@
-use emacs::{defun, Value, Result, Vector};
+use std::{
+    cell::RefCell,
+    collections::{hash_map::DefaultHasher, HashMap, HashSet},
+    hash::{Hash, Hasher},
+    rc::{Rc, Weak},
+};
+
+use emacs::{defun, Env, IntoLisp, Value, Result, Vector};
 
-use tree_sitter::{InputEdit, Tree};
+use tree_sitter::{InputEdit, Node, Tree};
 
 use crate::{
     types::{Shared, BytePos, Point, Range},
     lang::Language,
     node::RNode,
+    cursor::TreeOrNode,
 };
 
 // XXX: If we pass a &, #[defun] will assume it's refcell-wrapped. If we pass a Value, we need
@@ -14,6 +22,71 @@ pub(crate) type Borrowed<'e, T> = &'e Shared<T>;
 
 impl_pred!(tree_p, &Shared<Tree>);
 
+emacs::use_symbols! {
+    list
+    _inserted => ":inserted"
+    _deleted  => ":deleted"
+    _changed  => ":changed"
+
+    _node_count       => ":node-count"
+    _named_node_count => ":named-node-count"
+    _max_depth        => ":max-depth"
+    _error_count      => ":error-count"
+    _missing_count    => ":missing-count"
+
+    _reused_count        => ":reused-count"
+    _new_count           => ":new-count"
+    _changed_range_count => ":changed-range-count"
+    _changed_byte_count  => ":changed-byte-count"
+}
+
+#[derive(Default)]
+struct Generations {
+    last: usize,
+    /// Keyed by the trees' addresses. The weak references prevent the addresses from being reused
+    /// while the entries are alive.
+    trees: HashMap<*const RefCell<Tree>, (Weak<RefCell<Tree>>, usize)>,
+    /// The table size at which entries of dropped trees are removed.
+    prune_at: usize,
+}
+
+thread_local! {
+    static GENERATIONS: RefCell<Generations> = RefCell::new(Generations::default());
+}
+
+/// Wrap TREE for sharing with Lisp, stamping it with a new generation number.
+pub(crate) fn stamped(tree: Tree) -> Shared<Tree> {
+    let tree = Rc::new(RefCell::new(tree));
+    GENERATIONS.with(|generations| {
+        let mut generations = generations.borrow_mut();
+        if generations.trees.len() >= generations.prune_at {
+            generations.trees.retain(|_, (weak, _)| weak.strong_count() > 0);
+            generations.prune_at = (generations.trees.len() * 2).max(64);
+        }
+        generations.last += 1;
+        let generation = generations.last;
+        generations.trees.insert(Rc::as_ptr(&tree), (Rc::downgrade(&tree), generation));
+    });
+    tree
+}
+
+/// Return the generation number of TREE-OR-NODE's syntax tree.
+///
+/// Each syntax tree is stamped with a number greater than those of all the trees
+/// created before it, by parsing or otherwise. This allows caches keyed on trees
+/// or nodes to detect that their entries belong to an outdated parse. Editing a
+/// tree with `tsc-edit-tree' doesn't change its generation.
+///
+/// Return nil for the shifted trees of `tsc-root-node-with-offset', which are not
+/// the results of parsing.
+#[defun]
+fn tree_generation(tree_or_node: TreeOrNode) -> Result<Option<usize>> {
+    let tree = tree_or_node.tree();
+    Ok(GENERATIONS.with(|generations| {
+        generations.borrow().trees.get(&Rc::as_ptr(&tree)).map(|&(_, generation)| generation)
+    }))
+}
+
 /// Return the language that was used to parse the syntax TREE.
 #[defun(mod_in_name = true)]
 fn language(tree: Borrowed<Tree>) -> Result<Language> {
@@ -32,6 +105,40 @@ fn root_node(tree: Borrowed<Tree>) -> Result<RNode> {
     Ok(RNode::new(tree.clone(), |tree| tree.root_node()))
 }
 
+/// Return the root node of the syntax TREE, shifted to start at START-BYTEPOS and START-POINT.
+///
+/// This is useful when TREE was parsed from a piece of text extracted from a host
+/// buffer (e.g. a code block inside an Org document). The returned node, and all
+/// the nodes obtained from it, report positions in the host buffer's coordinates.
+///
+/// TREE itself is not modified. The returned node belongs to a shifted copy of TREE,
+/// which should not be used for incremental parsing. Since the copy is shifted by
+/// editing it, the returned node and the nodes at the start of the copy report
+/// that they have changes (see `tsc-node-has-changes-p'). The copy has no
+/// generation (see `tsc-tree-generation').
+#[defun]
+fn root_node_with_offset(
+    tree: Borrowed<Tree>,
+    start_bytepos: BytePos,
+    start_point: Point,
+) -> Result<RNode> {
+    // The tree-sitter version we use doesn't have `Tree::root_node_with_offset`. Inserting
+    // text at the very beginning only grows the leading padding, shifting all nodes the
+    // same way the upstream function does.
+    let start = tree_sitter::Point::new(0, 0);
+    let edit = InputEdit {
+        start_byte: 0,
+        old_end_byte: 0,
+        new_end_byte: start_bytepos.into(),
+        start_position: start,
+        old_end_position: start,
+        new_end_position: start_point.into(),
+    };
+    let mut shifted = tree.borrow().clone();
+    shifted.edit(&edit);
+    Ok(RNode::new(Rc::new(RefCell::new(shifted)), |tree| tree.root_node()))
+}
+
 /// Edit the syntax TREE to keep it in sync with source code that has been edited.
 ///
 /// You must describe the edit both in terms of byte positions and in terms of
@@ -86,10 +193,320 @@ fn changed_ranges<'e>(old_tree: Value<'e>, new_tree: Borrowed<'e, Tree>) -> Resu
     Ok(vec)
 }
 
-/// Create a shallow copy of the syntax TREE.
+/// Return a plist of aggregate metrics about the syntax TREE.
+///
+/// The plist has these properties:
+///  - `:node-count': the total number of nodes, including anonymous nodes.
+///  - `:named-node-count': the number of named nodes.
+///  - `:max-depth': the depth of the deepest node, the root node having depth 0.
+///  - `:error-count': the number of ERROR nodes.
+///  - `:missing-count': the number of missing nodes, inserted by the parser to recover
+///    from syntax errors.
+#[defun]
+fn tree_stats<'e>(env: &'e Env, tree: Borrowed<'e, Tree>) -> Result<Value<'e>> {
+    let tree = tree.borrow();
+    let mut cursor = tree.walk();
+    let (mut node_count, mut named_node_count) = (0usize, 0);
+    let (mut error_count, mut missing_count) = (0, 0);
+    let (mut depth, mut max_depth) = (0, 0);
+    'traversal: loop {
+        let node = cursor.node();
+        node_count += 1;
+        if node.is_named() {
+            named_node_count += 1;
+        }
+        if node.is_error() {
+            error_count += 1;
+        }
+        if node.is_missing() {
+            missing_count += 1;
+        }
+        max_depth = max_depth.max(depth);
+        if cursor.goto_first_child() {
+            depth += 1;
+            continue;
+        }
+        while !cursor.goto_next_sibling() {
+            if !cursor.goto_parent() {
+                break 'traversal;
+            }
+            depth -= 1;
+        }
+    }
+    let stats = [
+        (_node_count, node_count),
+        (_named_node_count, named_node_count),
+        (_max_depth, max_depth),
+        (_error_count, error_count),
+        (_missing_count, missing_count),
+    ];
+    let mut plist = ().into_lisp(env)?;
+    for &(key, value) in stats.iter().rev() {
+        plist = env.cons(key, env.cons(value, plist)?)?;
+    }
+    Ok(plist)
+}
+
+/// Call F on each node of TREE, in depth-first pre-order.
+fn for_each_node<'t>(tree: &'t Tree, mut f: impl FnMut(Node<'t>)) {
+    let mut cursor = tree.walk();
+    loop {
+        f(cursor.node());
+        if cursor.goto_first_child() {
+            continue;
+        }
+        while !cursor.goto_next_sibling() {
+            if !cursor.goto_parent() {
+                return;
+            }
+        }
+    }
+}
+
+/// Return a plist of statistics about how NEW-TREE reused the edited OLD-TREE.
+///
+/// OLD-TREE and NEW-TREE should be as for `tsc-changed-ranges'. The plist has
+/// these properties:
+///  - `:reused-count': the number of NEW-TREE's nodes that were reused from OLD-TREE.
+///  - `:new-count': the number of NEW-TREE's nodes that were created by the parse.
+///  - `:changed-range-count': the number of ranges returned by `tsc-changed-ranges'.
+///  - `:changed-byte-count': the total size of these ranges, in bytes.
+///
+/// This is useful to check that edits are correctly recorded with `tsc-edit-tree':
+/// a small edit should cause only a few nodes to be created. Reuse is detected at
+/// the level of nodes' children, so a reused leaf whose parent is new may be counted
+/// as new.
+#[defun]
+fn reuse_stats<'e>(old_tree: Value<'e>, new_tree: Borrowed<'e, Tree>) -> Result<Value<'e>> {
+    let env = old_tree.env;
+    let old_tree = old_tree.into_rust::<Borrowed<Tree>>()?.borrow();
+    let new_tree = new_tree.borrow();
+    let mut old_ids = HashSet::new();
+    for_each_node(&old_tree, |node| {
+        old_ids.insert(node.id());
+    });
+    let (mut reused_count, mut new_count) = (0usize, 0usize);
+    for_each_node(&new_tree, |node| {
+        let reused = old_ids.contains(&node.id())
+            || node.child(0).map_or(false, |child| old_ids.contains(&child.id()));
+        if reused {
+            reused_count += 1;
+        } else {
+            new_count += 1;
+        }
+    });
+    let (mut changed_range_count, mut changed_byte_count) = (0usize, 0usize);
+    for range in old_tree.changed_ranges(&*new_tree) {
+        changed_range_count += 1;
+        changed_byte_count += range.end_byte - range.start_byte;
+    }
+    let stats = [
+        (_reused_count, reused_count),
+        (_new_count, new_count),
+        (_changed_range_count, changed_range_count),
+        (_changed_byte_count, changed_byte_count),
+    ];
+    let mut plist = ().into_lisp(env)?;
+    for &(key, value) in stats.iter().rev() {
+        plist = env.cons(key, env.cons(value, plist)?)?;
+    }
+    Ok(plist)
+}
+
+/// Create a copy of the syntax TREE.
 ///
-/// This is not very useful currently, as Emacs Lisp threads are subjected to a GIL.
+/// The copy is independent of TREE: editing either of them does not affect the
+/// other. This allows keeping a snapshot of a tree around, while the original is
+/// edited and used for incremental parsing. Copying is cheap, as the trees share
+/// their underlying immutable nodes.
+#[defun]
+fn clone_tree(tree: Borrowed<Tree>) -> Result<Shared<Tree>> {
+    Ok(stamped(tree.borrow().clone()))
+}
+
+enum Change<'t> {
+    Inserted(Node<'t>),
+    Deleted(Node<'t>),
+    Changed(Node<'t>, Node<'t>),
+}
+
+/// Beyond this number of (old child, new child) pairs, children are not aligned by their longest
+/// common subsequence, to avoid quadratic behavior on nodes with a huge number of children.
+const MAX_ALIGNMENT_SIZE: usize = 1 << 20;
+
+/// A subtree's structural hash, memoized by node id.
+struct Hashes<'s> {
+    source: Option<&'s [u8]>,
+    memo: HashMap<usize, u64>,
+}
+
+impl<'s> Hashes<'s> {
+    fn new(source: &'s Option<String>) -> Self {
+        Self { source: source.as_ref().map(|s| s.as_bytes()), memo: HashMap::new() }
+    }
+
+    fn get(&mut self, node: Node) -> u64 {
+        // Hash the subtree in post-order, with an explicit stack instead of recursion, so that
+        // deeply nested trees don't overflow the call stack. A node is pushed a second time, as
+        // expanded, after its children, so that it is hashed after them.
+        let mut stack = vec![(node, false)];
+        while let Some((node, expanded)) = stack.pop() {
+            if self.memo.contains_key(&node.id()) {
+                continue;
+            }
+            let mut cursor = node.walk();
+            if !expanded {
+                stack.push((node, true));
+                stack.extend(node.children(&mut cursor).map(|child| (child, false)));
+                continue;
+            }
+            let mut hasher = DefaultHasher::new();
+            node.kind_id().hash(&mut hasher);
+            node.is_missing().hash(&mut hasher);
+            node.child_count().hash(&mut hasher);
+            if node.child_count() == 0 {
+                let range = node.start_byte()..node.end_byte();
+                if let Some(text) = self.source.and_then(|source| source.get(range)) {
+                    text.hash(&mut hasher);
+                }
+            }
+            for child in node.children(&mut cursor) {
+                self.memo[&child.id()].hash(&mut hasher);
+            }
+            self.memo.insert(node.id(), hasher.finish());
+        }
+        self.memo[&node.id()]
+    }
+}
+
+/// A pending step of `Differ::diff`.
+enum Task<'t> {
+    Diff(Node<'t>, Node<'t>),
+    Record(Change<'t>),
+}
+
+struct Differ<'s, 't> {
+    old: Hashes<'s>,
+    new: Hashes<'s>,
+    changes: Vec<Change<'t>>,
+}
+
+impl<'s, 't> Differ<'s, 't> {
+    /// Record the changes between OLD and NEW, in pre-order. An explicit stack of tasks is used
+    /// instead of recursion, so that deeply nested trees don't overflow the call stack.
+    fn diff(&mut self, old: Node<'t>, new: Node<'t>) {
+        let mut stack = vec![Task::Diff(old, new)];
+        while let Some(task) = stack.pop() {
+            match task {
+                Task::Record(change) => self.changes.push(change),
+                Task::Diff(old, new) => stack.extend(self.diff_step(old, new).into_iter().rev()),
+            }
+        }
+    }
+
+    /// Return the tasks that diff OLD and NEW, in order.
+    fn diff_step(&mut self, old: Node<'t>, new: Node<'t>) -> Vec<Task<'t>> {
+        if self.old.get(old) == self.new.get(new) {
+            return vec![];
+        }
+        if old.kind_id() != new.kind_id() || old.child_count() == 0 || new.child_count() == 0 {
+            return vec![Task::Record(Change::Changed(old, new))];
+        }
+        let old_children: Vec<Node> = old.children(&mut old.walk()).collect();
+        let new_children: Vec<Node> = new.children(&mut new.walk()).collect();
+        let old_hashes: Vec<u64> = old_children.iter().map(|&n| self.old.get(n)).collect();
+        let new_hashes: Vec<u64> = new_children.iter().map(|&n| self.new.get(n)).collect();
+        let mut tasks = vec![];
+        let (mut i, mut j) = (0, 0);
+        for (next_i, next_j) in align(&old_hashes, &new_hashes) {
+            diff_unaligned(&old_children[i..next_i], &new_children[j..next_j], &mut tasks);
+            i = next_i + 1;
+            j = next_j + 1;
+        }
+        diff_unaligned(&old_children[i..], &new_children[j..], &mut tasks);
+        tasks
+    }
+}
+
+/// Add the tasks that diff sequences of children that have no identical counterparts to TASKS,
+/// pairing the children up by position, as long as their types match.
+fn diff_unaligned<'t>(old: &[Node<'t>], new: &[Node<'t>], tasks: &mut Vec<Task<'t>>) {
+    let paired = old.iter().zip(new).take_while(|(o, n)| o.kind_id() == n.kind_id()).count();
+    tasks.extend(old[..paired].iter().zip(&new[..paired]).map(|(&o, &n)| Task::Diff(o, n)));
+    let (old, new) = (&old[paired..], &new[paired..]);
+    if old.len() == 1 && new.len() == 1 {
+        tasks.push(Task::Record(Change::Changed(old[0], new[0])));
+        return;
+    }
+    tasks.extend(old.iter().map(|&o| Task::Record(Change::Deleted(o))));
+    tasks.extend(new.iter().map(|&n| Task::Record(Change::Inserted(n))));
+}
+
+/// Return the index pairs of the longest common subsequence of OLD and NEW.
+fn align(old: &[u64], new: &[u64]) -> Vec<(usize, usize)> {
+    let prefix = old.iter().zip(new).take_while(|(o, n)| o == n).count();
+    let suffix = old[prefix..].iter().rev().zip(new[prefix..].iter().rev())
+        .take_while(|(o, n)| o == n)
+        .count();
+    let mut pairs: Vec<(usize, usize)> = (0..prefix).map(|i| (i, i)).collect();
+    let (old_mid, new_mid) = (&old[prefix..old.len() - suffix], &new[prefix..new.len() - suffix]);
+    let (m, n) = (old_mid.len(), new_mid.len());
+    if m > 0 && n > 0 && m * n <= MAX_ALIGNMENT_SIZE {
+        // lengths[i][j] is the length of the LCS of old_mid[i..] and new_mid[j..].
+        let mut lengths = vec![vec![0u32; n + 1]; m + 1];
+        for i in (0..m).rev() {
+            for j in (0..n).rev() {
+                lengths[i][j] = if old_mid[i] == new_mid[j] {
+                    lengths[i + 1][j + 1] + 1
+                } else {
+                    lengths[i + 1][j].max(lengths[i][j + 1])
+                };
+            }
+        }
+        let (mut i, mut j) = (0, 0);
+        while i < m && j < n {
+            if old_mid[i] == new_mid[j] {
+                pairs.push((prefix + i, prefix + j));
+                i += 1;
+                j += 1;
+            } else if lengths[i + 1][j] >= lengths[i][j + 1] {
+                i += 1;
+            } else {
+                j += 1;
+            }
+        }
+    }
+    pairs.extend((0..suffix).map(|k| (old.len() - suffix + k, new.len() - suffix + k)));
+    pairs
+}
+
+/// Compare the syntax trees OLD-TREE and NEW-TREE structurally. See `tsc-tree-diff'.
 #[defun]
-fn _clone_tree(tree: Borrowed<Tree>) -> Result<Shared<Tree>> {
-    Ok(tree.clone())
+fn _tree_diff<'e>(
+    env: &'e Env,
+    old_tree: Borrowed<'e, Tree>,
+    new_tree: Value<'e>,
+    old_source: Option<String>,
+    new_source: Option<String>,
+) -> Result<Value<'e>> {
+    let new_tree = new_tree.into_rust::<Borrowed<Tree>>()?;
+    let (old, new) = (old_tree.borrow(), new_tree.borrow());
+    let mut differ = Differ {
+        old: Hashes::new(&old_source),
+        new: Hashes::new(&new_source),
+        changes: vec![],
+    };
+    differ.diff(old.root_node(), new.root_node());
+    let old_node = |node: Node| RNode::new(old_tree.clone(), |_| node);
+    let new_node = |node: Node| RNode::new(new_tree.clone(), |_| node);
+    let mut result = ().into_lisp(env)?;
+    for change in differ.changes.into_iter().rev() {
+        let entry = match change {
+            Change::Inserted(n) => env.call(list, (_inserted, new_node(n)))?,
+            Change::Deleted(o) => env.call(list, (_deleted, old_node(o)))?,
+            Change::Changed(o, n) => env.call(list, (_changed, old_node(o), new_node(n)))?,
+        };
+        result = env.cons(entry, result)?;
+    }
+    Ok(result)
 }
@@ -104,7 +104,7 @@
 (define-obsolete-function-alias 'ts--lang-symbol 'tsc--lang-symbol "2020-10-13")
 (define-obsolete-function-alias 'ts--buffer-input 'tsc--buffer-input "2020-10-13")
 (define-obsolete-function-alias 'ts--buffer-substring-no-properties 'tsc--buffer-substring-no-properties "2020-10-13")
-(define-obsolete-function-alias 'ts--clone-tree 'tsc--clone-tree "2020-10-13")
+(define-obsolete-function-alias 'ts--clone-tree 'tsc-clone-tree "2020-10-13")
 (define-obsolete-function-alias 'ts--disable-capture 'tsc--disable-capture "2020-10-13")
 (define-obsolete-function-alias 'ts--dyn-version 'tsc--dyn-version "2020-10-13")
 (define-obsolete-function-alias 'ts--get-child-by-field-name 'tsc--get-child-by-field-name "2020-10-13")
@@ -119,20 +119,28 @@
 (define-obsolete-function-alias 'ts--node-steps 'tsc--node-steps "2020-10-13")
 (define-obsolete-function-alias 'ts--node-text 'tsc--node-text "2020-10-13")
 (define-obsolete-function-alias 'ts--point-from-position 'tsc--point-from-position "2020-10-13")
-(define-obsolete-function-alias 'ts--query-capture-names 'tsc--query-capture-names "2020-10-13")
+(define-obsolete-function-alias 'ts--query-capture-names 'tsc-query-capture-names "2020-10-13")
 (define-obsolete-function-alias 'ts--query-cursor-captures 'tsc--query-cursor-captures "2020-10-13")
 (define-obsolete-function-alias 'ts--query-cursor-captures-1 'tsc--query-cursor-captures-1 "2020-10-13")
 (define-obsolete-function-alias 'ts--query-cursor-matches 'tsc--query-cursor-matches "2020-10-13")
-(define-obsolete-function-alias 'ts--query-cursor-set-byte-range 'tsc--query-cursor-set-byte-range "2020-10-13")
-(define-obsolete-function-alias 'ts--query-cursor-set-point-range 'tsc--query-cursor-set-point-range "2020-10-13")
-(define-obsolete-function-alias 'ts--query-start-byte-for-pattern 'tsc--query-start-byte-for-pattern "2020-10-13")
-(define-obsolete-function-alias 'ts--reset-parser 'tsc--reset-parser "2020-10-13")
+(define-obsolete-function-alias 'ts--query-cursor-set-byte-range 'tsc-query-cursor-set-byte-range "2020-10-13")
+(define-obsolete-function-alias 'ts--query-cursor-set-point-range 'tsc-query-cursor-set-point-range "2020-10-13")
+(define-obsolete-function-alias 'ts--query-start-byte-for-pattern 'tsc-query-start-byte-for-pattern "2020-10-13")
+(define-obsolete-function-alias 'ts--reset-parser 'tsc-reset-parser "2020-10-13")
 (define-obsolete-function-alias 'ts--save-context 'tsc--save-context "2020-10-13")
-(define-obsolete-function-alias 'ts--set-timeout-micros 'tsc--set-timeout-micros "2020-10-13")
+(define-obsolete-function-alias 'ts--set-timeout-micros 'tsc-set-parse-timeout "2020-10-13")
 (define-obsolete-function-alias 'ts--stringify-patterns 'tsc--stringify-patterns "2020-10-13")
-(define-obsolete-function-alias 'ts--timeout-micros 'tsc--timeout-micros "2020-10-13")
+(define-obsolete-function-alias 'ts--timeout-micros 'tsc-parse-timeout "2020-10-13")
 (define-obsolete-function-alias 'ts--try-load-dyn 'tsc--try-load-dyn "2020-10-13")
 (define-obsolete-function-alias 'ts--without-restriction 'tsc--without-restriction "2020-10-13")
 
+(define-obsolete-function-alias 'tsc--reset-parser 'tsc-reset-parser "0.18.1")
+(define-obsolete-function-alias 'tsc--set-timeout-micros 'tsc-set-parse-timeout "0.18.1")
+(define-obsolete-function-alias 'tsc--timeout-micros 'tsc-parse-timeout "0.18.1")
+(define-obsolete-function-alias 'tsc--query-cursor-set-byte-range 'tsc-query-cursor-set-byte-range "0.18.1")
+(define-obsolete-function-alias 'tsc--query-cursor-set-point-range 'tsc-query-cursor-set-point-range "0.18.1")
+(define-obsolete-function-alias 'tsc--query-start-byte-for-pattern 'tsc-query-start-byte-for-pattern "0.18.1")
+(define-obsolete-function-alias 'tsc--query-capture-names 'tsc-query-capture-names "0.18.1")
+
 (provide 'tsc-obsolete)
 ;;; tsc-obsolete.el ends here
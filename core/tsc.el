@@ -88,6 +88,72 @@ A \"point\" in this context is a (LINE-NUMBER . BYTE-COLUMN) pair. See
       (byte-to-position (+ byte-column (position-bytes (line-beginning-position)))))))
 
 
+;;; Parsing.
+
+(defvar tsc-last-parse-failure nil
+  "Error symbol describing why the last parse asked to keep its old tree failed.
+This is set by `tsc-parse-chunks' and `tsc-parse-buffer' when they are called
+with non-nil KEEP-OLD-TREE: to nil if the parse succeeded, or to one of
+`tsc-parse-timeout', `tsc-parse-cancelled', `tsc-parse-no-language',
+`tsc-parse-failed' otherwise.")
+
+(defun tsc-parse-chunks (parser input-function old-tree &optional keep-old-tree)
+  "Parse source code chunks generated by INPUT-FUNCTION with PARSER; return a tree.
+
+INPUT-FUNCTION should take 3 parameters: (BYTEPOS LINE-NUMBER BYTE-COLUMN), and
+return a fragment of the source code, starting from the position identified by
+either BYTEPOS or (LINE-NUMBER . BYTE-COLUMN). It should return nil, or an empty
+string, to signal the end of the source code.
+
+The source code doesn't need to come from a buffer. It can be e.g. process
+output, or text stored in a custom data structure.
+
+BYTEPOS is Emacs's 1-based byte position.
+
+LINE-NUMBER is the number returned by `line-number-at-pos', which counts from 1.
+
+BYTE-COLUMN counts from 0, likes Emacs's `current-column'. However, unlike that
+function, it counts bytes, instead of displayed glyphs.
+
+If you have already parsed an earlier version of this document, and it has since
+been edited, pass the previously parsed OLD-TREE so that its unchanged parts can
+be reused. This will save time and memory. For this to work correctly, you must
+have already edited it using `tsc-edit-tree' function in a way that exactly
+matches the source code changes.
+
+If KEEP-OLD-TREE is non-nil, and the parse fails, e.g. because it timed out or
+was cancelled, return OLD-TREE unchanged instead of signaling an error. The
+reason for the failure is then stored in `tsc-last-parse-failure'."
+  (tsc--parse-chunks parser input-function old-tree keep-old-tree))
+
+(defun tsc-parse-strings (parser chunks &optional old-tree)
+  "Use PARSER to parse the concatenation of the strings in CHUNKS; return a tree.
+CHUNKS should be a list or a vector. This avoids building a large string when
+the source code is already available in pieces, e.g. from a process filter.
+
+See `tsc-parse-chunks' for the meaning of OLD-TREE."
+  (tsc--parse-strings parser (if (vectorp chunks) chunks (vconcat chunks)) old-tree))
+
+(defvar tsc--cancellation-tokens (make-hash-table :test #'eq :weakness 'key)
+  "Cancellation tokens attached to parsers.
+Parsers only hold raw pointers to their tokens, so this keeps the tokens alive
+for as long as the parsers they are attached to.")
+
+(defun tsc-set-cancellation-token (parser token)
+  "Attach the cancellation TOKEN to PARSER, replacing the previous one, if any.
+If TOKEN is nil, detach the current token instead.
+
+Once a parse is cancelled, PARSER is reset, so the next parse starts from the
+beginning. A token can be attached to multiple parsers.
+
+With a token attached, parsing stops as soon as the input function passed to
+`tsc-parse-chunks' exits non-locally, instead of continuing until the end of
+the input. This allows parsing to be interrupted by `while-no-input'."
+  (tsc--set-cancellation-token parser token)
+  (if token
+      (puthash parser token tsc--cancellation-tokens)
+    (remhash parser tsc--cancellation-tokens)))
+
 ;;; Extracting buffer's text.
 
 (defvar tsc--buffer-input-chunk-size 4096)
@@ -106,6 +172,44 @@ This function must be called with narrowing disabled, e.g. within a
          (end-pos (or (byte-to-position end-byte) max-pos)))
     (buffer-substring-no-properties beg-pos end-pos)))
 
+(defun tsc-parse-buffer (parser &optional old-tree buffer keep-old-tree)
+  "Use PARSER to parse BUFFER's whole text, ignoring narrowing; return a tree.
+BUFFER defaults to the current buffer. This is equivalent to, but faster than,
+calling `tsc-parse-chunks' with `tsc--buffer-input', since the text is read in
+larger chunks, without going through a Lisp input function. Each chunk is still
+copied out of the buffer into a new string.
+
+See `tsc-parse-chunks' for the meaning of OLD-TREE and KEEP-OLD-TREE."
+  (with-current-buffer (or buffer (current-buffer))
+    (tsc--without-restriction
+      (tsc--parse-buffer parser old-tree keep-old-tree))))
+
+(defun tsc-parse-region (parser beg end &optional old-tree buffer)
+  "Use PARSER to parse only BUFFER's text between BEG and END; return a tree.
+BUFFER defaults to the current buffer. Narrowing is ignored, so BEG and END can
+be e.g. the bounds of the current narrowing, or of a single function.
+
+The tree's positions are relative to the whole buffer, so its nodes can be used
+like those of a tree returned by `tsc-parse-buffer'. Afterward, PARSER includes
+whole documents again: ranges set earlier with `tsc-set-included-ranges' are
+reset, and must be set again if needed. See `tsc-parse-chunks' for the meaning
+of OLD-TREE."
+  (with-current-buffer (or buffer (current-buffer))
+    (tsc--save-context
+      (tsc--parse-region parser
+                         (vector (position-bytes beg) (position-bytes end)
+                                 (tsc--point-from-position beg)
+                                 (tsc--point-from-position end))
+                         old-tree))))
+
+(defun tsc-edit-batch-flush (batch tree parser &optional buffer)
+  "Apply BATCH's pending edits to TREE, then re-parse BUFFER with PARSER.
+BUFFER defaults to the current buffer. Return the new tree, or TREE itself if
+there were no pending edits. See `tsc-make-edit-batch'."
+  (if (= 0 (tsc-edit-batch-apply batch tree))
+      tree
+    (tsc-parse-buffer parser tree buffer)))
+
 (defun tsc--buffer-substring-no-properties (beg-byte end-byte)
   "Return the current buffer's text from BEG-BYTE to END-BYTE.
 This function must be called with narrowing disabled, e.g. within a
@@ -123,12 +227,135 @@ This function must be called with narrowing disabled, e.g. within a
   (pcase-let ((`(,beg . ,end) (tsc-node-position-range node)))
     (buffer-substring-no-properties beg end)))
 
-(defun tsc-node-text (node)
-  "Return NODE's text, assuming it's from the current buffer's syntax tree."
-  (tsc--without-restriction
-    (tsc--node-text node)))
+(defun tsc-node-text (node &optional source)
+  "Return NODE's text, assuming it's from SOURCE's syntax tree.
+SOURCE defaults to the current buffer. It can be another buffer, or the string
+that NODE's syntax tree was parsed from, e.g. by `tsc-parse-string'. The text of
+a string is extracted by the dynamic module, which converts NODE's byte offsets
+into the string directly."
+  (if (stringp source)
+      (tsc--node-text-in-string node source)
+    (with-current-buffer (or source (current-buffer))
+      (tsc--without-restriction
+        (tsc--node-text node)))))
 
 
+;;; Debugging.
+
+(defun tsc-set-logger (parser logger)
+  "Make PARSER call LOGGER with the messages it logs while lexing and parsing.
+LOGGER is called with 2 arguments: the message's type, either :lex or :parse,
+and the message itself. Errors signaled by LOGGER are ignored.
+
+If LOGGER is t, the messages are appended to the buffer \"*tsc-log*\" instead. If
+it is nil, logging is disabled."
+  (tsc--set-logger parser (if (eq logger t) #'tsc--log-to-buffer logger)))
+
+(defun tsc-parser-print-dot-graphs (parser file)
+  "Make PARSER write DOT graphs of its parse stacks to FILE, while parsing.
+This shows how the parser explores and recovers from ambiguities and syntax
+errors. FILE is overwritten. The graphs can be rendered by Graphviz. If FILE is
+nil, stop writing the graphs.
+
+This is only supported on Unix-like systems."
+  (unless (fboundp 'tsc--print-dot-graphs)
+    (error "DOT graph output is not supported on this system"))
+  (tsc--print-dot-graphs parser (and file (expand-file-name file))))
+
+(defun tsc--log-to-buffer (type message)
+  "Append the log MESSAGE of the given TYPE to the buffer \"*tsc-log*\"."
+  (with-current-buffer (get-buffer-create "*tsc-log*")
+    (save-excursion
+      (goto-char (point-max))
+      (insert (format "%s %s\n" type message)))))
+
+;;; Background parsing.
+
+(defvar tsc-background-parse-poll-interval 0.05
+  "How often, in seconds, to check whether background parses have finished.")
+
+(defun tsc-parse-in-background (language text callback &optional old-tree)
+  "Parse TEXT as LANGUAGE on a background thread, then call CALLBACK.
+CALLBACK is called on the main thread, with the resulting syntax tree, or with nil
+if the parse failed or was cancelled. This is useful to avoid blocking redisplay
+during the initial parse of large buffers.
+
+The main thread is not notified when the parse finishes. Instead, a timer polls
+the job every `tsc-background-parse-poll-interval' seconds, so CALLBACK may be
+called up to that long afterward.
+
+TEXT must be the whole source code, e.g. a buffer's text, obtained with narrowing
+disabled. See `tsc-parse-chunks' for the meaning of OLD-TREE.
+
+Return a job object, which can be passed to `tsc-cancel-background-parse'."
+  (let* ((job (tsc--parse-in-background language text old-tree))
+         (timer nil)
+         (poll (lambda ()
+                 (let ((tree (condition-case nil
+                                 (or (tsc--parse-job-result job)
+                                     'running)
+                               (tsc-parse-failed nil))))
+                   (unless (eq tree 'running)
+                     (cancel-timer timer)
+                     (funcall callback tree))))))
+    (setq timer (run-with-timer tsc-background-parse-poll-interval
+                                tsc-background-parse-poll-interval
+                                poll))
+    job))
+
+(defun tsc-cancel-background-parse (job)
+  "Cancel the background parse JOB, started by `tsc-parse-in-background'.
+Its callback will be called with nil, unless the parse has already finished."
+  (tsc--cancel-parse-job job))
+
+;;; Parser registries.
+
+(defun tsc-registry-register (registry key language &optional ranges)
+  "Register a parser for LANGUAGE in REGISTRY under the symbol KEY.
+If RANGES is non-nil, it should be a vector of the ranges the parser should
+include, like for `tsc-set-included-ranges'. Otherwise, the parser covers the
+whole buffer.
+
+If KEY is already registered, its parser and syntax tree are replaced."
+  (tsc--registry-register registry key language ranges))
+
+(defun tsc-registry-parse-buffer (registry &optional keys buffer keep-old-tree)
+  "Parse BUFFER's text for each of KEYS in REGISTRY, ignoring narrowing.
+KEYS defaults to all the keys, in registration order. BUFFER defaults to the
+current buffer. Each key's previous syntax tree is reused, so the edits made to
+the buffer since must have been applied with `tsc-registry-edit'.
+
+Return an alist of (KEY . TREE). See `tsc-parse-chunks' for the meaning of
+KEEP-OLD-TREE."
+  (with-current-buffer (or buffer (current-buffer))
+    (tsc--without-restriction
+      (mapcar (lambda (key)
+                (cons key (tsc--registry-parse-buffer registry key keep-old-tree)))
+              (or keys (tsc-registry-keys registry))))))
+
+(defun tsc-registry-query-captures (registry queries &optional beg end buffer)
+  "Execute QUERIES on the syntax trees in REGISTRY, and return the captures.
+This allows e.g. highlighting a buffer containing multiple languages with a
+single call.
+
+QUERIES is an alist of (KEY . QUERY), where QUERY should be created for the
+language registered under KEY. Keys whose syntax trees haven't been parsed yet
+are skipped. If BEG and END are non-nil, only the captures intersecting that
+region of BUFFER are returned. BUFFER defaults to the current buffer, whose
+narrowing is ignored.
+
+Each capture has the form (KEY CAPTURE-TAG . CAPTURED-NODE), like those returned
+by `tsc-query-captures', with KEY added. Since the injected languages' syntax
+trees are parsed from the host buffer, their nodes' positions are in the buffer
+coordinates. Captures are sorted by their start positions. Those starting at the
+same position are in the order of QUERIES."
+  (with-current-buffer (or buffer (current-buffer))
+    (tsc--without-restriction
+      (tsc--registry-query-captures registry queries
+                                    #'tsc--buffer-substring-no-properties
+                                    (and beg (position-bytes beg))
+                                    (and end (position-bytes end))))))
+
 ;;; Convenient versions of some functions.
 
 (defun tsc-get-descendant-for-position-range (node beg end)
@@ -153,6 +380,43 @@ This function must be called in NODE's source buffer."
     (signal 'wrong-type-argument (list 'keywordp field)))
   (tsc--get-child-by-field-name node (substring (symbol-name field) 1)))
 
+(defun tsc-get-ancestor-of-type (node types &optional max-depth)
+  "Return NODE's closest ancestor whose type is one of TYPES.
+TYPES is a list of node types, or a single node type. Named node types are
+symbols, anonymous node types are strings. See `tsc-node-type'.
+
+If the optional arg MAX-DEPTH is non-nil, look at most that many levels up,
+e.g. 1 means only NODE's parent is checked. Return nil if there's no such
+ancestor."
+  (tsc--get-ancestor-of-type node (if (listp types) types (list types)) max-depth))
+
+(defun tsc-lowest-common-ancestor (a b &optional tree-or-node)
+  "Return the deepest node that contains both A and B.
+A and B are either nodes, or byte positions within TREE-OR-NODE. If a node
+contains the other, it is returned. Return nil if A and B are nodes from
+different syntax trees."
+  (let ((root (if (tsc-tree-p tree-or-node)
+                  (tsc-root-node tree-or-node)
+                tree-or-node)))
+    (when (integerp a)
+      (setq a (tsc-get-descendant-for-byte-range root a a)))
+    (when (integerp b)
+      (setq b (tsc-get-descendant-for-byte-range root b b)))
+    (tsc--lowest-common-ancestor a b)))
+
+(defun tsc-resolve-node-path (tree-or-node path)
+  "Follow PATH from TREE-OR-NODE, and return the final node.
+If TREE-OR-NODE is a tree, PATH is followed from its root node. PATH should be a
+list of steps, as returned by `tsc-node-path'. Return nil if a step cannot be
+followed.
+
+This can be used to find a node again, in the syntax tree obtained by re-parsing
+the source code."
+  (tsc--resolve-node-path (if (tsc-tree-p tree-or-node)
+                              (tsc-root-node tree-or-node)
+                            tree-or-node)
+                          path))
+
 (defun tsc-node-start-position (node)
   "Return NODE's start position.
 This function must be called in NODE's source buffer."
@@ -182,7 +446,9 @@ Return the index of the child node if one was found, nil otherwise."
 If the optional arg PROPS is a vector of property names, this function returns a
 vector containing the node's corresponding properties. If the optional arg
 OUTPUT is also non-nil, it must be a vector of the same length, where the
-properties will be written into.
+properties will be written into. OUTPUT can also be a record with that many
+slots, e.g. an instance of a `cl-defstruct' type, which allows reading the
+properties with the type's accessors.
 
 PROPS can also be a single property name, in which case this function returns
 only that property, and OUTPUT is ignored.
@@ -190,11 +456,25 @@ only that property, and OUTPUT is ignored.
 See `tsc-valid-node-props' for the list of available properties."
   (tsc--check-node-props props)
   ;; TODO: Fix this.
-  (when (or (eq props :depth)
-            (and (seqp props) (cl-find :depth props)))
-    (error "Cursor doesn't currently support :depth property"))
+  (dolist (prop '(:depth :event))
+    (when (or (eq props prop)
+              (and (seqp props) (cl-find prop props)))
+      (error "Cursor doesn't currently support %s property" prop)))
   (tsc--current-node cursor props output))
 
+(defun tsc-load-language (file symbol-name lang-symbol)
+  "Load the shared lib FILE and return the language under SYMBOL-NAME.
+FILE can be any path to the shared lib. A relative path is expanded against
+`default-directory'. The language's name symbol is set to LANG-SYMBOL.
+
+The language's ABI version is checked against `tsc-supported-abi-range'. If it's
+outside of that range, `tsc-lang-abi-too-old' or `tsc-lang-abi-too-new' is
+signaled, with the data (VERSION (MIN . MAX) FILE).
+
+To also make the language available through `tree-sitter-require', use
+`tree-sitter-load' instead."
+  (tsc--load-language (expand-file-name file) symbol-name lang-symbol))
+
 (defun tsc-lang-field-id (language field)
   "Return the numeric id of FIELD in LANGUAGE. FIELD should be a keyword."
   (unless (keywordp field)
@@ -224,6 +504,41 @@ NODE-TYPE should be a symbol (named nodes) or a string (anonymous nodes)."
       (replace-regexp-in-string (regexp-quote "\\.") ".")))
    (t (error "Invalid patterns"))))
 
+(defvar tsc-query-predicate-functions nil
+  "Alist of custom query predicates, of the form (NAME . FUNCTION).
+Use `tsc-query-define-predicate' to register a predicate.")
+
+(defun tsc-query-define-predicate (name function)
+  "Register FUNCTION as the implementation of the query predicate NAME.
+NAME is the predicate's name as a string, without the prefix \"#\", e.g.
+\"in-comment?\".
+
+When a query using the predicate is executed, FUNCTION is called for each match
+of a pattern containing it, with the predicate's arguments: captured nodes for
+captures (the first one, for captures of multiple nodes, or nil, for captures of
+none), and strings for strings. If it returns nil, the match is dropped.
+
+Only queries created afterward use FUNCTION."
+  (let ((entry (assoc name tsc-query-predicate-functions)))
+    (if entry
+        (setcdr entry function)
+      (push (cons name function) tsc-query-predicate-functions))))
+
+(defvar tsc-query-regexp-syntax 'rust
+  "The syntax of the regular expressions in #match? and its variants.
+If `rust', they use the syntax of Rust's `regex' crate, like other tree-sitter
+bindings. Each one is compiled once per query, and matched without calling
+into Lisp, so this is the fastest. If `emacs', they use Emacs's syntax, and are
+matched with `string-match-p', case-sensitively. This is useful for queries
+written for Emacs, e.g. using syntax classes.
+
+Only queries created afterward are affected.")
+
+(defun tsc--query-regexp-match-p (regexp string)
+  "Return non-nil if STRING matches the Emacs REGEXP, case-sensitively."
+  (let ((case-fold-search nil))
+    (string-match-p regexp string)))
+
 (defun tsc-make-query (language patterns &optional tag-assigner)
   "Create a new query for LANGUAGE from a sequence of S-expression PATTERNS.
 The query is associated with LANGUAGE, and can only be run on syntax nodes
@@ -236,9 +551,43 @@ that are captured as \"@function.builtin\" will be tagged with the symbol
 TAG-ASSIGNER, which should return a tag value when given a capture name (without
 the prefix \"@\"). If it returns nil, the associated capture name is disabled.
 
+The standard predicates #eq?, #match?, #any-of?, and their negations #not-eq?,
+#not-match?, #not-any-of?, are evaluated when the query is executed, so that
+matches that don't satisfy them are not returned. So are #any-eq?, #any-match?,
+#any-not-eq?, #any-not-match?, which, for captures of multiple nodes, require
+only one of the nodes to satisfy the condition, instead of all of them. Custom
+predicates can be registered with `tsc-query-define-predicate'. The syntax of
+the regular expressions is determined by `tsc-query-regexp-syntax'.
+
+If PATTERNS are invalid, an error of type `tsc-query-invalid' (or one of its
+subtypes, e.g. `tsc-query-invalid-node-type') is signaled, with data of the form
+\(MESSAGE POINT BYTEPOS KIND). POINT and BYTEPOS identify the error's location in
+the query source, as a (LINE-NUMBER . BYTE-COLUMN) pair, and a 1-based byte
+position. KIND is one of :syntax, :node-type, :field, :capture, :predicate,
+:structure, :language.
+
 See also: `tsc-query-captures' and `tsc-query-matches'."
   (tsc--make-query language (tsc--stringify-patterns patterns)
-                   (or tag-assigner #'intern)))
+                   (or tag-assigner #'intern)
+                   (eq tsc-query-regexp-syntax 'emacs)))
+
+(defun tsc-validate-query (language patterns)
+  "Check PATTERNS against LANGUAGE, and return a list of the problems found.
+This allows linting third-party query files before using them. Unlike
+`tsc-make-query', which signals an error for the first problem, this reports all
+unknown node types, field names, and capture names in PATTERNS. Return nil if
+PATTERNS are valid.
+
+Each problem is a plist with the properties:
+ - :kind, one of :node-type, :field, :capture, :predicate, :syntax, :structure,
+   :language, like the KIND of `tsc-make-query' errors.
+ - :name, the unknown name, for :node-type, :field, and :capture problems.
+ - :message, the error message, for the other kinds of problems.
+ - :point and :bytepos, the problem's location in the query source.
+
+Problems of the other kinds prevent further checking, so they are always the
+last one reported."
+  (tsc--validate-query language (tsc--stringify-patterns patterns)))
 
 (defun tsc-query-matches (query node text-function &optional cursor)
   "Execute QUERY on NODE and return a sequence of matches.
@@ -247,7 +596,8 @@ Matches are sorted in the order they were found.
 Each match has the form (PATTERN-INDEX . MATCH-CAPTURES), where PATTERN-INDEX is
 the 0-based position of the matched pattern within QUERY, and MATCH-CAPTURES is
 a sequence of captures associated with the match, similar to that returned by
-`tsc-query-captures'.
+`tsc-query-captures'. The properties set by the pattern's #set! directives can
+be obtained with `tsc-query-pattern-properties'.
 
 TEXT-FUNCTION is called to get nodes' texts (for text-based predicates). It
 should take 2 parameters: (BEG-BYTE END-BYTE), and return the corresponding
@@ -258,7 +608,67 @@ QUERY. Otherwise, a newly created query-cursor is used."
   (tsc--query-cursor-matches
    (or cursor (tsc-make-query-cursor)) query node text-function))
 
-(defun tsc-query-captures (query node text-function &optional cursor)
+(defun tsc-query-matches-by-pattern (query node text-function &optional cursor)
+  "Execute QUERY on NODE and return its matches, grouped by pattern.
+The result is a vector with one element per pattern in QUERY. The element at
+PATTERN-INDEX is the list of that pattern's matches, in the order they were
+found. Each match is a plist of its captures, of the form (TAG NODE TAG NODE
+...), with tags assigned the same way as for `tsc-query-captures'.
+
+This is convenient for processing whole matches together, e.g. a definition with
+its scope, rather than their captures one by one. If the capture tags are not
+symbols, `lax-plist-get' should be used to look them up.
+
+TEXT-FUNCTION and CURSOR are the same as for `tsc-query-matches'."
+  (tsc--query-cursor-matches-by-pattern
+   (or cursor (tsc-make-query-cursor)) query node text-function))
+
+(defun tsc-query-matches-iter (query node text-function)
+  "Return an iterator over the matches of QUERY on NODE.
+Unlike `tsc-query-matches', matches are found lazily, one per iteration, so
+large result sets don't have to be collected up front, and the iteration can be
+abandoned early. The iterator yields matches of the same form. TEXT-FUNCTION is
+also the same.
+
+For example, to find the first function definition with a given name:
+
+    (iter-do (match (tsc-query-matches-iter query root-node text-function))
+      (when (equal (tsc-node-text (cdr (aref (cdr match) 0))) name)
+        (throw 'found match)))
+
+NODE's tree should not be edited while the iterator is in use."
+  (let ((iter (tsc--query-matches-iter query node)))
+    (lambda (control _yield-result)
+      (pcase control
+        (:next (or (and iter (tsc--query-next-match iter query text-function))
+                   (signal 'iter-end-of-sequence nil)))
+        (:close (setq iter nil))
+        (_ (error "???"))))))
+
+(defun tsc-query-matches-start (query node)
+  "Return a resumable cursor over the matches of QUERY on NODE.
+The cursor can be advanced in bounded chunks, with `tsc-query-matches-continue',
+and resumed later. This allows analyzing a large buffer cooperatively, e.g. from
+an idle timer, without blocking Emacs. For example:
+
+    (let ((cursor (tsc-query-matches-start query root-node))
+          (timer nil))
+      (setq timer
+            (run-with-idle-timer
+             0.5 t (lambda ()
+                     (with-current-buffer buffer
+                       (seq-doseq (match (tsc-query-matches-continue
+                                          cursor query text-function 100))
+                         (process match))
+                       (message \"%d%%\" (/ (* 100 (tsc-query-matches-position cursor))
+                                            (position-bytes (point-max))))
+                       (when (tsc-query-matches-done-p cursor)
+                         (cancel-timer timer)))))))
+
+NODE's tree should not be edited while the cursor is in use."
+  (tsc--query-matches-iter query node))
+
+(defun tsc-query-captures (query node text-function &optional cursor deduplicate)
   "Execute QUERY on NODE and return a sequence of captures.
 Captures are sorted in the order they appear.
 
@@ -272,9 +682,119 @@ should take 2 parameters: (BEG-BYTE END-BYTE), and return the corresponding
 chunk of text in the source code.
 
 If the optional arg CURSOR is non-nil, it is used as the query-cursor to execute
-QUERY. Otherwise, a newly created query-cursor is used."
+QUERY. Otherwise, a newly created query-cursor is used.
+
+If the optional arg DEDUPLICATE is non-nil, captures are sorted by their start
+positions, and those overlapping a capture from an earlier pattern (or an
+earlier capture from the same pattern) are dropped. This is cheaper than doing
+it in Lisp, e.g. for highlighting, where earlier patterns take precedence."
   (tsc--query-cursor-captures
-   (or cursor (tsc-make-query-cursor)) query node text-function))
+   (or cursor (tsc-make-query-cursor)) query node text-function deduplicate))
+
+(defun tsc-query-captures-in-node (query node text-function
+                                         &optional cursor deduplicate)
+  "Execute QUERY on NODE, limited to NODE's byte range, and return the captures.
+This is like `tsc-query-captures', with the query-cursor's byte range set to
+NODE's, so that no time is spent outside of it, without the need to compute and
+set the range beforehand. It is useful e.g. for resolving the locals or
+injections within a specific node.
+
+If the optional arg CURSOR is non-nil, its match limit is used. Its byte range is
+ignored, and left unchanged. DEDUPLICATE is the same as for `tsc-query-captures'."
+  (tsc--query-captures-in-node
+   (or cursor (tsc-make-query-cursor)) query node text-function deduplicate))
+
+(defun tsc-query-changed-captures (old-tree new-tree query text-function
+                                           &optional padding cursor)
+  "Execute QUERY on NEW-TREE, within the ranges that changed since OLD-TREE.
+Return the captures, like `tsc-query-captures'. This is the basis of efficient
+re-highlighting: after a re-parse, only the regions whose syntactic structure
+changed need to be queried again.
+
+The ranges are those returned by `tsc-changed-ranges', so OLD-TREE must have
+been edited to match NEW-TREE. If PADDING is non-nil, each range is extended by
+that many bytes on both sides, e.g. to account for patterns spanning multiple
+nodes. Overlapping ranges are merged, and captures are not repeated.
+
+TEXT-FUNCTION is the same as for `tsc-query-captures'. If the optional arg CURSOR
+is non-nil, its match limit is used. Its byte range is ignored, and left
+unchanged."
+  (tsc--query-changed-captures
+   (or cursor (tsc-make-query-cursor)) old-tree new-tree query text-function padding))
+
+(defun tsc-query-highlight-spans (query node text-function faces &optional cursor)
+  "Execute QUERY on NODE, and return the spans to highlight, according to FACES.
+FACES is an alist of (CAPTURE-NAME . FACE), where CAPTURE-NAME is a string or a
+symbol, e.g. (\"keyword\" . font-lock-keyword-face). Captures whose names are
+not in FACES are ignored.
+
+The returned value is a flat vector [BEG END FACE BEG END FACE ...], where BEG
+and END are positions in the current buffer, ready to be used with e.g.
+`put-text-property'. Spans from earlier patterns in QUERY come first. This is
+faster than mapping the results of `tsc-query-captures' to faces in Lisp.
+
+This function must be called in NODE's source buffer. TEXT-FUNCTION and CURSOR
+are the same as for `tsc-query-captures'."
+  (let ((spans (tsc--query-cursor-highlight-spans
+                (or cursor (tsc-make-query-cursor)) query node text-function faces)))
+    ;; Byte positions are the same as character positions if the buffer is all ASCII.
+    (unless (tsc--without-restriction
+              (= (position-bytes (point-max)) (point-max)))
+      (let ((i 0)
+            (length (length spans)))
+        (while (< i length)
+          (aset spans i (byte-to-position (aref spans i)))
+          (aset spans (1+ i) (byte-to-position (aref spans (1+ i))))
+          (setq i (+ i 3)))))
+    spans))
+
+(defconst tsc-valid-capture-props
+  '(:node ;the captured node
+    :text ;the captured node's text, as returned by the text function
+    :capture-tag ;see `tsc-query-captures'
+    :capture-index ;0-based index of the capture name within the query
+    :pattern-index ;0-based index of the matched pattern
+    :start-byte :end-byte)
+  "Capture properties that `tsc-query-captures-into' can write.
+Except for :node and :text, none of them requires allocating Lisp objects.
+Retrieving :text while executing the query is cheaper than calling e.g.
+`buffer-substring' for each capture afterward.")
+
+(defun tsc-query-captures-into (query node text-function props output
+                                      &optional cursor)
+  "Execute QUERY on NODE, writing the captures' properties into OUTPUT.
+Return the number of captures found.
+
+This is like `tsc-query-captures', except that no Lisp objects are allocated for
+the captures, unless PROPS asks for them. OUTPUT can thus be reused across
+calls, e.g. for highlighting, to reduce the pressure on the garbage collector.
+
+PROPS is a vector of K capture property names. See `tsc-valid-capture-props' for
+the list of available properties. OUTPUT is a vector, treated as a flat sequence
+of K-slot chunks, where the I-th capture's properties are written into the slots
+from I*K to (I+1)*K-1. If the returned number is larger than what OUTPUT can
+hold, the extra captures are not written. The query should then be executed
+again, with a larger OUTPUT.
+
+For example:
+
+    (let* ((output (make-vector (* 3 1024) nil))
+           (count (tsc-query-captures-into query root-node text-function
+                                           [:capture-tag :start-byte :end-byte]
+                                           output)))
+      (dotimes (i (min count 1024))
+        (let ((tag (aref output (* 3 i)))
+              (beg (byte-to-position (aref output (+ (* 3 i) 1))))
+              (end (byte-to-position (aref output (+ (* 3 i) 2)))))
+          ...)))
+
+TEXT-FUNCTION and CURSOR are the same as for `tsc-query-captures'."
+  (when-let ((invalid-props (seq-remove (lambda (prop)
+                                          (memq prop tsc-valid-capture-props))
+                                        props)))
+    (error "Invalid capture properties %s" invalid-props))
+  (tsc--query-cursor-captures-into
+   (or cursor (tsc-make-query-cursor)) query node text-function props output))
 
 
 ;;; Traversal.
@@ -282,13 +802,33 @@ QUERY. Otherwise, a newly created query-cursor is used."
 (defconst tsc-valid-node-props
   '(:type
     :field ;node's field name within the parent node
+    :parent-type ;type of the parent node
+    :parent-field ;parent node's field name within the grandparent node
+    :kind-id ;numeric id of node's type, see `tsc-lang-node-type-id'
+    :field-id ;numeric id of node's field, see `tsc-lang-field-id'
     :depth ;node's depth, relative to the iterator's start
+    :event ;:enter or :exit, see the traversal option :order
     :named-p :extra-p :error-p :missing-p :has-error-p
     :start-byte :end-byte
     :start-point :end-point
-    :range :byte-range)
+    :range :byte-range
+    :text ;node's text, from the current buffer, or the traversal option :source
+    :id ;number that uniquely identifies the node within its tree
+    :child-count :named-child-count)
   "Node properties that the traversal functions can return.
 
+The property :text assumes that the syntax tree belongs to the current buffer,
+which must not be narrowed to exclude the node. When traversing a tree that
+belongs to a string, or to another buffer, pass its source code as the traversal
+option :source instead.
+
+The properties :parent-type and :parent-field are nil for the node that the
+traversal starts from.
+
+A function can also be used as a property, whose value is the result of calling
+it with the node. This allows computing custom properties without giving up the
+vector-filling fast path for the rest.
+
 When dealing with a large number of nodes, working with node objects creates a
 huge pressure on the garbage collector. To increase performance, it's better to
 instead extract and work with individual node properties. Several functions can
@@ -299,23 +839,51 @@ This wouldn't be necessary if the runtime supported stack-allocated objects.
 e.g. automatically through escape analysis. How about porting ELisp to GraalVM?")
 
 (defun tsc--check-node-props (props)
-  "Validate that PROPS are valid node properties."
+  "Validate that PROPS are valid node properties.
+Functions are valid properties, see `tsc-valid-node-props'."
   (cond
    ((keywordp props)
     (unless (memq props tsc-valid-node-props)
       (error "Invalid node property %s" props)))
-   ((vectorp props)
+   ((functionp props) nil)
+   ((or (vectorp props) (consp props))
     (when-let ((invalid-props (seq-filter
-                               (lambda (kw)
-                                 (not (memq kw tsc-valid-node-props)))
+                               (lambda (prop)
+                                 (not (or (memq prop tsc-valid-node-props)
+                                          (functionp prop))))
                                props)))
       (error "Invalid node properties %s" invalid-props)))
    ((null props) nil)
-   (t (error "Expected vectors, keyword, or nil %s" props))))
-
-(defun tsc-traverse-mapc (func tree-or-node &optional props)
+   (t (error "Expected vector, list, keyword, or nil %s" props))))
+
+(defconst tsc--traversal-options
+  '((:order :pre :post :pre-post)
+    (:leaves-only)
+    (:named-only)
+    (:reverse)
+    (:start-byte)
+    (:end-byte)
+    (:max-depth)
+    (:with-depth)
+    (:source))
+  "Options that the traversal functions accept, with their valid values.
+An option whose list of valid values is empty accepts any value.")
+
+(defun tsc--check-traversal-options (options)
+  "Validate that OPTIONS is a plist of valid traversal options."
+  (unless (= 0 (% (length options) 2))
+    (error "Expected a plist of traversal options %s" options))
+  (cl-loop for (key value) on options by #'cddr
+           for valid = (assq key tsc--traversal-options)
+           do (cond
+               ((null valid)
+                (error "Invalid traversal option %s" key))
+               ((and (cdr valid) (not (memq value (cdr valid))))
+                (error "Invalid value %s for traversal option %s" value key)))))
+
+(defun tsc-traverse-mapc (func tree-or-node &optional props &rest options)
   "Call FUNC for each node of TREE-OR-NODE.
-The traversal is depth-first pre-order.
+The traversal is depth-first pre-order, unless specified otherwise by OPTIONS.
 
 If the optional arg PROPS is a vector of property names, FUNC is called with a
 vector containing the node's corresponding properties, instead of the node
@@ -326,6 +894,14 @@ properties. See `tsc-valid-node-props' for the list of available properties.
 PROPS can also be a single property name, in which case FUNC receives only that
 property each invocation.
 
+If PROPS is a list of property names, FUNC is called with a fresh plist of the
+node's corresponding properties, which can be read with `plist-get'. This is
+more convenient, but slower than the vector form.
+
+If FUNC returns :skip-children after a node is entered, that node's descendants
+are not visited. This allows pruning the traversal, e.g. when looking for only
+top-level constructs.
+
 For example, to crudely render a syntax tree:
 
     (tsc-traverse-mapc
@@ -336,13 +912,71 @@ For example, to crudely render a syntax tree:
                    (format \"%S\" type) \"\\n\"))))
      tree
      [:type :depth :named-p])
+
+OPTIONS is a plist that controls the traversal. The supported options are:
+
+  :order        Either :pre (the default), :post, or :pre-post. In post-order,
+                each node is visited after all of its descendants. With
+                :pre-post, each node is visited before its descendants, and
+                nodes that have descendants are visited again after them. The
+                property :event tells which visit it is: :enter or :exit.
+
+  :leaves-only  If non-nil, only nodes without children (i.e. tokens) are
+                visited.
+
+  :named-only   If non-nil, anonymous nodes are skipped. Their descendants,
+                if any, are still visited.
+
+  :reverse      If non-nil, each node's children are visited from last to
+                first. Combined with `:order :post', this visits nodes in the
+                exact reverse of the default order, i.e. backward from the end
+                of the source code.
+
+  :start-byte   Byte positions limiting the traversal to nodes that intersect
+  :end-byte     the range from START-BYTE to END-BYTE. Subtrees that are
+                entirely outside of the range are skipped.
+
+  :max-depth    If non-nil, nodes deeper than MAX-DEPTH are skipped. The
+                starting node has depth 0.
+
+  :with-depth   If non-nil, FUNC is called with the node's depth as an
+                additional argument. This is cheaper than requesting the
+                property :depth in a vector, if only the node and its depth
+                are needed. It has no effect on iterators.
+
+  :source       If non-nil, the string that TREE-OR-NODE's syntax tree was
+                parsed from. The property :text is then extracted from it,
+                instead of from the current buffer. An error is signaled if
+                a node does not fit in it.
 "
   (tsc--check-node-props props)
-  (tsc--traverse-mapc func tree-or-node props))
+  (tsc--check-traversal-options options)
+  (tsc--traverse-mapc func tree-or-node props options))
+
+(defun tsc-traverse-fold (func init tree-or-node &optional props &rest options)
+  "Combine the nodes of TREE-OR-NODE using FUNC, starting with INIT.
+FUNC is called with 2 arguments: the accumulated value so far, and the current
+node (or its properties, as specified by PROPS). Its return value becomes the
+accumulated value for the next node. Return the final accumulated value. With
+the option :with-depth, FUNC also receives the node's depth as a third argument.
+
+The nodes are visited in the same order as `tsc-traverse-mapc', which also
+describes PROPS and the supported OPTIONS. Note that if PROPS is a vector, it is
+reused across invocations of FUNC, so it must not be accumulated directly.
 
-(defun tsc-traverse-iter (tree-or-node &optional props)
+For example, to count the number of error nodes:
+
+    (tsc-traverse-fold (lambda (count type)
+                         (if (eq type 'ERROR) (1+ count) count))
+                       0 tree :type)
+"
+  (tsc--check-node-props props)
+  (tsc--check-traversal-options options)
+  (tsc--traverse-fold func init tree-or-node props options))
+
+(defun tsc-traverse-iter (tree-or-node &optional props &rest options)
   "Return an iterator that traverse TREE-OR-NODE.
-The traversal is depth-first pre-order.
+The traversal is depth-first pre-order, unless specified otherwise by OPTIONS.
 
 If the optional arg PROPS is a vector of property names, the iterator yields a
 vector containing the node's corresponding properties, instead of the node
@@ -351,7 +985,8 @@ reference to it. It's recommended to use `pcase-let' to extract the properties.
 See `tsc-valid-node-props' for the list of available properties.
 
 PROPS can also be a single property name, in which case the iterator yields only
-that property each iteration.
+that property each iteration. If PROPS is a list of property names, the iterator
+yields a fresh plist of the node's corresponding properties.
 
 For example, to crudely render a syntax tree:
 
@@ -361,9 +996,12 @@ For example, to crudely render a syntax tree:
         (when named-p                       ;AST
           (insert (make-string depth \\? )   ;indentation
                   (format \"%S\" type) \"\\n\"))))
+
+See `tsc-traverse-mapc' for the supported OPTIONS.
 "
   (tsc--check-node-props props)
-  (let ((iter (tsc--iter tree-or-node))
+  (tsc--check-traversal-options options)
+  (let ((iter (tsc--iter tree-or-node options))
         (output (when (vectorp props)
                   (make-vector (length props) nil))))
     (lambda (control _yield-result)
@@ -373,9 +1011,50 @@ For example, to crudely render a syntax tree:
         (:close (setq iter nil))
         (_ (error "???"))))))
 
-(cl-defmacro tsc-traverse-do ((vars tree-or-node) &rest body)
+(defun tsc-diff-iter (old new)
+  "Return an iterator over the structural differences between OLD and NEW.
+OLD and NEW are trees or nodes, e.g. the syntax trees of a buffer before and
+after an edit. They are walked in lock-step, and the iterator yields a cons cell
+(OLD-NODE . NEW-NODE) for each pair of corresponding nodes whose types or
+numbers of children differ. The descendants of such a pair are not compared.
+
+For example, to collect the types of the changed nodes:
+
+    (iter-do (pair (tsc-diff-iter old-tree new-tree))
+      (push (tsc-node-type (cdr pair)) types))
+"
+  (let ((iter (tsc--diff-iter old new)))
+    (lambda (control _yield-result)
+      (pcase control
+        (:next (or (and iter (tsc--diff-iter-next iter))
+                   (signal 'iter-end-of-sequence nil)))
+        (:close (setq iter nil))
+        (_ (error "???"))))))
+
+(defun tsc-tree-diff (old-tree new-tree &optional old-text new-text)
+  "Return the list of subtrees that differ between OLD-TREE and NEW-TREE.
+Unlike `tsc-diff-iter', the children of 2 corresponding nodes are aligned by
+comparing the hashes of their subtrees, so that inserting or deleting a node
+doesn't make its following siblings appear changed.
+
+Each element of the returned list is one of:
+
+    (:inserted NEW-NODE)
+    (:deleted OLD-NODE)
+    (:changed OLD-NODE NEW-NODE)
+
+The changes are ordered by their positions. Their ranges can be retrieved with
+e.g. `tsc-node-byte-range'.
+
+By default, only the structure of the trees is compared. If the optional args
+OLD-TEXT and NEW-TEXT are non-nil, they must be the source code the trees were
+parsed from, and the text of their leaf nodes is compared as well, so that e.g.
+renaming an identifier is reported as a change."
+  (tsc--tree-diff old-tree new-tree old-text new-text))
+
+(cl-defmacro tsc-traverse-do ((vars tree-or-node &rest options) &rest body)
   "Evaluate BODY with VARS bound to properties of each node in TREE-OR-NODE.
-The traversal is depth-first pre-order.
+The traversal is depth-first pre-order, unless specified otherwise by OPTIONS.
 
 VARS must be a vector of symbols. See `tsc-valid-node-props' for the list of
 available properties. (In VARS, they must be symbols, not keywords.)
@@ -386,9 +1065,11 @@ For example, to crudely render a syntax tree:
       (when named-p                     ;AST
         (insert (make-string depth \\? ) ;identation
                 (format \"%S\" type) \"\\n\")))
+
+See `tsc-traverse-mapc' for the supported OPTIONS.
 "
   (declare (indent 1)
-           (debug ((vectorp form) body)))
+           (debug ((vectorp form &rest form) body)))
   (unless (vectorp vars)
     (error "Var bindings must be a vector"))
   (let ((props (cl-map 'vector
@@ -398,7 +1079,10 @@ For example, to crudely render a syntax tree:
         (iter (make-symbol "iter"))
         (output (make-symbol "output")))
     (tsc--check-node-props props)
-    `(let ((,iter (tsc--iter ,tree-or-node))
+    `(let ((,iter (tsc--iter ,tree-or-node
+                             (let ((options (list ,@options)))
+                               (tsc--check-traversal-options options)
+                               options)))
            (,output ,(make-vector (length props) nil)))
        (while (tsc--iter-next-node ,iter ,props ,output)
          (let* (,@(cl-loop for i below (length vars)
@@ -413,6 +1097,51 @@ For example, to crudely render a syntax tree:
   "Return the pretty-printed string of TREE's sexp."
   (pp-to-string (read (tsc-tree-to-sexp tree))))
 
+(defun tsc-node-to-pretty-sexp (node &optional max-depth ranges fields)
+  "Return the indented sexp representation of NODE, in a string.
+Each named node is put on its own line, indented according to its depth.
+
+If the optional arg MAX-DEPTH is non-nil, descendants deeper than MAX-DEPTH are
+elided with \"...\". If RANGES is non-nil, each node is followed by its byte
+positions, in the form [START-BYTEPOS-END-BYTEPOS]. If FIELDS is non-nil, each
+node is preceded by its field name, if any."
+  (tsc--node-to-pretty-sexp node max-depth ranges fields))
+
+(defun tsc-node-to-json (node &optional named-only)
+  "Return the JSON representation of NODE, and its descendants, in a string.
+Each node is represented as an object with the keys \"type\", \"named\",
+\"field\" (if any), \"missing\" (if true), \"start_byte\", \"end_byte\",
+\"start_point\", \"end_point\", and \"children\". Like elsewhere in this
+package, and in `tsc-node-to-pretty-sexp', positions use Emacs's conventions:
+\"start_byte\" and \"end_byte\" are 1-based byte positions, and points are
+[LINE-NUMBER, BYTE-COLUMN] arrays, where LINE-NUMBER counts from 1, and
+BYTE-COLUMN counts from 0.
+
+If the optional arg NAMED-ONLY is non-nil, anonymous nodes are omitted.
+
+To serialize a whole syntax tree, pass its root node."
+  (tsc--node-to-json node named-only))
+
+(defun tsc-save-tree-cache (tree file key)
+  "Save the nodes of the syntax TREE to FILE, tagging them with KEY.
+KEY is a string identifying the source code TREE was parsed from, typically a
+hash of the buffer's content, e.g. (secure-hash \='sha256 (current-buffer)).
+The nodes can later be restored with `tsc-load-tree-cache', given the same KEY,
+without parsing the source code again."
+  (tsc--save-tree-cache tree (expand-file-name file) key))
+
+(defun tsc-load-tree-cache (language file key)
+  "Restore the syntax tree of LANGUAGE saved in FILE by `tsc-save-tree-cache'.
+Return nil if FILE doesn't exist, or if it was saved with a key other than KEY, by
+a different version of LANGUAGE's grammar, or by an older version of this
+package.
+
+The returned object is a read-only snapshot of the tree's nodes, whose positions
+are only valid as long as the source code is unchanged, i.e. still has KEY. Its
+nodes can be visited with `tsc-cached-tree-mapc'."
+  (when (file-exists-p file)
+    (tsc--load-tree-cache language (expand-file-name file) key)))
+
 (defun tsc--node-steps (node)
   "Return the sequence of steps from the root node to NODE.
 
@@ -542,9 +542,9 @@ If LOUDLY is non-nil, print debug messages."
             (query-region `(,beg . ,end)))
         (tree-sitter-hl--extend-regions hl-region query-region)
         (setf `(,beg . ,end) hl-region)
-        (tsc--query-cursor-set-byte-range tree-sitter-hl--query-cursor
-                                          (position-bytes (car query-region))
-                                          (position-bytes (cdr query-region))))
+        (tsc-query-cursor-set-byte-range tree-sitter-hl--query-cursor
+                                         (position-bytes (car query-region))
+                                         (position-bytes (cdr query-region))))
       (let* ((root-node (tsc-root-node tree-sitter-tree))
              (captures  (tsc--query-cursor-captures-1
                          tree-sitter-hl--query-cursor
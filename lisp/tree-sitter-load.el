@@ -19,7 +19,8 @@
 (require 'tree-sitter-cli)
 
 (eval-when-compile
-  (require 'pcase))
+  (require 'pcase)
+  (require 'subr-x))
 
 (defvar tree-sitter-languages nil
   "An alist of mappings from language name symbols to language objects.
@@ -47,12 +48,17 @@ If another language was already registered under the same name, override it.
 This function returns the loaded language object.
 
 FILE should be the base name (without extension) of the native shared library
-that exports the language as the native symbol NATIVE-SYMBOL-NAME.
+that exports the language as the native symbol NATIVE-SYMBOL-NAME. It is
+searched for in `tree-sitter-load-path'. FILE can also be the absolute path to
+the shared library, in which case it is loaded as-is.
 
 If FILE is nil, the base name is assumed to be LANG-SYMBOL's name.
 
 If NATIVE-SYMBOL-NAME is nil, the name of the exported native symbol is assumed
-to be LANG-SYMBOL's name, prefixed with \"tree_sitter_\"."
+to be LANG-SYMBOL's name, prefixed with \"tree_sitter_\".
+
+See `tsc-load-language' for the errors signaled when the language's ABI version
+is not supported."
   (let* ((lang-name (symbol-name lang-symbol))
          ;; Example: c-sharp -> c_sharp.
          (fallback-name (replace-regexp-in-string "-" "_" lang-name))
@@ -68,15 +74,17 @@ to be LANG-SYMBOL's name, prefixed with \"tree_sitter_\"."
                   (cons lang-name
                         (unless (string= lang-name fallback-name)
                           (list fallback-name)))))
-         (full-path (seq-some (lambda (base-name)
-                               (locate-file base-name
-                                            tree-sitter-load-path
-                                            tree-sitter-load-suffixes))
-                             files)))
+         (full-path (if (and file (file-name-absolute-p file))
+                        (and (file-exists-p file) file)
+                      (seq-some (lambda (base-name)
+                                  (locate-file base-name
+                                               tree-sitter-load-path
+                                               tree-sitter-load-suffixes))
+                                files))))
     (unless full-path
       ;; TODO: Define custom error class.
       (error "Cannot find shared library for language: %S" lang-symbol))
-    (let ((language (tsc--load-language full-path native-symbol-name lang-symbol)))
+    (let ((language (tsc-load-language full-path native-symbol-name lang-symbol)))
       (setf (map-elt tree-sitter-languages lang-symbol) language)
       language)))
 
@@ -95,5 +103,72 @@ to be LANG-SYMBOL's name, prefixed with \"tree_sitter_\"."
   (or (alist-get lang-symbol tree-sitter-languages)
       (tree-sitter-load lang-symbol file native-symbol-name)))
 
+;;; ----------------------------------------------------------------------------
+;;; Query files.
+
+(defvar tree-sitter-query-path nil
+  "List of directories to search for query files.
+The query NAME for the language LANG-SYMBOL is looked up as the file
+LANG-SYMBOL/NAME.scm in each of these directories. This is the layout of e.g.
+nvim-treesitter's \"queries\" directory.")
+
+(defun tree-sitter--query-inherits (source)
+  "Return the languages that the query SOURCE inherits from.
+They are declared by `; inherits: LANG1,LANG2' comment lines at the top of
+SOURCE. Each element of the returned list has the form (LANG-SYMBOL . OPTIONAL).
+A language is optional if its name is wrapped in parentheses."
+  (with-temp-buffer
+    (insert source)
+    (goto-char (point-min))
+    (let ((inherits nil))
+      (while (looking-at "[ \t]*;+[ \t]*\\(?:inherits[ \t]*:\\(.*\\)\\)?")
+        (when-let ((names (match-string 1)))
+          (dolist (name (split-string names "," t "[ \t]+"))
+            (push (if (string-match "\\`(\\(.*\\))\\'" name)
+                      (cons (intern (match-string 1 name)) t)
+                    (cons (intern name) nil))
+                  inherits)))
+        (forward-line 1))
+      (nreverse inherits))))
+
+(defun tree-sitter--query-sources (lang-symbol name visited &optional optional)
+  "Return the sources of the query NAME for LANG-SYMBOL and its ancestors.
+Ancestors' sources come first, in the order they are inherited. Languages in the
+hash table VISITED are skipped, so that each source is included once. If
+OPTIONAL is non-nil, a missing query file is not an error."
+  (unless (gethash lang-symbol visited)
+    (puthash lang-symbol t visited)
+    (if-let ((file (locate-file (format "%s/%s.scm" lang-symbol name)
+                                tree-sitter-query-path)))
+        (let ((source (with-temp-buffer
+                        (insert-file-contents file)
+                        (buffer-string))))
+          (append (seq-mapcat (pcase-lambda (`(,parent . ,optional))
+                                (tree-sitter--query-sources parent name visited optional))
+                              (tree-sitter--query-inherits source))
+                  (list source)))
+      (unless optional
+        ;; TODO: Define custom error class.
+        (error "Cannot find %s query file for language: %S" name lang-symbol)))))
+
+(defun tree-sitter-query-source (lang-symbol name)
+  "Return the source of the query NAME, e.g. \"highlights\", for LANG-SYMBOL.
+The query file is searched for in `tree-sitter-query-path'. The files that it
+inherits from, through `; inherits: LANG1,LANG2' headers, are resolved
+recursively, and concatenated before it. Inherited languages whose names are
+wrapped in parentheses are optional, i.e. skipped if their query files are
+missing."
+  (mapconcat #'identity
+             (tree-sitter--query-sources lang-symbol name (make-hash-table :test #'eq))
+             "\n"))
+
+(defun tree-sitter-load-query (lang-symbol name &optional tag-assigner)
+  "Compile the query NAME for LANG-SYMBOL, from the query files it's composed of.
+See `tree-sitter-query-source'. The language is loaded if necessary, with
+`tree-sitter-require'. See `tsc-make-query' for the meaning of TAG-ASSIGNER."
+  (tsc-make-query (tree-sitter-require lang-symbol)
+                  (tree-sitter-query-source lang-symbol name)
+                  tag-assigner))
+
 (provide 'tree-sitter-load)
 ;;; tree-sitter-load.el ends here
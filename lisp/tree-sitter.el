@@ -142,8 +142,7 @@ OLD-LEN is the char length of the old text."
   (let ((old-tree tree-sitter-tree))
     (setq tree-sitter-tree
           ;; https://github.com/emacs-tree-sitter/elisp-tree-sitter/issues/3
-          (tsc--without-restriction
-            (tsc-parse-chunks tree-sitter-parser #'tsc--buffer-input old-tree)))
+          (tsc-parse-buffer tree-sitter-parser old-tree))
     (run-hook-with-args 'tree-sitter-after-change-functions old-tree)))
 
 (defun tree-sitter--setup ()
@@ -47,6 +47,25 @@
       (should-not (null (tsc-lang-node-type language (- type-count 1))))
       (should (null (tsc-lang-node-type language type-count))))))
 
+(ert-deftest language::node-type-vocabulary ()
+  (let* ((language (tree-sitter-require 'rust))
+         (node-types (tsc-lang-node-types language)))
+    (should (= (length node-types) (tsc-lang-count-types language)))
+    (ert-info ("Node types should be indexed by their numeric ids")
+      (dotimes (id (length node-types))
+        (should (equal (aref node-types id) (tsc-lang-node-type language id)))))
+    (ert-info ("Node types' named and visible flags should be exposed")
+      (let ((id (tsc-lang-node-type-id language 'identifier)))
+        (should (tsc-lang-node-type-named-p language id))
+        (should (tsc-lang-node-type-visible-p language id)))
+      (let ((id (tsc-lang-node-type-id language "if")))
+        (should-not (tsc-lang-node-type-named-p language id))
+        (should (tsc-lang-node-type-visible-p language id))))
+    (ert-info ("Invisible node types should be keywords")
+      (dotimes (id (length node-types))
+        (should (eq (keywordp (aref node-types id))
+                    (not (tsc-lang-node-type-visible-p language id))))))))
+
 (ert-deftest language::fields ()
   (let* ((language (tree-sitter-require 'rust))
          (field-count (tsc-lang-count-fields language)))
@@ -73,9 +92,186 @@
                         body: (block))))))))
 
 (ert-deftest parsing::without-setting-language ()
-  (ert-skip "Need to distinguish between this and timeout/cancellation")
   (let ((parser (tsc-make-parser)))
-    (should-error (tsc-parse-string parser "fn foo() {}") :type 'rust-panic)))
+    (should-error (tsc-parse-string parser "fn foo() {}") :type 'tsc-parse-no-language)))
+
+(ert-deftest parsing::timeout ()
+  (tsc-test-with rust parser
+    (let ((code (tsc-test-with-file "data/types.rs"
+                  (let ((text (buffer-string)))
+                    (apply #'concat (make-list 50 text))))))
+      (should (= 0 (tsc-parse-timeout parser)))
+      (tsc-set-parse-timeout parser 1)
+      (should (= 1 (tsc-parse-timeout parser)))
+      (should-error (tsc-parse-string parser code) :type 'tsc-parse-timeout)
+      (ert-info ("Parsing should resume after a timeout")
+        (tsc-set-parse-timeout parser nil)
+        (should (tsc-tree-p (tsc-parse-string parser code))))
+      (ert-info ("Parsing should restart after a timeout, if the parser was reset")
+        (tsc-set-parse-timeout parser 1)
+        (should-error (tsc-parse-string parser code) :type 'tsc-parse-timeout)
+        (tsc-reset-parser parser)
+        (tsc-set-parse-timeout parser 0)
+        (should (equal (read (tsc-tree-to-sexp (tsc-parse-string parser "fn foo() {}")))
+                       '(source_file
+                         (function_item
+                          name: (identifier)
+                          parameters: (parameters)
+                          body: (block)))))))))
+
+(ert-deftest parsing::keep-old-tree ()
+  (tsc-test-with rust parser
+    (let* ((code (tsc-test-with-file "data/types.rs"
+                   (let ((text (buffer-string)))
+                     (apply #'concat (make-list 50 text)))))
+           (input (lambda (bytepos _line _column)
+                    (when (<= bytepos (length code))
+                      (substring code (1- bytepos) (min (length code) (+ bytepos 1023))))))
+           (old-tree (tsc-parse-string parser "fn foo() {}"))
+           (tsc-last-parse-failure 'unset))
+      (tsc-set-parse-timeout parser 1)
+      (should-error (tsc-parse-chunks parser input old-tree) :type 'tsc-parse-timeout)
+      (tsc-reset-parser parser)
+      (should (= (tsc-tree-generation old-tree)
+                 (tsc-tree-generation (tsc-parse-chunks parser input old-tree :keep))))
+      (should (eq tsc-last-parse-failure 'tsc-parse-timeout))
+      (ert-info ("A successful parse should clear the failure")
+        (tsc-reset-parser parser)
+        (tsc-set-parse-timeout parser nil)
+        (let ((tree (tsc-parse-chunks parser input old-tree :keep)))
+          (should (> (tsc-tree-generation tree) (tsc-tree-generation old-tree)))
+          (should-not tsc-last-parse-failure))))))
+
+(ert-deftest parsing::utf16 ()
+  (tsc-test-with rust parser
+    ;; "😀" is 1 code point, 2 UTF-16 code units, and 4 UTF-8 bytes.
+    (let* ((code "fn f() { \"😀\"; }\nfn g() {}")
+           (tree (tsc-parse-string-utf16 parser code))
+           (root (tsc-root-node tree))
+           (g (tsc-get-child-by-field (tsc-get-nth-child root 1) :name)))
+      (should (equal (read (tsc-tree-to-sexp tree))
+                     (read (tsc-tree-to-sexp (tsc-parse-string parser code)))))
+      (should (equal (tsc-node-utf16-range root) '(0 . 26)))
+      (should (equal (tsc-node-utf16-range g) '(20 . 21)))
+      (should (equal (tsc-node-utf16-start-point g) '(2 . 3)))
+      (should (equal (tsc-node-utf16-end-point (tsc-get-nth-child root 0)) '(1 . 16))))))
+
+(ert-deftest parsing::cancellation ()
+  (tsc-test-with rust parser
+    (let ((token (tsc-make-cancellation-token)))
+      (should (tsc-cancellation-token-p token))
+      (tsc-set-cancellation-token parser token)
+      (tsc-cancel-token token)
+      (should (tsc-token-cancelled-p token))
+      (should-error (tsc-parse-string parser "fn foo() {}") :type 'tsc-parse-cancelled)
+      (tsc-reset-cancellation-token token)
+      (should-not (tsc-token-cancelled-p token))
+      (should (tsc-tree-p (tsc-parse-string parser "fn foo() {}")))
+      (ert-info ("A non-local exit from the input function should abort parsing")
+        (with-temp-buffer
+          (insert "fn foo() {}")
+          (should (eq 'interrupted
+                      (catch 'input
+                        (tsc-parse-chunks parser
+                                          (lambda (&rest _) (throw 'input 'interrupted))
+                                          nil))))
+          (should-not (tsc-token-cancelled-p token))
+          (should (tsc-tree-p (tsc-parse-chunks parser #'tsc--buffer-input nil)))
+          (ert-info ("A cancellation requested by the input function should be kept")
+            (should (eq 'interrupted
+                        (catch 'input
+                          (tsc-parse-chunks parser
+                                            (lambda (&rest _)
+                                              (tsc-cancel-token token)
+                                              (throw 'input 'interrupted))
+                                            nil))))
+            (should (tsc-token-cancelled-p token))
+            (tsc-reset-cancellation-token token))))
+      (ert-info ("Detaching the token should make it ineffective")
+        (tsc-set-cancellation-token parser nil)
+        (tsc-cancel-token token)
+        (should (tsc-tree-p (tsc-parse-string parser "fn foo() {}")))))))
+
+(ert-deftest parsing::included-ranges ()
+  (tsc-test-with rust parser
+    (let ((code "<? fn foo() {} ?>"))
+      (tsc-set-included-ranges parser [[4 15 (1 . 3) (1 . 14)]])
+      (let* ((tree (tsc-parse-string parser code))
+             (root (tsc-root-node tree)))
+        (should (equal (read (tsc-tree-to-sexp tree))
+                       '(source_file
+                         (function_item
+                          name: (identifier)
+                          parameters: (parameters)
+                          body: (block)))))
+        (ert-info ("Positions should be relative to the whole document")
+          (should (equal (tsc-node-byte-range (tsc-get-nth-child root 0)) '(4 . 15)))))
+      (ert-info ("Empty ranges should make the parser include the whole document")
+        (tsc-set-included-ranges parser [])
+        (should (tsc-node-has-error-p (tsc-root-node (tsc-parse-string parser code)))))
+      (ert-info ("Overlapping ranges should be rejected")
+        (should-error (tsc-set-included-ranges parser [[4 15 (1 . 3) (1 . 14)]
+                                                       [1 5 (1 . 0) (1 . 4)]])
+                      :type 'tsc-invalid-ranges)))))
+
+(ert-deftest parsing::registry ()
+  (let ((registry (tsc-make-parser-registry))
+        (rust (tree-sitter-require 'rust)))
+    (should (tsc-parser-registry-p registry))
+    (tsc-registry-register registry 'host (tree-sitter-require 'javascript))
+    (tsc-registry-register registry 'embedded rust [[4 15 (1 . 3) (1 . 14)]])
+    (should (equal (tsc-registry-keys registry) '(host embedded)))
+    (should (eq 'rust (tsc--lang-symbol (tsc-registry-language registry 'embedded))))
+    (should (equal (tsc-registry-ranges registry 'host) []))
+    (should-not (tsc-registry-tree registry 'embedded))
+    (with-temp-buffer
+      (insert "<? fn foo() {} ?>")
+      (let ((trees (tsc-registry-parse-buffer registry)))
+        (should (equal (mapcar #'car trees) '(host embedded)))
+        (should (equal (read (tsc-tree-to-sexp (cdr (assq 'embedded trees))))
+                       '(source_file
+                         (function_item
+                          name: (identifier)
+                          parameters: (parameters)
+                          body: (block)))))
+        (should (= (tsc-tree-generation (cdr (assq 'embedded trees)))
+                   (tsc-tree-generation (tsc-registry-tree registry 'embedded))))))
+    (ert-info ("The innermost language should be found by position")
+      (should (eq 'embedded (tsc-registry-key-at registry 5)))
+      (should (eq 'host (tsc-registry-key-at registry 1))))
+    (ert-info ("Ranges should be updatable")
+      (tsc-registry-set-ranges registry 'embedded [])
+      (should (eq 'host (tsc-registry-key-at registry 5)))
+      (should-error (tsc-registry-set-ranges registry 'embedded [[4 15 (1 . 3) (1 . 14)]
+                                                                 [1 5 (1 . 0) (1 . 4)]])
+                    :type 'tsc-invalid-ranges))
+    (ert-info ("Unknown keys should be rejected")
+      (should-error (tsc-registry-tree registry 'missing) :type 'tsc-registry-unknown-key)
+      (should (tsc-registry-unregister registry 'embedded))
+      (should-not (tsc-registry-unregister registry 'embedded))
+      (should (equal (tsc-registry-keys registry) '(host))))))
+
+(ert-deftest query::registry-captures ()
+  (let* ((registry (tsc-make-parser-registry))
+         (rust (tree-sitter-require 'rust))
+         (query (tsc-make-query rust [(identifier) @name (parameters) @parameters])))
+    (tsc-registry-register registry 'host (tree-sitter-require 'javascript))
+    (tsc-registry-register registry 'embedded rust [[4 15 (1 . 3) (1 . 14)]])
+    (with-temp-buffer
+      (insert "<? fn foo() {} ?>")
+      (tsc-registry-parse-buffer registry '(host embedded))
+      (tsc-registry-register registry 'unparsed rust)
+      (let ((captures (tsc-registry-query-captures
+                       registry `((unparsed . ,query) (embedded . ,query)))))
+        (should (equal (mapcar (lambda (capture)
+                                 (list (car capture) (cadr capture)
+                                       (tsc-node-position-range (cddr capture))))
+                               captures)
+                       '((embedded name (7 . 10))
+                         (embedded parameters (10 . 12))))))
+      (ert-info ("Captures should be limited to the given region")
+        (should (= 1 (length (tsc-registry-query-captures
+                              registry `((embedded . ,query)) 10 12))))))))
 
 (ert-deftest parsing::rust-buffer ()
   (tsc-test-with rust parser
@@ -93,6 +289,368 @@
           (ert-info ("Incremental parsing should be faster than initial")
             (should (> (car initial) (car reparse)))))))))
 
+(ert-deftest parsing::chunks ()
+  (tsc-test-with rust parser
+    (let* ((chunks ["fn fo" "o() " "{}"])
+           (code (mapconcat #'identity chunks ""))
+           (starts '()))
+      (cl-flet ((input (bytepos _line-number _byte-column)
+                  ;; Return the chunk containing BYTEPOS, or nil at the end.
+                  (push bytepos starts)
+                  (let ((offset (1- bytepos)))
+                    (cl-loop for chunk across chunks
+                             if (< offset (length chunk))
+                             return (substring chunk offset)
+                             else do (cl-decf offset (length chunk))))))
+        (should (equal (tsc-tree-to-sexp (tsc-parse-chunks parser #'input nil))
+                       (tsc-tree-to-sexp (tsc-parse-string parser code))))
+        (ert-info ("The input function should have been asked for text at the end")
+          (should (memq 12 starts)))))))
+
+(ert-deftest parsing::buffer ()
+  (tsc-test-with rust parser
+    (with-temp-buffer
+      ;; Large enough to span multiple chunks, with multi-byte characters straddling the chunk
+      ;; boundaries.
+      (dotimes (i 5000)
+        (insert (format "fn f%d() { let s = \"héllo wörld\"; }\n" i)))
+      (narrow-to-region 10 20)
+      (let ((tree (tsc-parse-buffer parser))
+            (expected (tsc--without-restriction
+                        (tsc-parse-chunks parser #'tsc--buffer-input nil))))
+        (should (equal (tsc-tree-to-sexp tree) (tsc-tree-to-sexp expected)))
+        (should-not (tsc-node-has-error-p (tsc-root-node tree)))
+        (should (= (tsc-node-end-byte (tsc-root-node tree))
+                   (tsc--without-restriction (position-bytes (point-max)))))
+        (ert-info ("Parsing from another buffer")
+          (let ((buffer (current-buffer)))
+            (with-temp-buffer
+              (should (equal (tsc-tree-to-sexp (tsc-parse-buffer parser nil buffer))
+                             (tsc-tree-to-sexp tree))))))))))
+
+(ert-deftest parsing::logger ()
+  (tsc-test-with rust parser
+    (let ((types nil))
+      (tsc-set-logger parser (lambda (type message)
+                               (should (stringp message))
+                               (cl-pushnew type types)))
+      (tsc-parse-string parser "fn foo() {}")
+      (should (equal (sort types #'string<) '(:lex :parse)))
+      (ert-info ("Disabling the logger")
+        (setq types nil)
+        (tsc-set-logger parser nil)
+        (tsc-parse-string parser "fn foo() {}")
+        (should (null types))))
+    (ert-info ("Logging to a buffer")
+      (let ((buffer (get-buffer-create "*tsc-log*")))
+        (unwind-protect
+            (progn
+              (with-current-buffer buffer (erase-buffer))
+              (tsc-set-logger parser t)
+              (tsc-parse-string parser "fn foo() {}")
+              (tsc-set-logger parser nil)
+              (with-current-buffer buffer
+                (should (> (buffer-size) 0))))
+          (kill-buffer buffer))))))
+
+(ert-deftest parsing::print-dot-graphs ()
+  (unless (fboundp 'tsc--print-dot-graphs)
+    (ert-skip "DOT graph output is not supported on this system"))
+  (tsc-test-with rust parser
+    (let ((file (make-temp-file "tsc-test-" nil ".dot")))
+      (unwind-protect
+          (progn
+            (tsc-parser-print-dot-graphs parser file)
+            (tsc-parse-string parser "fn foo() {}")
+            (tsc-parser-print-dot-graphs parser nil)
+            (with-temp-buffer
+              (insert-file-contents file)
+              (should (string-match-p "digraph" (buffer-string)))))
+        (delete-file file)))))
+
+(ert-deftest parsing::background ()
+  (tsc-test-with rust parser
+    (tsc-test-with-file "data/types.rs"
+      (let* ((text (buffer-string))
+             (language (tsc-parser-language parser))
+             (result 'pending)
+             (job (tsc-parse-in-background language text (lambda (tree) (setq result tree)))))
+        (should (tsc-parse-job-p job))
+        (with-timeout (10 (ert-fail "Background parsing took too long"))
+          (while (eq result 'pending)
+            (accept-process-output nil 0.01)))
+        (should (tsc-tree-p result))
+        (should (equal (tsc-tree-to-sexp result)
+                       (tsc-tree-to-sexp (tsc-parse-string parser text))))))))
+
+(ert-deftest parsing::edit-tree ()
+  (tsc-test-with rust parser
+    (with-temp-buffer
+      (insert "fn foo() {}")
+      (let* ((old-tree (tsc-parse-chunks parser #'tsc--buffer-input nil))
+             (body (tsc-get-child-by-field
+                    (tsc-get-nth-child (tsc-root-node old-tree) 0) :body))
+             (new-tree))
+        (should (equal (tsc-node-position-range body) '(10 . 12)))
+        (goto-char 8)
+        (insert "x: u8")
+        (tsc-edit-tree old-tree 8 8 13 '(1 . 7) '(1 . 7) '(1 . 12))
+        (ert-info ("Editing should shift the positions of nodes after the edit")
+          (should (equal (tsc-node-position-range
+                          (tsc-get-child-by-field
+                           (tsc-get-nth-child (tsc-root-node old-tree) 0) :body))
+                         '(15 . 17))))
+        (setq new-tree (tsc-parse-chunks parser #'tsc--buffer-input old-tree))
+        (should (equal (read (tsc-tree-to-sexp new-tree))
+                       '(source_file
+                         (function_item
+                          name: (identifier)
+                          parameters: (parameters
+                                       (parameter pattern: (identifier)
+                                                  type: (primitive_type)))
+                          body: (block)))))
+        (should (< 0 (length (tsc-changed-ranges old-tree new-tree))))))))
+
+(ert-deftest parsing::strings ()
+  (tsc-test-with rust parser
+    (let ((expected '(source_file
+                      (function_item
+                       name: (identifier)
+                       parameters: (parameters)
+                       body: (block (string_literal))))))
+      (should (equal (read (tsc-tree-to-sexp
+                            (tsc-parse-strings parser '("fn fo" "" "o() {" " \"ả\" }"))))
+                     expected))
+      (should (equal (read (tsc-tree-to-sexp
+                            (tsc-parse-strings parser ["fn foo() { \"ả\" }"])))
+                     expected))
+      (ert-info ("Empty input should produce an empty tree")
+        (should (equal (read (tsc-tree-to-sexp (tsc-parse-strings parser nil)))
+                       '(source_file)))))))
+
+(ert-deftest parsing::region ()
+  (tsc-test-with rust parser
+    (with-temp-buffer
+      (insert "<?php\nfn foo() {}\n?>")
+      (narrow-to-region 7 18)
+      (let* ((tree (tsc-parse-region parser (point-min) (point-max)))
+             (function (tsc-get-nth-child (tsc-root-node tree) 0)))
+        (should (equal (read (tsc-tree-to-sexp tree))
+                       '(source_file
+                         (function_item
+                          name: (identifier)
+                          parameters: (parameters)
+                          body: (block)))))
+        (ert-info ("Positions should be relative to the whole buffer")
+          (should (equal (tsc-node-position-range function) '(7 . 18)))
+          (should (equal (tsc-node-start-point function) '(2 . 0))))
+        (ert-info ("The parser should include whole documents afterward")
+          (should (tsc-node-has-error-p (tsc-root-node (tsc-parse-buffer parser)))))
+        (ert-info ("The parser's own included ranges should be reset afterward")
+          (tsc-set-included-ranges parser [[7 18 (2 . 0) (2 . 11)]])
+          (tsc-parse-region parser 1 6)
+          (should (tsc-node-has-error-p (tsc-root-node (tsc-parse-buffer parser)))))))))
+
+(ert-deftest parsing::reuse-stats ()
+  (tsc-test-with rust parser
+    (tsc-test-with-file "data/types.rs"
+      (let* ((old-tree (tsc-parse-buffer parser))
+             (node-count (plist-get (tsc-tree-stats old-tree) :node-count)))
+        (goto-char (point-max))
+        (insert "\nfn foo() {}")
+        (let* ((beg (- (point-max) 12))
+               (beg:point (tsc-point-from-position beg))
+               (end:point (tsc-point-from-position (point-max)))
+               (copy (tsc-clone-tree old-tree)))
+          (tsc-edit-tree old-tree (position-bytes beg) (position-bytes beg)
+                         (position-bytes (point-max)) beg:point beg:point end:point)
+          (let ((stats (tsc-reuse-stats old-tree (tsc-parse-buffer parser old-tree))))
+            (ert-info ("Most nodes should be reused after a small, correctly recorded edit")
+              (should (> (plist-get stats :reused-count) (/ node-count 2)))
+              (should (< (plist-get stats :new-count) (/ node-count 2)))
+              (should (= 1 (plist-get stats :changed-range-count)))
+              (should (< 0 (plist-get stats :changed-byte-count)))))
+          (ert-info ("A fresh parse should reuse nothing")
+            (let ((stats (tsc-reuse-stats copy (tsc-parse-buffer (tsc-test-make-parser 'rust)))))
+              (should (= 0 (plist-get stats :reused-count))))))))))
+
+(ert-deftest parsing::edit-batch ()
+  (tsc-test-with rust parser
+    (with-temp-buffer
+      (insert "fn foo() {}")
+      (let ((tree (tsc-parse-buffer parser))
+            (batch (tsc-make-edit-batch)))
+        (should (tsc-edit-batch-p batch))
+        (ert-info ("Consecutive edits should be merged")
+          (tsc-edit-batch-record batch 8 8 9 '(1 . 7) '(1 . 7) '(1 . 8))
+          (tsc-edit-batch-record batch 9 9 11 '(1 . 8) '(1 . 8) '(1 . 10))
+          (tsc-edit-batch-record batch 10 11 10 '(1 . 9) '(1 . 10) '(1 . 9))
+          (should (equal (tsc-edit-batch-edits batch)
+                         [[8 8 10 (1 . 7) (1 . 7) (1 . 9)]])))
+        (ert-info ("Distant edits should be kept separate")
+          (tsc-edit-batch-record batch 1 1 2 '(1 . 0) '(1 . 0) '(1 . 1))
+          (should (= 2 (tsc-edit-batch-count batch)))
+          (tsc-edit-batch-clear batch)
+          (should (= 0 (tsc-edit-batch-count batch))))
+        (goto-char 8)
+        (insert "x")
+        (tsc-edit-batch-record batch 8 8 9 '(1 . 7) '(1 . 7) '(1 . 8))
+        (insert ": u8")
+        (tsc-edit-batch-record batch 9 9 13 '(1 . 8) '(1 . 8) '(1 . 12))
+        (should (= 1 (tsc-edit-batch-count batch)))
+        (let ((new-tree (tsc-edit-batch-flush batch tree parser)))
+          (should (= 0 (tsc-edit-batch-count batch)))
+          (should (equal (read (tsc-tree-to-sexp new-tree))
+                         '(source_file
+                           (function_item
+                            name: (identifier)
+                            parameters: (parameters
+                                         (parameter pattern: (identifier)
+                                                    type: (primitive_type)))
+                            body: (block)))))
+          (ert-info ("Flushing without pending edits should not re-parse")
+            (should (eq new-tree (tsc-edit-batch-flush batch new-tree parser)))))))))
+
+(ert-deftest parsing::changed-ranges ()
+  (tsc-test-with rust parser
+    (with-temp-buffer
+      (insert "fn foo() {}\nfn bar() {}")
+      (let ((old-tree (tsc-parse-chunks parser #'tsc--buffer-input nil))
+            (new-tree))
+        (goto-char 8)
+        (insert "x: u8")
+        (tsc-edit-tree old-tree 8 8 13 '(1 . 7) '(1 . 7) '(1 . 12))
+        (setq new-tree (tsc-parse-chunks parser #'tsc--buffer-input old-tree))
+        (let ((ranges (tsc-changed-ranges old-tree new-tree)))
+          (should (< 0 (length ranges)))
+          (ert-info ("Changed ranges should be limited to the edited parameter list")
+            (seq-doseq (range ranges)
+              (pcase-let ((`[,beg ,end ,_ ,_] range))
+                (should (<= 7 beg))
+                (should (<= end 14))))))))))
+
+(ert-deftest parsing::root-node-with-offset ()
+  (tsc-test-with rust parser
+    (let* ((tree (tsc-parse-string parser "fn foo() {}"))
+           (root (tsc-root-node-with-offset tree 101 '(5 . 4)))
+           (name (tsc-get-child-by-field (tsc-get-nth-child root 0) :name)))
+      (should (equal (tsc-node-position-range root) '(101 . 112)))
+      (should (equal (tsc-node-position-range name) '(104 . 107)))
+      (should (equal (tsc-node-start-point name) '(5 . 7)))
+      (ert-info ("The original tree should not be shifted")
+        (should (equal (tsc-node-position-range (tsc-root-node tree)) '(1 . 12))))
+      (ert-info ("The shifted copy should not be stamped with a generation")
+        (should (integerp (tsc-tree-generation tree)))
+        (should (null (tsc-tree-generation root)))))))
+
+(ert-deftest parsing::clone-tree ()
+  (tsc-test-with rust parser
+    (let* ((tree (tsc-parse-string parser "fn foo() {}"))
+           (snapshot (tsc-clone-tree tree)))
+      (should (tsc-tree-p snapshot))
+      (should (equal (tsc-tree-to-sexp snapshot) (tsc-tree-to-sexp tree)))
+      (tsc-edit-tree tree 1 1 5 '(1 . 0) '(1 . 0) '(1 . 4))
+      (should (equal (tsc-node-position-range (tsc-root-node tree)) '(5 . 16)))
+      (ert-info ("Editing a tree should not affect its clones")
+        (should (equal (tsc-node-position-range (tsc-root-node snapshot)) '(1 . 12)))))))
+
+(ert-deftest parsing::tree-cache ()
+  (tsc-test-lang-with-file rust "data/types.rs"
+    (let ((file (make-temp-file "tsc-test-" nil ".cache"))
+          (key (secure-hash 'sha256 (current-buffer)))
+          (language (tsc-tree-language tree-sitter-tree))
+          expected actual cached)
+      (unwind-protect
+          (progn
+            (tsc-save-tree-cache tree-sitter-tree file key)
+            (should (null (tsc-load-tree-cache language file "other key")))
+            (should (null (tsc-load-tree-cache language (concat file ".missing") key)))
+            (setq cached (tsc-load-tree-cache language file key))
+            (should (tsc-cached-tree-p cached))
+            (should (eq (tsc--lang-symbol language)
+                        (tsc--lang-symbol (tsc-cached-tree-language cached))))
+            (tsc-traverse-mapc (lambda (props) (push (append props nil) expected))
+                               tree-sitter-tree
+                               [:type :field :named-p :extra-p :missing-p
+                                :start-byte :end-byte :start-point :end-point :depth])
+            (tsc-cached-tree-mapc (lambda (props) (push (append props nil) actual)) cached)
+            (should (= (length expected) (tsc-cached-tree-node-count cached)))
+            (should (equal expected actual)))
+        (delete-file file)))))
+
+(ert-deftest parsing::tree-diff ()
+  (tsc-test-with rust parser
+    (cl-flet ((summarize (changes)
+                (mapcar (lambda (change)
+                          (cons (car change) (mapcar #'tsc-node-type (cdr change))))
+                        changes)))
+      (let* ((old-text "fn a() {}\nfn b() {}")
+             (new-text "fn a() {}\nstruct S;\nfn b() {}")
+             (old-tree (tsc-parse-string parser old-text))
+             (new-tree (tsc-parse-string parser new-text)))
+        (should (null (tsc-tree-diff old-tree old-tree)))
+        (ert-info ("Inserting a node should not make its following siblings appear changed")
+          (let ((changes (tsc-tree-diff old-tree new-tree old-text new-text)))
+            (should (equal (summarize changes) '((:inserted struct_item))))
+            (should (equal (tsc-node-byte-range (nth 1 (car changes))) '(11 . 20)))))
+        (ert-info ("Deletions are the reverse of insertions")
+          (should (equal (summarize (tsc-tree-diff new-tree old-tree))
+                         '((:deleted struct_item))))))
+      (let* ((old-text "fn a() {}")
+             (new-text "fn c() {}")
+             (old-tree (tsc-parse-string parser old-text))
+             (new-tree (tsc-parse-string parser new-text)))
+        (ert-info ("Leaves' text is only compared if the source code is given")
+          (should (null (tsc-tree-diff old-tree new-tree)))
+          (should (equal (summarize (tsc-tree-diff old-tree new-tree old-text new-text))
+                         '((:changed identifier identifier)))))))))
+
+(ert-deftest parsing::tree-stats ()
+  (tsc-test-lang-with-file rust "data/types.rs"
+    (let ((stats (tsc-tree-stats tree-sitter-tree)))
+      (should (= (plist-get stats :node-count)
+                 (tsc-traverse-fold (lambda (count _) (1+ count)) 0 tree-sitter-tree :type)))
+      (should (= (plist-get stats :named-node-count)
+                 (tsc-traverse-fold (lambda (count _) (1+ count)) 0 tree-sitter-tree :type
+                                    :named-only t)))
+      (should (= (plist-get stats :max-depth)
+                 (tsc-traverse-fold #'max 0 tree-sitter-tree :depth)))
+      (should (= 0 (plist-get stats :error-count)))
+      (should (= 0 (plist-get stats :missing-count)))))
+  (tsc-test-with rust parser
+    (should (= 1 (plist-get (tsc-tree-stats (tsc-parse-string parser "fn")) :error-count)))
+    (should (= 1 (plist-get (tsc-tree-stats (tsc-parse-string parser "let x = 1"))
+                            :missing-count)))))
+
+(ert-deftest parsing::tree-generation ()
+  (tsc-test-with rust parser
+    (let* ((old-tree (tsc-parse-string parser "fn foo() {}"))
+           (new-tree (tsc-parse-string parser "fn foo() {}"))
+           (generation (tsc-tree-generation old-tree)))
+      (should (integerp generation))
+      (should (< generation (tsc-tree-generation new-tree)))
+      (should (< (tsc-tree-generation new-tree) (tsc-tree-generation (tsc-clone-tree old-tree))))
+      (ert-info ("Nodes should have the generation of their trees")
+        (should (= generation (tsc-tree-generation (tsc-root-node old-tree)))))
+      (ert-info ("Editing should not change the generation")
+        (tsc-edit-tree old-tree 1 1 5 '(1 . 0) '(1 . 0) '(1 . 4))
+        (should (= generation (tsc-tree-generation old-tree)))))))
+
+(ert-deftest parsing::tree-language ()
+  (tsc-test-with rust parser
+    (with-temp-buffer
+      (insert "fn foo() {}")
+      (let* ((tree (tsc-parse-chunks parser #'tsc--buffer-input nil))
+             (language (tsc-tree-language tree)))
+        (should (tsc-language-p language))
+        (should (eq 'rust (tsc--lang-symbol language)))
+        (ert-info ("A tree's language should be usable to compile queries for it")
+          (let ((query (tsc-make-query language [(function_item name: (identifier) @name)])))
+            (should (equal (mapcar #'car (tsc-query-captures
+                                          query (tsc-root-node tree)
+                                          #'tsc--buffer-substring-no-properties))
+                           '(name)))))))))
+
 (ert-deftest minor-mode::basic-editing ()
   (with-temp-buffer
     (tsc-test-use-lang 'rust)
@@ -162,6 +720,81 @@
       (should (tsc-node-eq node1 node2))
       (should-not (tsc-node-eq node1 (tsc-get-nth-child node1 0))))))
 
+(ert-deftest node::id ()
+  (tsc-test-with rust parser
+    (let* ((tree (tsc-parse-string parser "fn foo() {}"))
+           (root (tsc-root-node tree))
+           (func (tsc-get-nth-child root 0)))
+      (should (= (tsc-node-id root) (tsc-node-id (tsc-root-node tree))))
+      (should (= (tsc-node-id func) (tsc-node-id (tsc-get-parent (tsc-get-nth-child func 1)))))
+      (should-not (= (tsc-node-id root) (tsc-node-id func))))))
+
+(ert-deftest node::structural-equality ()
+  (tsc-test-with rust parser
+    (let ((root1 (tsc-root-node (tsc-parse-string parser "fn foo(x: u8) {}")))
+          (root2 (tsc-root-node (tsc-parse-string parser "\n\nfn  bar( y:u8 ) {}")))
+          (root3 (tsc-root-node (tsc-parse-string parser "fn foo(x: u8, y: u8) {}"))))
+      (should (tsc-node-structurally-equal-p root1 root2))
+      (should-not (tsc-node-structurally-equal-p root1 root3))
+      (ert-info ("Nodes of different types should not be equal")
+        (should-not (tsc-node-structurally-equal-p root1 (tsc-get-nth-child root1 0)))))))
+
+(ert-deftest node::pretty-sexp ()
+  (tsc-test-with rust parser
+    (let ((root (tsc-root-node (tsc-parse-string parser "fn foo() {}"))))
+      (should (equal (read (tsc-node-to-pretty-sexp root nil nil t))
+                     (read (tsc-node-to-sexp root))))
+      (should (equal (tsc-node-to-pretty-sexp root)
+                     (concat "(source_file\n"
+                             "  (function_item\n"
+                             "    (identifier)\n"
+                             "    (parameters)\n"
+                             "    (block)))")))
+      (should (equal (tsc-node-to-pretty-sexp root 1 t)
+                     (concat "(source_file [1-12]\n"
+                             "  (function_item [1-12] ...))")))
+      (ert-info ("Siblings should be written after deeper nodes are closed")
+        (should (equal (tsc-node-to-pretty-sexp
+                        (tsc-root-node (tsc-parse-string parser "fn foo(x: u8) {}\nstruct S;"))
+                        nil nil t)
+                       (concat "(source_file\n"
+                               "  (function_item\n"
+                               "    name: (identifier)\n"
+                               "    parameters: (parameters\n"
+                               "      (parameter\n"
+                               "        pattern: (identifier)\n"
+                               "        type: (primitive_type)))\n"
+                               "    body: (block))\n"
+                               "  (struct_item\n"
+                               "    name: (type_identifier)))")))))))
+
+(ert-deftest node::json ()
+  (require 'json)
+  (tsc-test-with rust parser
+    (let* ((root (tsc-root-node (tsc-parse-string parser "fn foo() {}")))
+           (json-object-type 'alist)
+           (json-array-type 'list)
+           (json (json-read-from-string (tsc-node-to-json root)))
+           (func (car (alist-get 'children json)))
+           (name (nth 1 (alist-get 'children func))))
+      (should (equal "source_file" (alist-get 'type json)))
+      (should (equal 12 (alist-get 'end_byte json)))
+      (should (equal "function_item" (alist-get 'type func)))
+      (should (equal 4 (length (alist-get 'children func))))
+      (should (equal "identifier" (alist-get 'type name)))
+      (should (equal "name" (alist-get 'field name)))
+      (should (equal '(1 3) (alist-get 'start_point name)))
+      (should (equal (tsc-node-start-byte name) (alist-get 'start_byte name)))
+      (ert-info ("Anonymous nodes should be omitted if requested")
+        (let* ((json (json-read-from-string (tsc-node-to-json root t)))
+               (func (car (alist-get 'children json))))
+          (should (equal 3 (length (alist-get 'children func))))
+          (ert-info ("A node whose children are all anonymous should be followed by its siblings")
+            (should (equal '("identifier" "parameters" "block")
+                           (mapcar (lambda (child) (alist-get 'type child))
+                                   (alist-get 'children func))))
+            (should (null (alist-get 'children (nth 1 (alist-get 'children func)))))))))))
+
 (ert-deftest node::using-without-tree ()
   "Test that a tree's nodes are still usable after no direct reference to the
 tree is held (since nodes internally reference the tree)."
@@ -196,6 +829,217 @@ tree is held (since nodes internally reference the tree)."
           (should (equal (tsc-node-type semi) ";")))
         (should (tsc-node-missing-p semi))))))
 
+(ert-deftest node::text ()
+  (let ((buffer (generate-new-buffer "*tsc-test*")))
+    (unwind-protect
+        (let (root)
+          (with-current-buffer buffer
+            (insert "fn foo() { \"héllo wörld\" }")
+            (tsc-test-use-lang 'rust)
+            (setq root (tsc-root-node tree-sitter-tree))
+            (ert-info ("Narrowing should not matter")
+              (narrow-to-region 1 3)
+              (should (equal (tsc-node-text root) "fn foo() { \"héllo wörld\" }"))
+              (widen)))
+          (ert-info ("Text should be read from the given buffer")
+            (with-temp-buffer
+              (let ((string (tsc-traverse-fold
+                             (lambda (found node)
+                               (or found
+                                   (and (eq (tsc-node-type node) 'string_literal) node)))
+                             nil root)))
+                (should (equal (tsc-node-text string buffer) "\"héllo wörld\""))))))
+      (kill-buffer buffer)))
+  (ert-info ("Text should be extracted from the string that was parsed")
+    (tsc-test-with rust parser
+      (let* ((source "fn foo() { \"héllo wörld\" }")
+             (root (tsc-root-node (tsc-parse-string parser source)))
+             (string (tsc-traverse-fold
+                      (lambda (found node)
+                        (or found
+                            (and (eq (tsc-node-type node) 'string_literal) node)))
+                      nil root)))
+        (should (equal (tsc-node-text string source) "\"héllo wörld\""))
+        (should-error (tsc-node-text root "fn") :type 'tsc-error)))))
+
+(ert-deftest node::children ()
+  (tsc-test-with rust parser
+    (let* ((root (tsc-root-node (tsc-parse-string parser "fn foo(x: u8, y: u8) {}")))
+           (params (tsc-get-descendant-for-byte-range root 7 21))
+           (children (tsc-node-children params))
+           (named-children (tsc-node-named-children params)))
+      (should (eq 'parameters (tsc-node-type params)))
+      (should (= (length children) (tsc-count-children params)))
+      (should (= (length named-children) (tsc-count-named-children params)))
+      (dotimes (i (length children))
+        (should (tsc-node-eq (aref children i) (tsc-get-nth-child params i))))
+      (dotimes (i (length named-children))
+        (should (tsc-node-eq (aref named-children i) (tsc-get-nth-named-child params i)))))))
+
+(ert-deftest node::children-with-fields ()
+  (tsc-test-with rust parser
+    (let* ((root (tsc-root-node (tsc-parse-string parser "fn foo() {}")))
+           (func (tsc-get-nth-child root 0))
+           (alist (tsc-node-children-with-fields func)))
+      (should (equal (mapcar #'car alist) '(nil :name :parameters :body)))
+      (should (equal (mapcar (lambda (pair) (tsc-node-type (cdr pair))) alist)
+                     '("fn" identifier parameters block)))
+      (should (tsc-node-eq (alist-get :name alist)
+                           (tsc-get-child-by-field func :name))))))
+
+(ert-deftest node::field-name-for-child ()
+  (tsc-test-with rust parser
+    (let* ((root (tsc-root-node (tsc-parse-string parser "fn foo() {}")))
+           (func (tsc-get-nth-child root 0)))
+      (should (null (tsc-field-name-for-child func 0)))
+      (should (eq :name (tsc-field-name-for-child func 1)))
+      (should (eq :body (tsc-field-name-for-child func 3)))
+      (should (null (tsc-field-name-for-child func 10))))))
+
+(ert-deftest node::descendant-for-range ()
+  (tsc-test-with rust parser
+    (let* ((root (tsc-root-node (tsc-parse-string parser "fn foo() {}")))
+           (name (tsc-get-descendant-for-byte-range root 4 7)))
+      (should (eq 'identifier (tsc-node-type name)))
+      (should (tsc-node-eq name (tsc-get-descendant-for-point-range root '(1 . 3) '(1 . 6))))
+      (ert-info ("Anonymous nodes should be skipped by the named variants")
+        (should (equal "(" (tsc-node-type (tsc-get-descendant-for-byte-range root 7 8))))
+        (should (eq 'parameters
+                    (tsc-node-type (tsc-get-named-descendant-for-byte-range root 7 8))))
+        (should (eq 'parameters
+                    (tsc-node-type (tsc-get-named-descendant-for-point-range
+                                    root '(1 . 6) '(1 . 7)))))))))
+
+(ert-deftest node::ancestor-of-type ()
+  (tsc-test-with rust parser
+    (let* ((root (tsc-root-node (tsc-parse-string parser "fn foo(x: u8) { if x { 1 } }")))
+           (one (tsc-get-descendant-for-byte-range root 24 25))
+           (func (tsc-get-nth-child root 0)))
+      (should (eq 'integer_literal (tsc-node-type one)))
+      (should (tsc-node-eq func (tsc-get-ancestor-of-type one 'function_item)))
+      (should (tsc-node-eq func (tsc-get-ancestor-of-type one '(struct_item function_item))))
+      (should (eq 'if_expression
+                  (tsc-node-type (tsc-get-ancestor-of-type one '(if_expression function_item)))))
+      (should (null (tsc-get-ancestor-of-type one 'struct_item)))
+      (ert-info ("MAX-DEPTH should limit the search")
+        (should (null (tsc-get-ancestor-of-type one 'function_item 2)))
+        (should (tsc-get-ancestor-of-type func 'source_file 1))))))
+
+(ert-deftest node::named-siblings ()
+  (tsc-test-with rust parser
+    (let* ((root (tsc-root-node (tsc-parse-string parser "fn foo(x: u8, y: u8) {}")))
+           (params (tsc-get-descendant-for-byte-range root 7 21))
+           (x (tsc-get-nth-named-child params 0))
+           (y (tsc-get-nth-named-child params 1)))
+      (ert-info ("Anonymous siblings should be skipped")
+        (should (equal "," (tsc-node-type (tsc-get-next-sibling x))))
+        (should (tsc-node-eq y (tsc-get-next-named-sibling x)))
+        (should (tsc-node-eq x (tsc-get-prev-named-sibling y))))
+      (should (null (tsc-get-prev-named-sibling x)))
+      (should (null (tsc-get-next-named-sibling y))))))
+
+(ert-deftest node::collect-error-nodes ()
+  (tsc-test-with rust parser
+    (ert-info ("A tree without errors")
+      (should (equal [] (tsc-collect-error-nodes (tsc-parse-string parser "fn foo() {}")))))
+    (ert-info ("ERROR nodes")
+      (let ((errors (tsc-collect-error-nodes (tsc-parse-string parser "fn"))))
+        (should (= 1 (length errors)))
+        (pcase-let ((`[,node ,beg ,end ,missing] (aref errors 0)))
+          (should (tsc-node-error-p node))
+          (should (= 1 beg))
+          (should (= 3 end))
+          (should (null missing)))))
+    (ert-info ("Missing nodes")
+      (let* ((root (tsc-root-node (tsc-parse-string parser "let x = 1")))
+             (errors (tsc-collect-error-nodes root)))
+        (should (= 1 (length errors)))
+        (pcase-let ((`[,node ,beg ,end ,missing] (aref errors 0)))
+          (should (tsc-node-missing-p node))
+          (should (= beg end))
+          (should (equal ";" missing)))))))
+
+(ert-deftest node::position-predicates ()
+  (tsc-test-with rust parser
+    (let* ((root (tsc-root-node (tsc-parse-string parser "fn foo() {}")))
+           (name (tsc-get-descendant-for-byte-range root 4 7)))
+      (should-not (tsc-node-contains-byte-p name 3))
+      (should (tsc-node-contains-byte-p name 4))
+      (should (tsc-node-contains-byte-p name 6))
+      (should-not (tsc-node-contains-byte-p name 7))
+      (should (tsc-node-contains-point-p name '(1 . 3)))
+      (should-not (tsc-node-contains-point-p name '(1 . 6)))
+      (should-not (tsc-node-contains-point-p name '(2 . 0)))
+      (should (tsc-node-overlaps-range-p name 1 5))
+      (should (tsc-node-overlaps-range-p name 6 10))
+      (should-not (tsc-node-overlaps-range-p name 1 4))
+      (should-not (tsc-node-overlaps-range-p name 7 10))
+      (ert-info ("Empty ranges")
+        (should (tsc-node-overlaps-range-p name 4 4))
+        (should-not (tsc-node-overlaps-range-p name 7 7))))))
+
+(ert-deftest node::edit ()
+  (tsc-test-with rust parser
+    (let* ((root (tsc-root-node (tsc-parse-string parser "fn foo() {}")))
+           (name (tsc-get-descendant-for-byte-range root 4 7)))
+      (should (equal '(4 . 7) (tsc-node-byte-range name)))
+      ;; Insert 2 bytes at the beginning.
+      (tsc-edit-node name 1 1 3 '(1 . 0) '(1 . 0) '(1 . 2))
+      (should (equal '(6 . 9) (tsc-node-byte-range name)))
+      (should (equal '(1 . 5) (tsc-node-start-point name)))
+      (ert-info ("Edits after the node should not affect it")
+        (tsc-edit-node name 12 12 20 '(1 . 11) '(1 . 11) '(1 . 19))
+        (should (equal '(6 . 9) (tsc-node-byte-range name)))))))
+
+(ert-deftest node::at-region ()
+  (tsc-test-with rust parser
+    (let ((tree (tsc-parse-string parser "fn foo(x: u8) { x }")))
+      (should (eq 'identifier (tsc-node-type (tsc-node-at-region tree 4 7))))
+      (should (eq 'identifier (tsc-node-type (tsc-node-at-region tree '(1 . 3) '(1 . 6)))))
+      (ert-info ("Anonymous nodes should be skipped")
+        (should (eq 'parameters (tsc-node-type (tsc-node-at-region tree 7 8)))))
+      (ert-info ("Regions spanning multiple nodes")
+        (should (eq 'function_item (tsc-node-type (tsc-node-at-region tree 5 18))))
+        (should (eq 'parameter (tsc-node-type (tsc-node-at-region tree 8 12)))))
+      (ert-info ("Nodes should only be searched within the given node")
+        (let ((params (tsc-node-at-region tree 7 8)))
+          (should (null (tsc-node-at-region params 1 3))))))))
+
+(ert-deftest node::path ()
+  (tsc-test-with rust parser
+    (let* ((tree (tsc-parse-string parser "fn foo(x: u8, y: u8) {}"))
+           (root (tsc-root-node tree))
+           (name (tsc-get-descendant-for-byte-range root 4 7))
+           (y (tsc-get-descendant-for-byte-range root 15 16)))
+      (should (null (tsc-node-path root)))
+      (should (equal '(0 :name) (tsc-node-path name)))
+      (should (equal '(0 :parameters 3 :pattern) (tsc-node-path y)))
+      (should (tsc-node-eq root (tsc-resolve-node-path tree nil)))
+      (should (tsc-node-eq y (tsc-resolve-node-path tree (tsc-node-path y))))
+      (should (null (tsc-resolve-node-path tree '(0 :name 0))))
+      (ert-info ("Paths should be resolvable in a re-parsed tree")
+        (let ((new-tree (tsc-parse-string parser "\n\nfn bar(x: u8, y: u8) {}")))
+          (should (equal '(3 . 14) (tsc-node-start-point
+                                    (tsc-resolve-node-path new-tree (tsc-node-path y))))))))))
+
+(ert-deftest node::lowest-common-ancestor ()
+  (tsc-test-with rust parser
+    (let* ((tree (tsc-parse-string parser "fn foo(x: u8, y: u8) {}"))
+           (root (tsc-root-node tree))
+           (x (tsc-get-descendant-for-byte-range root 8 9))
+           (y (tsc-get-descendant-for-byte-range root 15 16))
+           (params (tsc-get-descendant-for-byte-range root 7 21)))
+      (should (tsc-node-eq params (tsc-lowest-common-ancestor x y)))
+      (should (tsc-node-eq params (tsc-lowest-common-ancestor params y)))
+      (should (tsc-node-eq x (tsc-lowest-common-ancestor x x)))
+      (should (tsc-node-eq params (tsc-lowest-common-ancestor 8 15 tree)))
+      (should (tsc-node-eq (tsc-get-nth-child root 0) (tsc-lowest-common-ancestor 1 15 root)))
+      (ert-info ("Nodes from different trees have no common ancestor")
+        (should (null (tsc-lowest-common-ancestor
+                       x (tsc-root-node (tsc-parse-string parser "fn foo() {}")))))
+        (should (null (tsc-lowest-common-ancestor
+                       x (tsc-root-node (tsc-clone-tree tree)))))))))
+
 (ert-deftest cursor::walk ()
   (tsc-test-with rust parser
     (let* ((tree (tsc-parse-string parser "fn foo() {}"))
@@ -218,6 +1062,88 @@ tree is held (since nodes internally reference the tree)."
       (should-error (tsc-current-node cursor :depth))
       (should-error (tsc-current-node cursor [:depth])))))
 
+(ert-deftest cursor::movement ()
+  (tsc-test-with rust parser
+    (let* ((tree (tsc-parse-string parser "fn foo() {}"))
+           (cursor (tsc-make-cursor tree)))
+      (should (= 0 (tsc-cursor-depth cursor)))
+      (should (tsc-goto-first-child cursor))
+      (should (eq (tsc-current-node cursor :type) 'function_item))
+      (should (= 1 (tsc-cursor-depth cursor)))
+      (ert-info ("Should be able to get the index of the current node")
+        (should (tsc-goto-first-child cursor))
+        (should (equal (tsc-current-node cursor :type) "fn"))
+        (should (= 2 (tsc-cursor-depth cursor)))
+        (should (= 0 (tsc-current-child-index cursor)))
+        (should (null (tsc-current-named-child-index cursor)))
+        (should (tsc-goto-next-sibling cursor))
+        (should (eq (tsc-current-node cursor :type) 'identifier))
+        (should (= 1 (tsc-current-child-index cursor)))
+        (should (= 0 (tsc-current-named-child-index cursor))))
+      (ert-info ("Should be able to go to the child containing a point")
+        (tsc-goto-parent cursor)
+        (should (= 2 (tsc-goto-first-child-for-point cursor '(1 . 6))))
+        (should (eq (tsc-current-node cursor :type) 'parameters)))
+      (ert-info ("Should be able to go to the child associated with a field")
+        (tsc-goto-parent cursor)
+        (should (tsc-goto-child-by-field cursor :body))
+        (should (eq (tsc-current-node cursor :type) 'block))
+        (should (eq (tsc-current-field cursor) :body))
+        (tsc-goto-parent cursor)
+        (should-not (tsc-goto-child-by-field cursor :left))
+        (should (eq (tsc-current-node cursor :type) 'function_item)))
+      (ert-info ("Should be able to skip anonymous nodes")
+        (should (tsc-goto-first-named-child cursor))
+        (should (eq (tsc-current-node cursor :type) 'identifier))
+        (should (tsc-goto-next-named-sibling cursor))
+        (should (eq (tsc-current-node cursor :type) 'parameters))
+        (should (tsc-goto-next-named-sibling cursor))
+        (should (eq (tsc-current-node cursor :type) 'block))
+        (should-not (tsc-goto-next-named-sibling cursor))
+        (should-not (tsc-goto-first-named-child cursor))
+        (should (eq (tsc-current-node cursor :type) 'block))))))
+
+(ert-deftest cursor::descendant-index ()
+  (tsc-test-lang-with-file rust "data/types.rs"
+    (let ((cursor (tsc-make-cursor tree-sitter-tree))
+          (types))
+      (should (= 0 (tsc-current-descendant-index cursor)))
+      (tsc-traverse-mapc (lambda (type) (push type types)) tree-sitter-tree :type)
+      (setq types (nreverse types))
+      (ert-info ("Indices should follow depth-first pre-order")
+        (dolist (index (list 1 7 (/ (length types) 2) (1- (length types))))
+          (should (tsc-goto-descendant cursor index))
+          (should (= index (tsc-current-descendant-index cursor)))
+          (should (equal (nth index types) (tsc-current-node cursor :type)))))
+      (ert-info ("Out-of-range indices should not move the cursor")
+        (should-not (tsc-goto-descendant cursor (length types)))
+        (should (= (1- (length types)) (tsc-current-descendant-index cursor)))))))
+
+(ert-deftest cursor::copy ()
+  (tsc-test-with rust parser
+    (let* ((cursor (tsc-make-cursor (tsc-parse-string parser "fn foo() {}")))
+           (copy))
+      (tsc-goto-first-child cursor)
+      (setq copy (tsc-copy-cursor cursor))
+      (should (eq (tsc-current-node copy :type) 'function_item))
+      (ert-info ("Moving the copy should not affect the original")
+        (should (tsc-goto-first-child copy))
+        (should (eq (tsc-current-node cursor :type) 'function_item))))))
+
+(ert-deftest cursor::state ()
+  (tsc-test-lang-with-file rust "data/types.rs"
+    (let ((cursor (tsc-make-cursor tree-sitter-tree))
+          (state))
+      (tsc-goto-first-child cursor)
+      (tsc-goto-next-sibling cursor)
+      (tsc-goto-first-child cursor)
+      (setq state (tsc-cursor-state cursor))
+      (ert-info ("State should be restorable onto a fresh cursor")
+        (let ((fresh (tsc-make-cursor tree-sitter-tree)))
+          (should (tsc-restore-cursor-state fresh state))
+          (should (tsc-node-eq (tsc-current-node cursor) (tsc-current-node fresh)))
+          (should (= (tsc-cursor-depth cursor) (tsc-cursor-depth fresh))))))))
+
 (ert-deftest cursor::using-without-tree ()
   (tsc-test-with rust parser
     (let ((cursor (tsc-make-cursor (tsc-parse-string parser "fn foo() {}"))))
@@ -310,6 +1236,292 @@ source_file (1 . 20)
         (should (equal do-result mapc-result))
         (should (equal do-result iter-result))))))
 
+(ert-deftest cursor::traverse:post-order ()
+  (tsc-test-with rust parser
+    (let ((tree (tsc-parse-string parser "fn foo() {}"))
+          (expected '("fn" identifier "(" ")" parameters "{" "}" block function_item source_file))
+          mapc-result
+          do-result
+          iter-result)
+      (tsc-traverse-mapc (lambda (type) (push type mapc-result)) tree :type :order :post)
+      (cl-loop for type
+               iter-by (tsc-traverse-iter tree :type :order :post)
+               do (push type iter-result))
+      (tsc-traverse-do ([type] tree :order :post)
+        (push type do-result))
+      (should (equal expected (reverse mapc-result)))
+      (should (equal expected (reverse iter-result)))
+      (should (equal expected (reverse do-result)))
+      (should-error (tsc-traverse-mapc #'ignore tree :type :order :in)))))
+
+(ert-deftest cursor::traverse:reverse ()
+  (tsc-test-lang-with-file rust "data/types.rs"
+    (let ((tree tree-sitter-tree)
+          forward
+          backward)
+      (tsc-traverse-mapc (lambda (props) (push (copy-sequence props) forward))
+                         tree [:type :start-byte])
+      (tsc-traverse-mapc (lambda (props) (push (copy-sequence props) backward))
+                         tree [:type :start-byte] :order :post :reverse t)
+      (ert-info ("Reverse post-order should be the exact reverse of pre-order")
+        (should (equal forward (nreverse backward)))))))
+
+(ert-deftest cursor::traverse:byte-range ()
+  (tsc-test-lang-with-file rust "data/types.rs"
+    (let ((tree tree-sitter-tree)
+          (beg 200)
+          (end 300)
+          (all 0)
+          (ranges))
+      (tsc-traverse-mapc (lambda (_) (cl-incf all)) tree :type)
+      (tsc-traverse-mapc (lambda (range) (push range ranges))
+                         tree :byte-range :start-byte beg :end-byte end)
+      (should (< (length ranges) all))
+      (ert-info ("All visited nodes should intersect the range")
+        (dolist (range ranges)
+          (should (< (car range) end))
+          (should (> (cdr range) beg)))))))
+
+(ert-deftest cursor::traverse:max-depth ()
+  (tsc-test-with rust parser
+    (let ((tree (tsc-parse-string parser "fn foo() {}"))
+          types)
+      (tsc-traverse-mapc (lambda (type) (push type types)) tree :type :max-depth 1)
+      (should (equal '(source_file function_item) (nreverse types))))))
+
+(ert-deftest cursor::traverse:skip-children ()
+  (tsc-test-lang-with-file rust "data/types.rs"
+    (let ((tree tree-sitter-tree)
+          (top-level (tsc-count-children (tsc-root-node tree-sitter-tree)))
+          (depths))
+      (tsc-traverse-mapc (lambda (depth)
+                           (push depth depths)
+                           (when (> depth 0)
+                             :skip-children))
+                         tree :depth)
+      (ert-info ("Only the root node and its children should be visited")
+        (should (= (1+ top-level) (length depths)))
+        (should (equal '(0) (cl-remove 1 depths)))))))
+
+(ert-deftest cursor::traverse:enter-exit ()
+  (tsc-test-with rust parser
+    (let ((tree (tsc-parse-string parser "fn foo() {}"))
+          events)
+      (tsc-traverse-mapc (lambda (props)
+                           (pcase-let ((`[,event ,type] props))
+                             (when (symbolp type)
+                               (push (list event type) events))))
+                         tree [:event :type] :order :pre-post)
+      (should (equal '((:enter source_file)
+                       (:enter function_item)
+                       (:enter identifier)
+                       (:enter parameters)
+                       (:exit parameters)
+                       (:enter block)
+                       (:exit block)
+                       (:exit function_item)
+                       (:exit source_file))
+                     (nreverse events))))))
+
+(ert-deftest cursor::traverse:leaves-only ()
+  (tsc-test-with rust parser
+    (let ((tree (tsc-parse-string parser "fn foo() {}"))
+          types)
+      (tsc-traverse-mapc (lambda (type) (push type types)) tree :type :leaves-only t)
+      (should (equal '("fn" identifier "(" ")" "{" "}") (nreverse types))))))
+
+(ert-deftest cursor::traverse:named-only ()
+  (tsc-test-lang-with-file rust "data/types.rs"
+    (let ((tree tree-sitter-tree)
+          all-named
+          named-only)
+      (tsc-traverse-mapc (lambda (props)
+                           (pcase-let ((`[,type ,named-p] props))
+                             (when named-p (push type all-named))))
+                         tree [:type :named-p])
+      (tsc-traverse-mapc (lambda (type) (push type named-only))
+                         tree :type :named-only t)
+      (should (equal all-named named-only)))))
+
+(ert-deftest cursor::traverse:fold ()
+  (tsc-test-lang-with-file rust "data/types.rs"
+    (let ((tree tree-sitter-tree)
+          (count 0)
+          types)
+      (tsc-traverse-mapc (lambda (type)
+                           (setq count (1+ count))
+                           (push type types))
+                         tree :type)
+      (should (= count (tsc-traverse-fold (lambda (n _) (1+ n)) 0 tree)))
+      (should (equal types (tsc-traverse-fold (lambda (acc type) (cons type acc))
+                                              nil tree :type)))
+      (should (equal (reverse types)
+                     (tsc-traverse-fold (lambda (acc type) (cons type acc))
+                                        nil tree :type :order :post :reverse t))))))
+
+(ert-deftest cursor::traverse:batched ()
+  (tsc-test-lang-with-file rust "data/types.rs"
+    (let* ((tree tree-sitter-tree)
+           (batch-size 7)
+           (output (make-vector (* 2 batch-size) nil))
+           (iter (tsc--iter tree nil))
+           expected
+           actual
+           count)
+      (tsc-traverse-mapc (lambda (props) (push (copy-sequence props) expected))
+                         tree [:type :depth])
+      (while (progn
+               (setq count (tsc--iter-next-nodes iter [:type :depth] output))
+               (dotimes (i count)
+                 (push (vector (aref output (* 2 i)) (aref output (1+ (* 2 i))))
+                       actual))
+               (= count batch-size)))
+      (should (equal expected actual)))))
+
+(ert-deftest cursor::diff ()
+  (tsc-test-with rust parser
+    (let* ((old (tsc-parse-string parser "fn foo() {} fn bar() {}"))
+           (new (tsc-parse-string parser "fn foo() {} fn bar(x: u8) {}"))
+           pairs)
+      (ert-info ("Identical trees should have no differences")
+        (iter-do (pair (tsc-diff-iter old old))
+          (push pair pairs))
+        (should (null pairs)))
+      (iter-do (pair (tsc-diff-iter old new))
+        (push pair pairs))
+      (should (= 1 (length pairs)))
+      (pcase-let ((`(,old-node . ,new-node) (car pairs)))
+        (should (eq 'parameters (tsc-node-type old-node)))
+        (should (eq 'parameters (tsc-node-type new-node)))
+        (should (= 2 (tsc-count-children old-node)))
+        (should (= 3 (tsc-count-children new-node)))))))
+
+(ert-deftest cursor::traverse:more-props ()
+  (tsc-test-lang-with-file rust "data/types.rs"
+    (let ((tree tree-sitter-tree)
+          nodes
+          all-props)
+      (tsc-traverse-mapc (lambda (node) (push node nodes)) tree)
+      (tsc-traverse-mapc (lambda (props) (push (copy-sequence props) all-props))
+                         tree [:text :id :child-count :named-child-count])
+      (should (= (length nodes) (length all-props)))
+      (cl-loop for node in nodes
+               for props in all-props
+               do (pcase-let ((`[,text ,_ ,child-count ,named-child-count] props))
+                    (should (equal text (tsc-node-text node)))
+                    (should (= child-count (tsc-count-children node)))
+                    (should (= named-child-count (tsc-count-named-children node)))))
+      (ert-info ("Node ids should be unique")
+        (let ((ids (mapcar (lambda (props) (aref props 1)) all-props)))
+          (should (equal ids (delete-dups (copy-sequence ids)))))))))
+
+(ert-deftest cursor::traverse:numeric-ids ()
+  (tsc-test-lang-with-file rust "data/types.rs"
+    (let ((language (tsc-tree-language tree-sitter-tree)))
+      (tsc-traverse-do ([type field kind-id field-id] tree-sitter-tree)
+        (should (equal type (tsc-lang-node-type language kind-id)))
+        (should (eq field (and field-id (tsc-lang-field language field-id))))))))
+
+(ert-deftest cursor::traverse:plist ()
+  (tsc-test-lang-with-file rust "data/types.rs"
+    (let ((tree tree-sitter-tree)
+          vectors
+          plists)
+      (tsc-traverse-mapc (lambda (props) (push (append props nil) vectors))
+                         tree [:type :depth :named-p])
+      (tsc-traverse-mapc (lambda (plist)
+                           (push (list (plist-get plist :type)
+                                       (plist-get plist :depth)
+                                       (plist-get plist :named-p))
+                                 plists))
+                         tree '(:type :depth :named-p))
+      (should (equal vectors plists))
+      (iter-do (plist (tsc-traverse-iter tree '(:type :depth) :max-depth 0))
+        (should (equal plist '(:type source_file :depth 0)))))))
+
+(ert-deftest cursor::traverse:parent-props ()
+  (tsc-test-lang-with-file rust "data/types.rs"
+    (let ((tree tree-sitter-tree)
+          nodes
+          all-props)
+      (tsc-traverse-mapc (lambda (node) (push node nodes)) tree)
+      (tsc-traverse-mapc (lambda (props) (push (copy-sequence props) all-props))
+                         tree [:parent-type :parent-field :depth])
+      (cl-loop for node in nodes
+               for props in all-props
+               do (pcase-let ((`[,parent-type ,parent-field ,depth] props))
+                    (if (= depth 0)
+                        (should (null parent-type))
+                      (should (equal parent-type
+                                     (tsc-node-type (tsc-get-parent node)))))
+                    (when (< depth 2)
+                      (should (null parent-field)))
+                    (when parent-field
+                      (should (keywordp parent-field))))))))
+
+(ert-deftest cursor::traverse:function-props ()
+  (tsc-test-lang-with-file rust "data/types.rs"
+    (let ((tree tree-sitter-tree)
+          (count 0))
+      (tsc-traverse-mapc
+       (lambda (props)
+         (pcase-let ((`[,child-count ,count-children] props))
+           (should (= child-count count-children))
+           (setq count (1+ count))))
+       tree
+       (vector :child-count #'tsc-count-children))
+      (should (> count 0))
+      (ert-info ("A single function should work as PROPS")
+        (should (= (tsc-traverse-fold #'+ 0 tree #'tsc-count-children)
+                   (tsc-traverse-fold #'+ 0 tree :child-count)))))))
+
+(cl-defstruct tsc-test--node-info type depth named-p)
+
+(ert-deftest cursor::traverse:record-output ()
+  (tsc-test-lang-with-file rust "data/types.rs"
+    (let ((iter (tsc--iter tree-sitter-tree nil))
+          (info (make-tsc-test--node-info))
+          vectors
+          records)
+      (tsc-traverse-mapc (lambda (props) (push (append props nil) vectors))
+                         tree-sitter-tree [:type :depth :named-p])
+      (while (tsc--iter-next-node iter [:type :depth :named-p] info)
+        (push (list (tsc-test--node-info-type info)
+                    (tsc-test--node-info-depth info)
+                    (tsc-test--node-info-named-p info))
+              records))
+      (should (equal vectors records)))))
+
+(ert-deftest cursor::traverse:with-depth ()
+  (tsc-test-lang-with-file rust "data/types.rs"
+    (let ((tree tree-sitter-tree)
+          expected
+          actual)
+      (tsc-traverse-mapc (lambda (props) (push (append props nil) expected))
+                         tree [:type :depth])
+      (tsc-traverse-mapc (lambda (node depth)
+                           (push (list (tsc-node-type node) depth) actual))
+                         tree nil :with-depth t)
+      (should (equal expected actual))
+      (should (equal (tsc-traverse-fold (lambda (acc depth) (max acc depth)) 0 tree :depth)
+                     (tsc-traverse-fold (lambda (acc _ depth) (max acc depth)) 0 tree
+                                        :type :with-depth t))))))
+
+(ert-deftest cursor::traverse:source ()
+  (tsc-test-with rust parser
+    (let* ((source "fn foo() { \"héllo wörld\" }")
+           (tree (tsc-parse-string parser source))
+           texts)
+      (with-temp-buffer
+        (insert "unrelated")
+        (tsc-traverse-mapc (lambda (props) (push (append props nil) texts))
+                           tree [:type :text] :source source)
+        (should (equal (assq 'source_file texts) `(source_file ,source)))
+        (should (equal (assq 'identifier texts) '(identifier "foo")))
+        (should (equal (assq 'string_literal texts) '(string_literal "\"héllo wörld\"")))
+        (should-error (tsc-traverse-mapc #'ignore tree :text :source "fn")
+                      :type 'tsc-error)))))
+
 (ert-deftest conversion::position<->tsc-point ()
   (tsc-test-with-file "tree-sitter-tests.el"
     (ert-info ("Testing buffer boundaries")
@@ -372,6 +1584,201 @@ source_file (1 . 20)
       (ert-info ("Should capture some macros")
         (should (member 'macro capture-tags))))))
 
+(ert-deftest query::standard-predicates ()
+  (tsc-test-with rust parser
+    (with-temp-buffer
+      (insert "fn foo() {}\nfn bar() {}\nfn baz() {}")
+      (let ((root-node (tsc-root-node (tsc-parse-buffer parser)))
+            (language (tree-sitter-require 'rust)))
+        (cl-flet ((captured-texts
+                   (patterns)
+                   (mapcar (lambda (capture) (tsc-node-text (cdr capture)))
+                           (tsc-query-captures (tsc-make-query language patterns) root-node
+                                               #'tsc--buffer-substring-no-properties))))
+          (should (equal (captured-texts "((identifier) @name (#eq? @name \"bar\"))")
+                         '("bar")))
+          (should (equal (captured-texts "((identifier) @name (#not-match? @name \"^ba\"))")
+                         '("foo")))
+          (should (equal (captured-texts "((identifier) @name (#any-of? @name \"foo\" \"baz\"))")
+                         '("foo" "baz")))
+          (should (equal (captured-texts [((identifier) @name (.not-any-of? @name "foo" "baz"))])
+                         '("bar")))
+          (ert-info ("Matches should be filtered too")
+            (should (equal (mapcar #'car (tsc-query-matches
+                                          (tsc-make-query
+                                           language
+                                           "((identifier) @name (#any-of? @name \"baz\"))")
+                                          root-node #'tsc--buffer-substring-no-properties))
+                           '(0))))
+          (ert-info ("Invalid predicate arguments should be rejected")
+            (should-error (tsc-make-query language "((identifier) @name (#any-of? \"foo\"))")
+                          :type 'tsc-query-invalid-predicate)))))))
+
+(ert-deftest query::emacs-regexps ()
+  (tsc-test-with rust parser
+    (with-temp-buffer
+      (insert "fn foo() {}\nfn bar() {}\nfn baz() {}")
+      (let ((root-node (tsc-root-node (tsc-parse-buffer parser)))
+            (language (tree-sitter-require 'rust)))
+        (cl-flet ((captured-texts
+                   (patterns)
+                   (mapcar (lambda (capture) (tsc-node-text (cdr capture)))
+                           (tsc-query-captures (tsc-make-query language patterns) root-node
+                                               #'tsc--buffer-substring-no-properties))))
+          (should (equal (captured-texts "((identifier) @name (#match? @name \"o{2}\"))")
+                         '("foo")))
+          (let ((tsc-query-regexp-syntax 'emacs))
+            (ert-info ("Regexps should use Emacs's syntax")
+              (should-not (captured-texts "((identifier) @name (#match? @name \"o{2}\"))"))
+              (should (equal (captured-texts
+                              "((identifier) @name (#match? @name \"^\\\\(bar\\\\|foo\\\\)$\"))")
+                             '("foo" "bar"))))
+            (ert-info ("Negated and any- variants should be supported")
+              (should (equal (captured-texts "((identifier) @name (#not-match? @name \"^ba\"))")
+                             '("foo")))
+              (should (equal (captured-texts "((identifier) @name (#any-match? @name \"Z\"))")
+                             '())))
+            (ert-info ("Regexps should be matched once per match, not once per capture")
+              (let* ((calls 0)
+                     (query (tsc-make-query
+                             language
+                             "(function_item name: (identifier) @name body: (block) @body
+                                             (#match? @name \"^ba\"))"))
+                     (captures (tsc-query-captures
+                                query root-node
+                                (lambda (beg end)
+                                  (setq calls (1+ calls))
+                                  (tsc--buffer-substring-no-properties beg end)))))
+                (should (= 4 (length captures)))
+                (should (= 3 calls))))))))))
+
+;; Only predicate names should be rewritten, and positions should refer to the original source.
+(ert-deftest query::emacs-regexps-source ()
+  (tsc-test-with rust parser
+    (with-temp-buffer
+      (insert "// #match?\nfn foo() {}")
+      (let* ((root-node (tsc-root-node (tsc-parse-buffer parser)))
+             (language (tree-sitter-require 'rust))
+             (source "; #match? in a comment\n((identifier) @name (#match? @name \"o\"))\n(line_comment) @c")
+             (invalid "((identifier) @name (#match? @name \"o\"))\n(identifer)"))
+        (cl-flet ((positions
+                   ()
+                   (let ((query (tsc-make-query language source))
+                         (err (should-error (tsc-make-query language invalid)
+                                            :type 'tsc-query-invalid-node-type)))
+                     (list (tsc-query-start-byte-for-pattern query 0)
+                           (tsc-query-start-byte-for-pattern query 1)
+                           (nth 2 err) (nth 3 err)))))
+          (let ((positions (positions)))
+            (let ((tsc-query-regexp-syntax 'emacs))
+              (ert-info ("Strings and comments should be left alone")
+                (should (equal (mapcar
+                                (lambda (capture) (tsc-node-text (cdr capture)))
+                                (tsc-query-captures
+                                 (tsc-make-query
+                                  language "((line_comment) @c (#eq? @c \"// #match?\"))")
+                                 root-node #'tsc--buffer-substring-no-properties))
+                               '("// #match?"))))
+              (ert-info ("Positions should be in the original source")
+                (should (equal (positions) positions))))))))))
+
+(ert-deftest query::any-predicates ()
+  (tsc-test-with rust parser
+    (with-temp-buffer
+      (insert "fn foo(a: u8, b: u16) {}\nfn bar(c: u8) {}")
+      (let ((root-node (tsc-root-node (tsc-parse-buffer parser)))
+            (language (tree-sitter-require 'rust)))
+        (cl-flet ((matched-functions
+                   (predicate)
+                   (let ((query (tsc-make-query
+                                 language
+                                 (format "((function_item
+                                             name: (identifier) @name
+                                             parameters: (parameters
+                                                          (parameter type: (_) @type)+))
+                                           %s)"
+                                         predicate))))
+                     (seq-uniq
+                      (delq nil (mapcar (lambda (capture)
+                                          (when (eq (car capture) 'name)
+                                            (tsc-node-text (cdr capture))))
+                                        (tsc-query-captures
+                                         query root-node
+                                         #'tsc--buffer-substring-no-properties)))))))
+          (should (equal (matched-functions "(#any-eq? @type \"u16\")") '("foo")))
+          (should (equal (matched-functions "(#any-not-eq? @type \"u8\")") '("foo")))
+          (should (equal (matched-functions "(#any-match? @type \"^u\")") '("foo" "bar")))
+          (should (equal (matched-functions "(#any-not-match? @type \"8$\")") '("foo"))))
+        (ert-info ("Invalid regexes should be rejected")
+          (should-error (tsc-make-query language "((identifier) @name (#any-match? @name \"(\"))")
+                        :type 'tsc-query-invalid-predicate))))))
+
+(ert-deftest query::custom-predicates ()
+  (tsc-test-with rust parser
+    (with-temp-buffer
+      (insert "fn foo() {}\nfn foobar() {}")
+      (let ((root-node (tsc-root-node (tsc-parse-buffer parser)))
+            (language (tree-sitter-require 'rust))
+            (tsc-query-predicate-functions nil)
+            (calls 0))
+        (tsc-query-define-predicate
+         "longer-than?" (lambda (node length)
+                          (setq calls (1+ calls))
+                          (> (length (tsc-node-text node)) (string-to-number length))))
+        (let ((query (tsc-make-query language "((identifier) @name (#longer-than? @name \"3\"))")))
+          (should (equal (mapcar (lambda (capture) (tsc-node-text (cdr capture)))
+                                 (tsc-query-captures query root-node
+                                                     #'tsc--buffer-substring-no-properties))
+                         '("foobar")))
+          (should (= 2 calls)))
+        (ert-info ("Predicates should be called once per match, not once per capture")
+          (setq calls 0)
+          (let ((query (tsc-make-query
+                        language
+                        "(function_item name: (identifier) @name body: (block) @body
+                                        (#longer-than? @name \"3\"))")))
+            (should (= 2 (length (tsc-query-captures query root-node
+                                                     #'tsc--buffer-substring-no-properties))))
+            (should (= 2 calls))))
+        (ert-info ("Unregistered predicates should be ignored")
+          (should (= 2 (length (tsc-query-captures
+                                (tsc-make-query language "((identifier) @name (#unknown? @name))")
+                                root-node #'tsc--buffer-substring-no-properties)))))))))
+
+(ert-deftest query::pattern-properties ()
+  (let ((query (tsc-make-query (tree-sitter-require 'rust)
+                               "((identifier) @name)
+                                ((string_literal) @injection.content
+                                 (#set! injection.language \"sql\")
+                                 (#set! injection.combined))")))
+    (should (equal (tsc-query-pattern-properties query 0) nil))
+    (should (equal (tsc-query-pattern-properties query 1)
+                   '(:injection.language "sql" :injection.combined t)))))
+
+(ert-deftest query::capture-quantifiers ()
+  (let ((query (tsc-make-query (tree-sitter-require 'rust)
+                               "(function_item name: (identifier) @name
+                                               parameters: (parameters (parameter)* @param))
+                                (function_item (visibility_modifier)? @vis
+                                               body: (block (_)+ @statement))")))
+    (should (equal (tsc-query-capture-tags query) [name param vis statement]))
+    (should (equal (tsc-query-capture-quantifiers query 0)
+                   [:one :zero-or-more :zero :zero]))
+    (should (equal (tsc-query-capture-quantifiers query 1)
+                   [:zero :zero :zero-or-one :one-or-more]))))
+
+(ert-deftest query::pattern-introspection ()
+  (let* ((source "(function_item name: (identifier) @name)
+((line_comment)+ @doc . (function_item))")
+         (query (tsc-make-query (tree-sitter-require 'rust) source)))
+    (should (= 2 (tsc-query-count-patterns query)))
+    (should (= 1 (tsc-query-start-byte-for-pattern query 0)))
+    (should (= (1+ (string-match-p "((line_comment" source))
+               (tsc-query-start-byte-for-pattern query 1)))
+    (ert-info ("Required children should be definite steps")
+      (should (tsc-query-step-definite-p
+               query (1+ (string-match-p "(identifier)" source)))))))
+
 (ert-deftest query::range-restriction ()
   ;; https://github.com/tree-sitter/tree-sitter/issues/685
   (tsc-test-lang-with-file c "data/range-restriction-and-early-termination.c"
@@ -391,7 +1798,7 @@ source_file (1 . 20)
                                       cursor query root-node #'tsc--buffer-substring-no-properties))
                        capture-names)))
       (ert-info ("Querying with range restriction")
-        (tsc--query-cursor-set-byte-range cursor 1 28)
+        (tsc-query-cursor-set-byte-range cursor 1 28)
         (should (equal (mapcar #'car (tsc-query-captures
                                       query root-node #'tsc--buffer-substring-no-properties cursor))
                        capture-names))
@@ -399,6 +1806,408 @@ source_file (1 . 20)
                                       cursor query root-node #'tsc--buffer-substring-no-properties))
                        capture-names))))))
 
+(ert-deftest query::byte-range ()
+  (tsc-test-with-file "data/types.rs"
+    (tsc-test-with rust parser
+      (let* ((tree (tsc-parse-buffer parser))
+             (root-node (tsc-root-node tree))
+             (query (tsc-make-query (tree-sitter-require 'rust) [(type_identifier) @type]))
+             (cursor (tsc-make-query-cursor))
+             (all (tsc-query-captures query root-node #'tsc--buffer-substring-no-properties
+                                      cursor))
+             (second-half (/ (position-bytes (point-max)) 2)))
+        (tsc-query-cursor-set-byte-range cursor second-half nil)
+        (let ((captures (tsc-query-captures query root-node
+                                            #'tsc--buffer-substring-no-properties cursor)))
+          (should (< 0 (length captures) (length all)))
+          (ert-info ("Only nodes intersecting the range should be captured")
+            (seq-doseq (capture captures)
+              (should (> (tsc-node-end-byte (cdr capture)) second-half)))))
+        (ert-info ("The range should be resettable")
+          (tsc-query-cursor-set-byte-range cursor 1 nil)
+          (should (= (length all)
+                     (length (tsc-query-captures query root-node
+                                                 #'tsc--buffer-substring-no-properties
+                                                 cursor)))))))))
+
+(ert-deftest query::captures-in-node ()
+  (tsc-test-with-file "data/types.rs"
+    (tsc-test-with rust parser
+      (let* ((root-node (tsc-root-node (tsc-parse-buffer parser)))
+             (language (tree-sitter-require 'rust))
+             (node (cdr (aref (tsc-query-captures (tsc-make-query language [(impl_item) @impl])
+                                                  root-node
+                                                  #'tsc--buffer-substring-no-properties)
+                              0)))
+             (query (tsc-make-query language [(type_identifier) @type]))
+             (cursor (tsc-make-query-cursor))
+             (all (tsc-query-captures query root-node #'tsc--buffer-substring-no-properties))
+             (captures (tsc-query-captures-in-node query node
+                                                   #'tsc--buffer-substring-no-properties
+                                                   cursor)))
+        (should (< 0 (length captures) (length all)))
+        (ert-info ("Only nodes within the node should be captured")
+          (seq-doseq (capture captures)
+            (should (<= (tsc-node-start-byte node)
+                        (tsc-node-start-byte (cdr capture))
+                        (tsc-node-end-byte (cdr capture))
+                        (tsc-node-end-byte node)))))
+        (ert-info ("The cursor's range should be left unchanged")
+          (should (= (length all)
+                     (length (tsc-query-captures query root-node
+                                                 #'tsc--buffer-substring-no-properties
+                                                 cursor))))
+          (tsc-query-cursor-set-byte-range cursor (tsc-node-end-byte node))
+          (let ((after (length (tsc-query-captures query root-node
+                                                   #'tsc--buffer-substring-no-properties
+                                                   cursor))))
+            (should (< after (length all)))
+            (tsc-query-captures-in-node query node #'tsc--buffer-substring-no-properties cursor)
+            (should (= after
+                       (length (tsc-query-captures query root-node
+                                                   #'tsc--buffer-substring-no-properties
+                                                   cursor))))))))))
+
+(ert-deftest query::deduplicated-captures ()
+  (tsc-test-with-file "data/types.rs"
+    (tsc-test-with rust parser
+      (let* ((root-node (tsc-root-node (tsc-parse-buffer parser)))
+             (query (tsc-make-query (tree-sitter-require 'rust)
+                                    [(function_item name: (identifier) @name)
+                                     (identifier) @identifier]))
+             (captures (tsc-query-captures query root-node
+                                           #'tsc--buffer-substring-no-properties nil t))
+             (identifiers (tsc-query-captures (tsc-make-query (tree-sitter-require 'rust)
+                                                              [(identifier) @identifier])
+                                              root-node
+                                              #'tsc--buffer-substring-no-properties))
+             (end 0))
+        (ert-info ("Each node should be captured once, by the earliest pattern")
+          (should (= (length captures) (length identifiers)))
+          (should (seq-find (lambda (capture) (eq (car capture) 'name)) captures))
+          (seq-doseq (capture captures)
+            (when (eq (car capture) 'identifier)
+              (should-not (equal (tsc-node-type (tsc-get-parent (cdr capture)))
+                                 'function_item)))))
+        (ert-info ("Captures should be sorted, and not overlap")
+          (seq-doseq (capture captures)
+            (should (<= end (tsc-node-start-byte (cdr capture))))
+            (setq end (tsc-node-end-byte (cdr capture)))))))))
+
+(ert-deftest query::captures-into ()
+  (tsc-test-with-file "data/types.rs"
+    (tsc-test-with rust parser
+      (let* ((root-node (tsc-root-node (tsc-parse-buffer parser)))
+             (query (tsc-make-query (tree-sitter-require 'rust)
+                                    [(function_item name: (identifier) @name)
+                                     (type_identifier) @type]))
+             (text-function #'tsc--buffer-substring-no-properties)
+             (captures (tsc-query-captures query root-node text-function))
+             (n (length captures))
+             (output (make-vector (* 3 n) nil)))
+        (ert-info ("Captures' properties should be written in order")
+          (should (= n (tsc-query-captures-into query root-node text-function
+                                                [:capture-tag :start-byte :end-byte]
+                                                output)))
+          (dotimes (i n)
+            (let ((capture (aref captures i)))
+              (should (equal (vector (car capture)
+                                     (tsc-node-start-byte (cdr capture))
+                                     (tsc-node-end-byte (cdr capture)))
+                             (seq-subseq output (* 3 i) (* 3 (1+ i))))))))
+        (ert-info ("Captures that don't fit should be counted, but not written")
+          (let ((small (make-vector 2 nil)))
+            (should (= n (tsc-query-captures-into query root-node text-function
+                                                  [:node :pattern-index] small)))
+            (should (tsc-node-eq (aref small 0) (cdr (aref captures 0))))))
+        (ert-info ("Captures' text should be retrieved with the text function")
+          (should (= n (tsc-query-captures-into query root-node text-function
+                                                [:text] output)))
+          (dotimes (i n)
+            (should (equal (tsc-node-text (cdr (aref captures i)))
+                           (aref output i)))))
+        (should-error (tsc-query-captures-into query root-node text-function
+                                               [:type] output))))))
+
+(ert-deftest query::highlight-spans ()
+  (tsc-test-with-file "data/types.rs"
+    (tsc-test-with rust parser
+      (let* ((root-node (tsc-root-node (tsc-parse-buffer parser)))
+             (query (tsc-make-query (tree-sitter-require 'rust)
+                                    [(function_item name: (identifier) @name)
+                                     (type_identifier) @type
+                                     (primitive_type) @builtin]))
+             (text-function #'tsc--buffer-substring-no-properties)
+             (spans (tsc-query-highlight-spans query root-node text-function
+                                               '(("type" . font-lock-type-face)
+                                                 (name . font-lock-function-name-face))))
+             (expected nil))
+        (seq-doseq (capture (tsc-query-captures query root-node text-function))
+          (pcase-let ((`(,tag . ,node) capture))
+            (unless (eq tag 'builtin)
+              (push (list (tsc-node-start-position node)
+                          (tsc-node-end-position node)
+                          (if (eq tag 'type) 'font-lock-type-face 'font-lock-function-name-face))
+                    expected))))
+        (setq expected (nreverse expected))
+        (should (> (length expected) 0))
+        (ert-info ("Spans from earlier patterns should come first")
+          (should (equal (append spans nil)
+                         (apply #'append
+                                (append (seq-filter (lambda (span)
+                                                      (eq (nth 2 span)
+                                                          'font-lock-function-name-face))
+                                                    expected)
+                                        (seq-filter (lambda (span)
+                                                      (eq (nth 2 span) 'font-lock-type-face))
+                                                    expected))))))
+        (ert-info ("Positions should be converted in non-ASCII buffers")
+          (with-temp-buffer
+            (insert "// é\nfn foo() {}")
+            (should (equal (tsc-query-highlight-spans query
+                                                      (tsc-root-node (tsc-parse-buffer parser))
+                                                      text-function
+                                                      '((name . bold)))
+                           [9 12 bold]))))))))
+
+(ert-deftest query::changed-captures ()
+  (tsc-test-with rust parser
+    (with-temp-buffer
+      (insert "fn foo() {}\nfn bar() {}")
+      (let ((old-tree (tsc-parse-chunks parser #'tsc--buffer-input nil))
+            (query (tsc-make-query (tree-sitter-require 'rust)
+                                   [(identifier) @name (primitive_type) @type]))
+            (new-tree))
+        (goto-char 8)
+        (insert "x: u8")
+        (tsc-edit-tree old-tree 8 8 13 '(1 . 7) '(1 . 7) '(1 . 12))
+        (setq new-tree (tsc-parse-chunks parser #'tsc--buffer-input old-tree))
+        (cl-flet ((captured-texts (&optional padding)
+                    (mapcar (lambda (capture) (tsc-node-text (cdr capture)))
+                            (tsc-query-changed-captures old-tree new-tree query
+                                                        #'tsc--buffer-substring-no-properties
+                                                        padding))))
+          (ert-info ("Only the changed ranges should be queried")
+            (should (equal (captured-texts) '("x" "u8"))))
+          (ert-info ("Padding should extend the ranges")
+            (should (equal (captured-texts 100) '("foo" "x" "u8" "bar")))))))))
+
+(ert-deftest query::capture-names ()
+  (let ((query (tsc-make-query (tree-sitter-require 'rust)
+                               [(function_item name: (identifier) @name)
+                                (type_identifier) @type
+                                (identifier) @name])))
+    (should (equal (tsc-query-capture-names query) ["name" "type"]))
+    (should (= 0 (tsc-query-capture-index query "name")))
+    (should (= 1 (tsc-query-capture-index query "type")))
+    (should-not (tsc-query-capture-index query "missing"))))
+
+(ert-deftest query::point-range ()
+  (tsc-test-with-file "data/types.rs"
+    (tsc-test-with rust parser
+      (let* ((root-node (tsc-root-node (tsc-parse-buffer parser)))
+             (query (tsc-make-query (tree-sitter-require 'rust) [(type_identifier) @type]))
+             (cursor (tsc-make-query-cursor))
+             (all (tsc-query-captures query root-node #'tsc--buffer-substring-no-properties
+                                      cursor))
+             (middle-line (/ (count-lines (point-min) (point-max)) 2)))
+        (tsc-query-cursor-set-point-range cursor '(1 . 0) (cons middle-line 0))
+        (let ((captures (tsc-query-captures query root-node
+                                            #'tsc--buffer-substring-no-properties cursor)))
+          (should (< 0 (length captures) (length all)))
+          (ert-info ("Only nodes intersecting the range should be captured")
+            (seq-doseq (capture captures)
+              (should (<= (car (tsc-node-start-point (cdr capture))) middle-line)))))
+        (ert-info ("The range should be resettable")
+          (tsc-query-cursor-set-point-range cursor '(1 . 0) nil)
+          (should (= (length all)
+                     (length (tsc-query-captures query root-node
+                                                 #'tsc--buffer-substring-no-properties
+                                                 cursor)))))))))
+
+(ert-deftest query::match-limit ()
+  (tsc-test-with-file "data/types.rs"
+    (tsc-test-with rust parser
+      (let ((root-node (tsc-root-node (tsc-parse-buffer parser)))
+            (query (tsc-make-query (tree-sitter-require 'rust) [(_ (_) @first (_) @second)]))
+            (cursor (tsc-make-query-cursor)))
+        (should-not (tsc-query-cursor-match-limit cursor))
+        (tsc-query-captures query root-node #'tsc--buffer-substring-no-properties cursor)
+        (should-not (tsc-query-cursor-exceeded-match-limit-p cursor))
+        (ert-info ("Exceeding the limit should be detectable")
+          (tsc-query-cursor-set-match-limit cursor 1)
+          (should (= 1 (tsc-query-cursor-match-limit cursor)))
+          (tsc-query-captures query root-node #'tsc--buffer-substring-no-properties cursor)
+          (should (tsc-query-cursor-exceeded-match-limit-p cursor)))
+        (ert-info ("The limit should be removable")
+          (tsc-query-cursor-set-match-limit cursor nil)
+          (should-not (tsc-query-cursor-match-limit cursor)))))))
+
+(ert-deftest query::matches-by-pattern ()
+  (tsc-test-with-file "data/types.rs"
+    (tsc-test-with rust parser
+      (let* ((root-node (tsc-root-node (tsc-parse-buffer parser)))
+             (query (tsc-make-query (tree-sitter-require 'rust)
+                                    [(function_item name: (identifier) @name
+                                                    body: (block) @body)
+                                     (type_identifier) @type
+                                     (string_literal) @string]))
+             (text-function #'tsc--buffer-substring-no-properties)
+             (matches (tsc-query-matches query root-node text-function))
+             (groups (tsc-query-matches-by-pattern query root-node text-function)))
+        (should (= (length groups) 3))
+        (ert-info ("Matches should be grouped by pattern, in the order they were found")
+          (dotimes (i 3)
+            (should (equal (mapcar (lambda (match)
+                                     (mapcar (lambda (capture)
+                                               (cons (car capture)
+                                                     (tsc-node-start-byte (cdr capture))))
+                                             (cdr match)))
+                                   (seq-filter (lambda (match) (= (car match) i))
+                                               matches))
+                           (mapcar (lambda (plist)
+                                     (mapcar (lambda (pair)
+                                               (cons (car pair)
+                                                     (tsc-node-start-byte (cadr pair))))
+                                             (seq-partition plist 2)))
+                                   (aref groups i))))))
+        (ert-info ("Each match should be a plist of its captures")
+          (let ((function (car (aref groups 0))))
+            (should (equal (tsc-node-type (plist-get function 'name)) 'identifier))
+            (should (equal (tsc-node-type (plist-get function 'body)) 'block))))))))
+
+(ert-deftest query::matches-iter ()
+  (tsc-test-with-file "data/types.rs"
+    (tsc-test-with rust parser
+      (let* ((root-node (tsc-root-node (tsc-parse-buffer parser)))
+             (query (tsc-make-query (tree-sitter-require 'rust)
+                                    "(function_item (identifier) @name)
+                                     ((type_identifier) @type (#match? @type \"^[A-Z]\"))"))
+             (text-function #'tsc--buffer-substring-no-properties)
+             (matches (tsc-query-matches query root-node text-function))
+             (collected nil))
+        (should (> (length matches) 1))
+        (ert-info ("The iterator should yield the same matches, in the same order")
+          (iter-do (match (tsc-query-matches-iter query root-node text-function))
+            (push match collected))
+          (cl-flet ((texts (match)
+                           (cons (car match)
+                                 (mapcar (lambda (capture) (tsc-node-text (cdr capture)))
+                                         (cdr match)))))
+            (should (equal (mapcar #'texts (append matches nil))
+                           (mapcar #'texts (nreverse collected))))))
+        (ert-info ("The iteration should be abandonable early")
+          (let ((iter (tsc-query-matches-iter query root-node text-function)))
+            (should (equal (car (iter-next iter)) (car (aref matches 0))))
+            (iter-close iter)
+            (should-error (iter-next iter) :type 'iter-end-of-sequence)))))))
+
+(ert-deftest query::resumable-matches ()
+  (tsc-test-with-file "data/types.rs"
+    (tsc-test-with rust parser
+      (let* ((root-node (tsc-root-node (tsc-parse-buffer parser)))
+             (query (tsc-make-query (tree-sitter-require 'rust) [(type_identifier) @type]))
+             (text-function #'tsc--buffer-substring-no-properties)
+             (all (tsc-query-matches query root-node text-function))
+             (cursor (tsc-query-matches-start query root-node))
+             (chunks nil))
+        (should (> (length all) 5))
+        (should (= 1 (tsc-query-matches-position cursor)))
+        (ert-info ("Matches should be returned in chunks of bounded size")
+          (let ((chunk (tsc-query-matches-continue cursor query text-function 5)))
+            (should (= 5 (length chunk)))
+            (should (< 1 (tsc-query-matches-position cursor)))
+            (push chunk chunks)))
+        (ert-info ("A byte budget should stop a chunk early")
+          (let* ((position (tsc-query-matches-position cursor))
+                 (chunk (tsc-query-matches-continue cursor query text-function nil 1)))
+            (should (= 1 (length chunk)))
+            (should (<= (1+ position) (tsc-query-matches-position cursor)))
+            (push chunk chunks)))
+        (while (not (tsc-query-matches-done-p cursor))
+          (push (tsc-query-matches-continue cursor query text-function 100) chunks))
+        (ert-info ("All chunks together should be the same as the full result")
+          (cl-flet ((ranges (matches)
+                      (mapcar (lambda (match) (tsc-node-byte-range (cdr (aref (cdr match) 0))))
+                              matches)))
+            (should (equal (ranges all)
+                           (ranges (apply #'vconcat (nreverse chunks)))))))))))
+
+(ert-deftest query::errors ()
+  (let ((language (tree-sitter-require 'rust)))
+    (pcase-dolist (`(,source ,type ,point ,bytepos ,kind)
+                   '(("(function_item\n  (identifer))" tsc-query-invalid-node-type
+                      (2 . 3) 19 :node-type)
+                     ("(function_item nam: (identifier))" tsc-query-invalid-field
+                      (1 . 15) 16 :field)
+                     ("((identifier) @x (#eq? @y \"a\"))" tsc-query-invalid-capture
+                      (1 . 24) 25 :capture)
+                     ("((identifier) @x (#any-of? \"a\"))" tsc-query-invalid-predicate
+                      (1 . 0) 1 :predicate)))
+      (let ((err (should-error (tsc-make-query language source) :type 'tsc-query-invalid)))
+        (should (eq type (car err)))
+        (pcase-let ((`(,message ,err-point ,err-bytepos ,err-kind) (cdr err)))
+          (should (stringp message))
+          (should (equal point err-point))
+          (should (equal bytepos err-bytepos))
+          (should (eq kind err-kind)))))))
+
+(ert-deftest query::validation-report ()
+  (let ((language (tree-sitter-require 'rust)))
+    (should-not (tsc-validate-query language [(function_item name: (identifier) @name)]))
+    (should (equal (mapcar (lambda (problem)
+                             (list (plist-get problem :kind)
+                                   (plist-get problem :name)
+                                   (plist-get problem :bytepos)))
+                           (tsc-validate-query
+                            language
+                            "(function_item nam: (identifer) @name)
+((identifier) @x (#eq? @y \"a\"))
+\"fnn\" @keyword"))
+                   '((:field "nam" 16)
+                     (:node-type "identifer" 22)
+                     (:capture "y" 64)
+                     (:node-type "fnn" 73))))
+    (ert-info ("Other errors should stop the validation")
+      (let ((problems (tsc-validate-query language "(identifer) (function_item")))
+        (should (equal (mapcar (lambda (problem) (plist-get problem :kind)) problems)
+                       '(:node-type :syntax)))
+        (should (stringp (plist-get (nth 1 problems) :message)))))))
+
+(ert-deftest query::cache ()
+  (let ((language (tree-sitter-require 'rust))
+        (source [(identifier) @name (string_literal) @string]))
+    (unwind-protect
+        (progn
+          (tsc-clear-query-cache)
+          (tsc-make-query language source)
+          (should (= 1 (tsc-query-cache-count)))
+          (ert-info ("Identical queries should share compiled patterns")
+            (let ((query (tsc-make-query language source (lambda (name)
+                                                           (unless (equal name "string")
+                                                             (intern name))))))
+              (should (= 1 (tsc-query-cache-count)))
+              (ert-info ("Disabling captures should not affect other queries")
+                (with-temp-buffer
+                  (insert "fn foo() { \"a\" }")
+                  (let ((root-node (tsc-root-node (tsc-parse-buffer (tsc-test-make-parser 'rust)))))
+                    (should (equal (mapcar #'car (tsc-query-captures
+                                                  query root-node
+                                                  #'tsc--buffer-substring-no-properties))
+                                   '(name)))
+                    (should (equal (mapcar #'car (tsc-query-captures
+                                                  (tsc-make-query language source) root-node
+                                                  #'tsc--buffer-substring-no-properties))
+                                   '(name string))))))))
+          (ert-info ("Least recently used queries should be evicted")
+            (tsc-set-query-cache-capacity 2)
+            (tsc-make-query language [(identifier) @a])
+            (tsc-make-query language [(identifier) @b])
+            (should (= 2 (tsc-query-cache-count)))
+            (tsc-set-query-cache-capacity 0)
+            (should (= 0 (tsc-query-cache-count)))))
+      (tsc-set-query-cache-capacity 32))))
+
 (ert-deftest query::validation ()
   ;; https://github.com/emacs-tree-sitter/elisp-tree-sitter/issues/125
   (should (tsc-make-query (tree-sitter-require 'rust)
@@ -408,6 +2217,41 @@ source_file (1 . 20)
   (should-error (tree-sitter-require 'abc-xyz))
   (tree-sitter-require 'rust))
 
+(ert-deftest load::path ()
+  (let ((file (tsc--lang-load-file (tree-sitter-require 'rust))))
+    (ert-info ("A language should be loadable from an arbitrary path")
+      (should (tsc-language-p (tsc-load-language file "tree_sitter_rust" 'rust)))
+      (should (tsc-language-p (tree-sitter-load 'rust file))))
+    (ert-info ("Missing symbols should be reported")
+      (should-error (tsc-load-language file "tree_sitter_xyz" 'rust)
+                    :type 'tsc-lang-load-failed))
+    (ert-info ("Loaded languages should have a supported ABI version")
+      (let ((range (tsc-supported-abi-range)))
+        (should (<= (car range) (tsc-lang-version (tree-sitter-require 'rust)) (cdr range)))))))
+
+(ert-deftest load::query-inherits ()
+  (let* ((dir (make-temp-file "tsc-test-" t))
+         (tree-sitter-query-path (list dir)))
+    (unwind-protect
+        (cl-flet ((write (lang source)
+                    (make-directory (expand-file-name (symbol-name lang) dir))
+                    (with-temp-file (expand-file-name (format "%s/highlights.scm" lang) dir)
+                      (insert source))))
+          (let ((base "(function_item) @function")
+                (middle "; inherits: base\n(type_identifier) @type")
+                (rust "; inherits: middle,base,(missing)\n;; comment\n(identifier) @variable"))
+            (write 'base base)
+            (write 'middle middle)
+            (write 'rust rust)
+            (should (equal (tree-sitter-query-source 'rust 'highlights)
+                           (mapconcat #'identity (list base middle rust) "\n"))))
+          (should (equal (tsc-query-capture-names (tree-sitter-load-query 'rust 'highlights))
+                         ["function" "type" "variable"]))
+          (ert-info ("Non-optional missing languages should be rejected")
+            (write 'broken "; inherits: missing\n(identifier) @variable")
+            (should-error (tree-sitter-query-source 'broken 'highlights))))
+      (delete-directory dir t))))
+
 ;;; ----------------------------------------------------------------------------
 ;;; Highlighting tests.
 